@@ -0,0 +1,12 @@
+-- Create Email Outbox Table
+CREATE TABLE email_outbox(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    recipient TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    html_body TEXT NOT NULL,
+    text_body TEXT NOT NULL,
+    n_retries SMALLINT NOT NULL DEFAULT 0,
+    execute_after timestamptz NOT NULL DEFAULT now(),
+    created_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,12 @@
+-- Give every subscriber a token they can use to unsubscribe
+BEGIN;
+ALTER TABLE subscriptions ADD COLUMN unsubscribe_token TEXT NULL;
+-- Backfill existing subscribers with a random alphanumeric token
+UPDATE subscriptions
+SET unsubscribe_token = substr(md5(random()::text || id::text), 1, 25)
+WHERE unsubscribe_token IS NULL;
+ALTER TABLE subscriptions
+ALTER COLUMN unsubscribe_token
+SET NOT NULL;
+ALTER TABLE subscriptions ADD UNIQUE (unsubscribe_token);
+COMMIT;
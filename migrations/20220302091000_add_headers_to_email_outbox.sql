@@ -0,0 +1,2 @@
+-- Extra headers to attach to an outgoing email, as a list of {"Name", "Value"} objects
+ALTER TABLE email_outbox ADD COLUMN headers JSONB NOT NULL DEFAULT '[]';
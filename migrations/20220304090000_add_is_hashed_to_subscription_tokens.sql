@@ -0,0 +1,4 @@
+-- From now on tokens are stored as a keyed hash rather than in plaintext.
+-- Rows issued before this migration keep `is_hashed = false` and are still
+-- accepted until they expire, which bounds the transition window.
+ALTER TABLE subscription_tokens ADD COLUMN is_hashed BOOLEAN NOT NULL DEFAULT false;
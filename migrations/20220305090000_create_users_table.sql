@@ -0,0 +1,9 @@
+-- Operator accounts allowed into the /admin area.
+-- Passwords are stored as Argon2id PHC strings.
+BEGIN;
+    CREATE TABLE users(
+        user_id uuid PRIMARY KEY,
+        username TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL
+    );
+COMMIT;
@@ -0,0 +1,2 @@
+-- Backs the keyset pagination of the admin subscribers listing.
+CREATE INDEX subscriptions_subscribed_at_id_idx ON subscriptions (subscribed_at DESC, id DESC);
@@ -0,0 +1,8 @@
+-- Operator-maintained rules on which email domains may subscribe.
+-- A rule on a domain applies to its subdomains too.
+CREATE TABLE email_domain_rules(
+    domain TEXT NOT NULL PRIMARY KEY CHECK (domain = lower(domain)),
+    rule TEXT NOT NULL CHECK (rule IN ('allow', 'block')),
+    note TEXT,
+    created_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,35 @@
+-- Subscribers are told apart by the canonical form of their address,
+-- while `email` keeps the address as they typed it.
+-- Rows stored so far only had their address lowercased here: their domains
+-- could not be converted to punycode from SQL, but `validate_email` only let
+-- through ASCII domains so far.
+BEGIN;
+ALTER TABLE subscriptions ADD COLUMN email_canonical TEXT;
+UPDATE subscriptions SET email_canonical = lower(trim(email));
+-- Keep one row per canonical address: the most engaged one, then the oldest.
+CREATE TEMPORARY TABLE duplicate_subscriptions ON COMMIT DROP AS
+SELECT id FROM (
+    SELECT
+        id,
+        ROW_NUMBER() OVER (
+            PARTITION BY email_canonical
+            ORDER BY
+                CASE status
+                    WHEN 'confirmed' THEN 0
+                    WHEN 'pending_confirmation' THEN 1
+                    ELSE 2
+                END,
+                subscribed_at,
+                id
+        ) AS rank
+    FROM subscriptions
+) ranked
+WHERE rank > 1;
+DELETE FROM subscription_tokens
+WHERE subscriber_id IN (SELECT id FROM duplicate_subscriptions);
+DELETE FROM subscriptions
+WHERE id IN (SELECT id FROM duplicate_subscriptions);
+ALTER TABLE subscriptions ALTER COLUMN email_canonical SET NOT NULL;
+ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
+CREATE UNIQUE INDEX subscriptions_email_canonical_key ON subscriptions (email_canonical);
+COMMIT;
@@ -0,0 +1,18 @@
+-- Preferences subscribers manage themselves, and the log of their changes.
+BEGIN;
+    -- NULL until the subscriber picks topics: they get every one, including new ones.
+    ALTER TABLE subscriptions ADD COLUMN topics TEXT[];
+    ALTER TABLE subscriptions ADD COLUMN frequency TEXT NOT NULL DEFAULT 'every_issue'
+        CHECK (frequency IN ('every_issue', 'weekly', 'monthly'));
+    CREATE TABLE subscriber_preference_changes(
+        id uuid NOT NULL,
+        PRIMARY KEY (id),
+        subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+        field TEXT NOT NULL,
+        old_value TEXT,
+        new_value TEXT,
+        changed_at timestamptz NOT NULL
+    );
+    CREATE INDEX subscriber_preference_changes_subscriber_id_idx
+        ON subscriber_preference_changes (subscriber_id, changed_at);
+COMMIT;
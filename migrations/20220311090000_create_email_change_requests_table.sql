@@ -0,0 +1,13 @@
+-- Changes of address waiting for the new address to be confirmed.
+CREATE TABLE email_change_requests(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+    new_email TEXT NOT NULL,
+    new_email_canonical TEXT NOT NULL,
+    -- HMAC of the token sent to the new address, like `subscription_tokens`.
+    token_hash TEXT NOT NULL UNIQUE,
+    requested_at timestamptz NOT NULL,
+    expires_at timestamptz NOT NULL,
+    confirmed_at timestamptz
+);
@@ -0,0 +1,10 @@
+-- Subscriptions never confirmed, moved out of `subscriptions` by the pending cleanup
+-- when it runs in `archive` mode.
+CREATE TABLE archived_pending_subscriptions(
+    id uuid NOT NULL,
+    PRIMARY KEY (id),
+    email TEXT NOT NULL,
+    name TEXT NOT NULL,
+    subscribed_at timestamptz NOT NULL,
+    archived_at timestamptz NOT NULL
+);
@@ -0,0 +1,22 @@
+-- Delivery history, so that we can tell a subscriber which emails we sent them,
+-- and tombstones of erased subscribers, so that they are not imported back.
+BEGIN;
+    CREATE TABLE email_deliveries(
+        -- The id the email had in the outbox.
+        id uuid NOT NULL,
+        PRIMARY KEY (id),
+        recipient TEXT NOT NULL,
+        subject TEXT NOT NULL,
+        outcome TEXT NOT NULL CHECK (outcome IN ('delivered', 'dropped')),
+        n_attempts SMALLINT NOT NULL,
+        queued_at timestamptz NOT NULL,
+        finished_at timestamptz NOT NULL
+    );
+    CREATE INDEX email_deliveries_recipient_idx ON email_deliveries (recipient);
+    CREATE TABLE erased_subscribers(
+        -- HMAC of the canonical email address, which is not kept.
+        email_hash TEXT NOT NULL,
+        PRIMARY KEY (email_hash),
+        erased_at timestamptz NOT NULL
+    );
+COMMIT;
@@ -0,0 +1,17 @@
+-- Tie queued and delivered emails to their subscriber, whatever spelling of the
+-- address they were sent to, so that access and erasure requests find all of them.
+-- No foreign key: the delivery history is erased along with the subscriber, and
+-- the outbox may still hold emails for subscribers purged since.
+BEGIN;
+    ALTER TABLE email_outbox ADD COLUMN subscriber_id uuid;
+    ALTER TABLE email_deliveries ADD COLUMN subscriber_id uuid;
+    -- Best effort for the existing rows, assuming the default email normalization.
+    UPDATE email_outbox o SET subscriber_id = s.id
+    FROM subscriptions s
+    WHERE s.email_canonical = lower(trim(o.recipient));
+    UPDATE email_deliveries d SET subscriber_id = s.id
+    FROM subscriptions s
+    WHERE s.email_canonical = lower(trim(d.recipient));
+    CREATE INDEX email_outbox_subscriber_id_idx ON email_outbox (subscriber_id);
+    CREATE INDEX email_deliveries_subscriber_id_idx ON email_deliveries (subscriber_id);
+COMMIT;
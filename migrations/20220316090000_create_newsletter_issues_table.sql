@@ -0,0 +1,17 @@
+-- Published issues are kept for the weekly and monthly digests, and subscriptions
+-- remember when their last digest went out.
+BEGIN;
+    CREATE TABLE newsletter_issues(
+        id uuid NOT NULL,
+        PRIMARY KEY (id),
+        title TEXT NOT NULL,
+        html_content TEXT NOT NULL,
+        text_content TEXT NOT NULL,
+        -- NULL for issues sent to every topic.
+        topic TEXT,
+        published_at timestamptz NOT NULL
+    );
+    CREATE INDEX newsletter_issues_published_at_idx ON newsletter_issues (published_at);
+    -- NULL until the first digest: the period then starts at `subscribed_at`.
+    ALTER TABLE subscriptions ADD COLUMN last_digest_at timestamptz;
+COMMIT;
@@ -0,0 +1,10 @@
+-- Subscription form tokens that were already submitted, so that each is accepted once.
+-- Rows are deleted once the token has expired, since it is turned down anyway.
+BEGIN;
+    CREATE TABLE used_form_tokens(
+        form_token TEXT NOT NULL,
+        PRIMARY KEY (form_token),
+        expires_at timestamptz NOT NULL
+    );
+    CREATE INDEX used_form_tokens_expires_at_idx ON used_form_tokens (expires_at);
+COMMIT;
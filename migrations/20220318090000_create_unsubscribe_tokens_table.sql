@@ -0,0 +1,15 @@
+-- Unsubscribe tokens are issued with every email and stored as a keyed hash, like
+-- subscription tokens. The plaintext token of each existing subscriber is carried
+-- over with `is_hashed = false`, so that the links already sent keep working.
+BEGIN;
+    CREATE TABLE unsubscribe_tokens(
+        unsubscribe_token TEXT NOT NULL,
+        PRIMARY KEY (unsubscribe_token),
+        subscriber_id uuid NOT NULL REFERENCES subscriptions (id),
+        is_hashed BOOLEAN NOT NULL
+    );
+    CREATE INDEX unsubscribe_tokens_subscriber_id_idx ON unsubscribe_tokens (subscriber_id);
+    INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id, is_hashed)
+    SELECT unsubscribe_token, id, false FROM subscriptions;
+    ALTER TABLE subscriptions DROP COLUMN unsubscribe_token;
+COMMIT;
@@ -0,0 +1,47 @@
+use crate::session_state::TypedSession;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header::LOCATION;
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpResponse};
+use uuid::Uuid;
+
+/// The id of the operator behind an authenticated request, available to every
+/// handler under the `/admin` scope.
+#[derive(Copy, Clone, Debug)]
+pub struct UserId(Uuid);
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::ops::Deref for UserId {
+    type Target = Uuid;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Sends requests without a logged-in session back to the login form.
+pub async fn reject_anonymous_users(
+    session: TypedSession,
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    match session.get_user_id().map_err(ErrorInternalServerError)? {
+        Some(user_id) => {
+            req.extensions_mut().insert(UserId(user_id));
+            next.call(req).await.map(|r| r.map_into_boxed_body())
+        }
+        None => {
+            let response = HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/login"))
+                .finish();
+            Ok(req.into_response(response))
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use crate::telemetry::spawn_blocking_with_tracing;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Verified against when the username is unknown, so that a login attempt takes
+/// the same time whether or not the user exists.
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$\
+2v5OBfj+jdQ5GHLOAbrGXw$003wUMn+pmOOwJEvmV1VKmUOmj1XsjadhGX8t83ZVb8";
+
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Invalid credentials")]
+    InvalidCredentials,
+    #[error("Failed to retrieve the stored credentials")]
+    DatabaseError(#[source] sqlx::Error),
+    #[error("Failed to verify the password hash")]
+    HashError(#[source] argon2::password_hash::Error),
+    #[error("Failed to run the password verification")]
+    TaskError(#[source] tokio::task::JoinError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CreateUserError {
+    #[error("The username {0} is already taken")]
+    UsernameTaken(String),
+    #[error("Failed to hash the password")]
+    HashError(#[source] argon2::password_hash::Error),
+    #[error("Failed to store the user")]
+    DatabaseError(#[source] sqlx::Error),
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    let mut expected_password_hash = Secret::new(DUMMY_PASSWORD_HASH.to_string());
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool)
+            .await
+            .map_err(AuthError::DatabaseError)?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .map_err(AuthError::TaskError)??;
+
+    user_id.ok_or(AuthError::InvalidCredentials)
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, Secret<String>)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT user_id, password_hash FROM users WHERE username = $1"#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.map(|r| (r.user_id, Secret::new(r.password_hash))))
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: Secret<String>,
+    password_candidate: Secret<String>,
+) -> Result<(), AuthError> {
+    let expected_password_hash =
+        PasswordHash::new(expected_password_hash.expose_secret()).map_err(AuthError::HashError)?;
+    Argon2::default()
+        .verify_password(
+            password_candidate.expose_secret().as_bytes(),
+            &expected_password_hash,
+        )
+        .map_err(|e| match e {
+            argon2::password_hash::Error::Password => AuthError::InvalidCredentials,
+            e => AuthError::HashError(e),
+        })
+}
+
+/// Stores an operator account with a random password, returned so that it can be
+/// shown once: only its hash is kept.
+#[tracing::instrument(name = "Create user", skip(pool))]
+pub async fn create_user(pool: &PgPool, username: &str) -> Result<Secret<String>, CreateUserError> {
+    let password = Secret::new(
+        rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .map(char::from)
+            .take(24)
+            .collect::<String>(),
+    );
+    let password_hash =
+        compute_password_hash(password.clone()).map_err(CreateUserError::HashError)?;
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO users (user_id, username, password_hash)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (username) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        username,
+        password_hash.expose_secret(),
+    )
+    .execute(pool)
+    .await
+    .map_err(CreateUserError::DatabaseError)?
+    .rows_affected();
+    if inserted == 0 {
+        return Err(CreateUserError::UsernameTaken(username.to_owned()));
+    }
+    Ok(password)
+}
+
+/// Argon2id with the parameters recommended by OWASP.
+pub fn compute_password_hash(
+    password: Secret<String>,
+) -> Result<Secret<String>, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let password_hash = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(19456, 2, 1, None)?,
+    )
+    .hash_password(password.expose_secret().as_bytes(), &salt)?
+    .to_string();
+    Ok(Secret::new(password_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_password_hash, verify_password_hash, AuthError, DUMMY_PASSWORD_HASH};
+    use claim::{assert_matches, assert_ok};
+    use secrecy::Secret;
+
+    #[test]
+    fn a_password_matches_its_own_hash() {
+        let password = Secret::new("correct horse battery staple".to_string());
+
+        let hash = compute_password_hash(password.clone()).unwrap();
+
+        assert_ok!(verify_password_hash(hash, password));
+    }
+
+    #[test]
+    fn a_wrong_password_is_reported_as_invalid_credentials() {
+        let hash = compute_password_hash(Secret::new("right".to_string())).unwrap();
+
+        let outcome = verify_password_hash(hash, Secret::new("wrong".to_string()));
+
+        assert_matches!(outcome, Err(AuthError::InvalidCredentials));
+    }
+
+    #[test]
+    fn the_dummy_hash_is_a_valid_phc_string() {
+        let outcome = verify_password_hash(
+            Secret::new(DUMMY_PASSWORD_HASH.to_string()),
+            Secret::new("whatever".to_string()),
+        );
+
+        assert_matches!(outcome, Err(AuthError::InvalidCredentials));
+    }
+}
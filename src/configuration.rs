@@ -1,14 +1,30 @@
+use reqwest::StatusCode;
 use secrecy::{ExposeSecret, Secret};
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::PgConnectOptions;
+use std::net::IpAddr;
+use std::num::NonZeroU32;
 
-use crate::domain::SubscriberEmail;
+use crate::domain::{EmailDomainPolicy, EmailNormalization, SubscriberEmail};
+use crate::email_client::{
+    EmailClient, EmailTransport, FileSinkTransport, PostmarkTransport, RetryPolicy, SmtpTransport,
+};
+use crate::email_templates::EmailTemplates;
+use crate::pending_cleanup::CleanupMode;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
     pub database: DataBaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub rate_limit: RateLimitSettings,
+    pub spam_protection: SpamProtectionSettings,
+    pub email_domain_policy: EmailDomainPolicySettings,
+    pub email_normalization: EmailNormalizationSettings,
+    pub email_templates: EmailTemplateSettings,
+    pub preferences: PreferencesSettings,
+    pub pending_cleanup: PendingCleanupSettings,
+    pub digests: DigestSettings,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -90,23 +106,242 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_ttl_hours: u32,
+    pub hmac_secret: Secret<String>,
+    /// Keys the tombstones of erased addresses. Kept apart from `hmac_secret` so
+    /// that rotating the latter does not let erased addresses be imported again.
+    pub tombstone_secret: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct RateLimitSettings {
+    /// Proxies whose `X-Forwarded-For` header is trusted to name the client.
+    pub trusted_proxies: Vec<IpAddr>,
+    pub per_ip: TokenBucketSettings,
+    pub per_email: TokenBucketSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TokenBucketSettings {
+    /// How many requests can be made in a burst.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub capacity: u32,
+    /// A bucket that never refilled would lock its key out for good.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub refill_per_hour: NonZeroU32,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SpamProtectionSettings {
+    /// Subscription forms sent back sooner than this after being served are dropped.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_form_fill_seconds: u32,
+    /// Subscription forms sent back later than this after being served are dropped,
+    /// so that a bot cannot fetch the form once and replay its token forever.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_form_age_minutes: u32,
+    /// Accept submissions without a form token, as API clients send them, subject
+    /// to the honeypot only. Off unless such clients have to be supported.
+    #[serde(default)]
+    pub allow_tokenless_submissions: bool,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailDomainPolicySettings {
+    /// Reject the domains of the bundled disposable email service list.
+    pub block_disposable: bool,
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+}
+
+impl EmailDomainPolicySettings {
+    pub fn policy(&self) -> Result<EmailDomainPolicy, String> {
+        EmailDomainPolicy::new(
+            self.block_disposable,
+            &self.blocked_domains,
+            &self.allowed_domains,
+        )
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailNormalizationSettings {
+    /// Changing this once subscribers are stored lets case variants of their
+    /// addresses subscribe again.
+    ///
+    /// Subscribers stored before canonical addresses were introduced were given
+    /// a lowercased one by the migration, as if this was `true`: turning it off
+    /// leaves them matched case-insensitively while newer subscribers are not.
+    pub lowercase_local_part: bool,
+}
+
+impl EmailNormalizationSettings {
+    pub fn normalization(&self) -> EmailNormalization {
+        EmailNormalization {
+            lowercase_local_part: self.lowercase_local_part,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailTemplateSettings {
+    /// Holds a directory of templates per locale, `en` being required.
+    pub directory: String,
+}
+
+impl EmailTemplateSettings {
+    pub fn templates(&self) -> Result<EmailTemplates, tera::Error> {
+        EmailTemplates::load(&self.directory)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PreferencesSettings {
+    /// How long the preferences link in an email keeps working.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub link_ttl_hours: u32,
+    /// What newsletter issues can be about, for subscribers to pick from.
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+impl PreferencesSettings {
+    pub fn link_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.link_ttl_hours.into())
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct PendingCleanupSettings {
+    /// Whether the server runs the cleanup in the background.
+    pub enabled: bool,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_age_hours: u32,
+    pub mode: CleanupMode,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: NonZeroU32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_minutes: u32,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DigestSettings {
+    /// How often the server looks for weekly and monthly digests to send.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub interval_minutes: u32,
+}
+
+impl PendingCleanupSettings {
+    pub fn max_age(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.max_age_hours.into())
+    }
 }
 
 impl ApplicationSettings {
     pub fn address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
+
+    pub fn subscription_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.subscription_token_ttl_hours.into())
+    }
 }
 
 #[derive(serde::Deserialize, Clone)]
 pub struct EmailClientSettings {
+    pub provider: EmailProvider,
     pub base_url: String,
     pub sender_email: String,
     pub authorization_token: Secret<String>,
     pub timeout_milliseconds: u64,
+    pub retry: EmailRetrySettings,
+    pub smtp: SmtpSettings,
+    pub file_sink: FileSinkSettings,
+    /// Provider to deliver through when the recipient requires SMTPUTF8
+    /// and the main provider cannot carry it.
+    #[serde(default)]
+    pub smtputf8_provider: Option<EmailProvider>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailProvider {
+    Postmark,
+    Smtp,
+    File,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<Secret<String>>,
+    pub starttls: bool,
+    /// Whether the relay advertises SMTPUTF8.
+    #[serde(default)]
+    pub smtputf8: bool,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct FileSinkSettings {
+    pub directory: String,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailRetrySettings {
+    pub max_attempts: u32,
+    pub base_delay_milliseconds: u64,
+    pub max_delay_milliseconds: u64,
+    pub jitter: bool,
+    pub retryable_status_codes: Vec<u16>,
 }
 
 impl EmailClientSettings {
+    pub fn client(self) -> EmailClient {
+        let sender_email = self.sender().expect("Invalid sender email address");
+        let client = EmailClient::new(sender_email, self.transport(&self.provider));
+        match &self.smtputf8_provider {
+            Some(provider) if *provider != self.provider => {
+                client.with_smtputf8_transport(self.transport(provider))
+            }
+            _ => client,
+        }
+    }
+
+    fn transport(&self, provider: &EmailProvider) -> Box<dyn EmailTransport> {
+        let timeout = self.timeout();
+        match provider {
+            EmailProvider::Postmark => Box::new(PostmarkTransport::new(
+                self.base_url.clone(),
+                self.authorization_token.clone(),
+                timeout,
+                self.retry_policy(),
+            )),
+            EmailProvider::Smtp => {
+                let credentials = self.smtp.username.clone().zip(self.smtp.password.clone());
+                Box::new(
+                    SmtpTransport::new(
+                        &self.smtp.host,
+                        self.smtp.port,
+                        credentials,
+                        self.smtp.starttls,
+                        self.smtp.smtputf8,
+                        timeout,
+                    )
+                    .expect("Failed to build the SMTP transport"),
+                )
+            }
+            EmailProvider::File => Box::new(FileSinkTransport::new(
+                self.file_sink.directory.clone().into(),
+            )),
+        }
+    }
+
     pub fn sender(&self) -> Result<SubscriberEmail, String> {
         SubscriberEmail::parse(self.sender_email.clone())
     }
@@ -114,4 +349,19 @@ impl EmailClientSettings {
     pub fn timeout(&self) -> std::time::Duration {
         std::time::Duration::from_millis(self.timeout_milliseconds)
     }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry.max_attempts.max(1),
+            base_delay: std::time::Duration::from_millis(self.retry.base_delay_milliseconds),
+            max_delay: std::time::Duration::from_millis(self.retry.max_delay_milliseconds),
+            jitter: self.retry.jitter,
+            retryable_status_codes: self
+                .retry
+                .retryable_status_codes
+                .iter()
+                .map(|code| StatusCode::from_u16(*code).expect("Invalid retryable status code"))
+                .collect(),
+        }
+    }
 }
@@ -0,0 +1,267 @@
+use super::SubscriberEmail;
+use std::collections::{HashMap, HashSet};
+
+const DISPOSABLE_EMAIL_DOMAINS: &str = include_str!("disposable_email_domains.txt");
+
+/// The values stored in `email_domain_rules.rule`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DomainRule {
+    Allow,
+    Block,
+}
+
+impl TryFrom<String> for DomainRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "allow" => Ok(Self::Allow),
+            "block" => Ok(Self::Block),
+            other => Err(format!("{} is not a valid email domain rule.", other)),
+        }
+    }
+}
+
+/// Rules maintained by operators in the `email_domain_rules` table, keyed by domain.
+#[derive(Debug, Default)]
+pub struct OperatorDomainRules(pub HashMap<String, DomainRule>);
+
+/// Why an otherwise valid email address cannot subscribe.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DomainRejection {
+    Disposable(String),
+    Blocked(String),
+}
+
+impl DomainRejection {
+    /// A stable identifier of the reason, for clients to branch on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DomainRejection::Disposable(_) => "disposable_domain",
+            DomainRejection::Blocked(_) => "blocked_domain",
+        }
+    }
+}
+
+impl std::fmt::Display for DomainRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DomainRejection::Disposable(domain) => write!(
+                f,
+                "{} is a disposable email service. Please use a permanent address.",
+                domain
+            ),
+            DomainRejection::Blocked(domain) => {
+                write!(f, "Email addresses from {} are not accepted.", domain)
+            }
+        }
+    }
+}
+
+/// Which email domains may subscribe.
+///
+/// The most specific rule wins, a rule on a domain applying to its subdomains too.
+/// At the same level, operator rules override the configured ones, and an allow rule
+/// anywhere exempts the address from the disposable domain list.
+pub struct EmailDomainPolicy {
+    block_disposable: bool,
+    disposable: HashSet<String>,
+    rules: HashMap<String, DomainRule>,
+}
+
+impl EmailDomainPolicy {
+    /// Configured domains are compared in the punycode form subscriber addresses are
+    /// checked in: a domain that has none is rejected.
+    pub fn new(
+        block_disposable: bool,
+        blocked: &[String],
+        allowed: &[String],
+    ) -> Result<Self, String> {
+        let disposable = DISPOSABLE_EMAIL_DOMAINS
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect();
+        let mut rules = HashMap::new();
+        for domain in blocked {
+            rules.insert(normalize_domain(domain)?, DomainRule::Block);
+        }
+        // Allowing a domain that is also blocked is the safer reading of the mistake.
+        for domain in allowed {
+            rules.insert(normalize_domain(domain)?, DomainRule::Allow);
+        }
+        Ok(Self {
+            block_disposable,
+            disposable,
+            rules,
+        })
+    }
+
+    pub fn check(
+        &self,
+        email: &SubscriberEmail,
+        operator_rules: &OperatorDomainRules,
+    ) -> Result<(), DomainRejection> {
+        let domains = candidate_domains(email.domain());
+        for domain in &domains {
+            let rule = operator_rules
+                .0
+                .get(domain)
+                .or_else(|| self.rules.get(domain));
+            match rule {
+                Some(DomainRule::Allow) => return Ok(()),
+                Some(DomainRule::Block) => return Err(DomainRejection::Blocked(domain.clone())),
+                None => {}
+            }
+        }
+        if self.block_disposable {
+            if let Some(domain) = domains.iter().find(|d| self.disposable.contains(*d)) {
+                return Err(DomainRejection::Disposable(domain.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The punycode form of a configured domain. Top-level domains are refused, since
+/// `candidate_domains` never looks them up.
+fn normalize_domain(domain: &str) -> Result<String, String> {
+    let is_valid = |ascii: &str| {
+        ascii.contains('.')
+            && ascii.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            })
+    };
+    match idna::domain_to_ascii(domain.trim().trim_end_matches('.')) {
+        Ok(ascii) if is_valid(&ascii) => Ok(ascii),
+        _ => Err(format!("{} is not a valid email domain.", domain)),
+    }
+}
+
+/// `domain` and its parent domains, most specific first, lowercased.
+/// Top-level domains are left out, since no rule should cover all of `.com`.
+pub fn candidate_domains(domain: &str) -> Vec<String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let labels: Vec<&str> = domain.split('.').collect();
+    (0..labels.len().saturating_sub(1))
+        .map(|i| labels[i..].join("."))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        candidate_domains, DomainRejection, DomainRule, EmailDomainPolicy, OperatorDomainRules,
+    };
+    use crate::domain::SubscriberEmail;
+    use claim::assert_ok;
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.into()).unwrap()
+    }
+
+    fn no_operator_rules() -> OperatorDomainRules {
+        OperatorDomainRules::default()
+    }
+
+    #[test]
+    fn candidate_domains_go_from_most_to_least_specific() {
+        assert_eq!(
+            candidate_domains("Mail.Example.co.uk"),
+            vec!["mail.example.co.uk", "example.co.uk", "co.uk"]
+        );
+    }
+
+    #[test]
+    fn bundled_disposable_domains_are_rejected() {
+        let policy = EmailDomainPolicy::new(true, &[], &[]).unwrap();
+
+        assert_eq!(
+            policy.check(&email("ursula@Mailinator.com"), &no_operator_rules()),
+            Err(DomainRejection::Disposable("mailinator.com".into()))
+        );
+        assert_eq!(
+            policy.check(&email("ursula@inbox.yopmail.com"), &no_operator_rules()),
+            Err(DomainRejection::Disposable("yopmail.com".into()))
+        );
+    }
+
+    #[test]
+    fn disposable_domains_can_be_let_through() {
+        let policy = EmailDomainPolicy::new(false, &[], &[]).unwrap();
+
+        assert_ok!(policy.check(&email("ursula@mailinator.com"), &no_operator_rules()));
+    }
+
+    #[test]
+    fn configured_blocked_domains_are_rejected() {
+        let policy = EmailDomainPolicy::new(true, &["example.org".into()], &[]).unwrap();
+
+        assert_eq!(
+            policy.check(&email("ursula@example.org"), &no_operator_rules()),
+            Err(DomainRejection::Blocked("example.org".into()))
+        );
+        assert_ok!(policy.check(&email("ursula@example.com"), &no_operator_rules()));
+    }
+
+    #[test]
+    fn an_allow_rule_exempts_a_disposable_domain() {
+        let policy = EmailDomainPolicy::new(true, &[], &["mailinator.com".into()]).unwrap();
+
+        assert_ok!(policy.check(&email("ursula@mailinator.com"), &no_operator_rules()));
+    }
+
+    #[test]
+    fn operator_rules_override_the_configured_ones() {
+        let policy = EmailDomainPolicy::new(true, &["example.org".into()], &[]).unwrap();
+        let operator_rules =
+            OperatorDomainRules([("example.org".to_string(), DomainRule::Allow)].into());
+
+        assert_ok!(policy.check(&email("ursula@example.org"), &operator_rules));
+    }
+
+    #[test]
+    fn configured_domains_are_matched_in_punycode() {
+        let policy = EmailDomainPolicy::new(true, &["Bücher.Example".into()], &[]).unwrap();
+
+        assert_eq!(
+            policy.check(&email("ursula@bücher.example"), &no_operator_rules()),
+            Err(DomainRejection::Blocked("xn--bcher-kva.example".into()))
+        );
+        assert_eq!(
+            policy.check(&email("ursula@xn--bcher-kva.example"), &no_operator_rules()),
+            Err(DomainRejection::Blocked("xn--bcher-kva.example".into()))
+        );
+    }
+
+    #[test]
+    fn invalid_configured_domains_are_rejected() {
+        for domain in [
+            "",
+            "com",
+            "example..org",
+            "exa mple.org",
+            "ursula@example.org",
+        ] {
+            assert!(
+                EmailDomainPolicy::new(true, &[domain.into()], &[]).is_err(),
+                "Accepted {:?}",
+                domain
+            );
+        }
+    }
+
+    #[test]
+    fn the_most_specific_rule_wins() {
+        let policy =
+            EmailDomainPolicy::new(true, &["example.org".into()], &["staff.example.org".into()])
+                .unwrap();
+
+        assert_ok!(policy.check(&email("ursula@staff.example.org"), &no_operator_rules()));
+        assert_eq!(
+            policy.check(&email("ursula@lists.example.org"), &no_operator_rules()),
+            Err(DomainRejection::Blocked("example.org".into()))
+        );
+    }
+}
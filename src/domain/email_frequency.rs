@@ -0,0 +1,66 @@
+/// The values stored in `subscriptions.frequency`: how often a subscriber wants to hear from us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailFrequency {
+    EveryIssue,
+    Weekly,
+    Monthly,
+}
+
+impl EmailFrequency {
+    pub const ALL: [EmailFrequency; 3] = [
+        EmailFrequency::EveryIssue,
+        EmailFrequency::Weekly,
+        EmailFrequency::Monthly,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmailFrequency::EveryIssue => "every_issue",
+            EmailFrequency::Weekly => "weekly",
+            EmailFrequency::Monthly => "monthly",
+        }
+    }
+
+    /// How the choice is worded on the preferences page.
+    pub fn label(&self) -> &'static str {
+        match self {
+            EmailFrequency::EveryIssue => "Every issue",
+            EmailFrequency::Weekly => "A weekly digest",
+            EmailFrequency::Monthly => "A monthly digest",
+        }
+    }
+}
+
+impl TryFrom<String> for EmailFrequency {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "every_issue" => Ok(EmailFrequency::EveryIssue),
+            "weekly" => Ok(EmailFrequency::Weekly),
+            "monthly" => Ok(EmailFrequency::Monthly),
+            other => Err(format!("{} is not a valid email frequency.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmailFrequency;
+    use claim::assert_err;
+
+    #[test]
+    fn every_frequency_round_trips_through_its_database_representation() {
+        for frequency in EmailFrequency::ALL {
+            assert_eq!(
+                EmailFrequency::try_from(frequency.as_str().to_string()),
+                Ok(frequency)
+            );
+        }
+    }
+
+    #[test]
+    fn an_unknown_frequency_is_rejected() {
+        assert_err!(EmailFrequency::try_from("hourly".to_string()));
+    }
+}
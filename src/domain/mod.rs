@@ -1,8 +1,16 @@
+mod email_domain_policy;
+mod email_frequency;
 mod new_subscriber;
 mod subscriber_email;
 mod subscriber_name;
+mod subscription_status;
 
+pub use email_domain_policy::{
+    candidate_domains, DomainRejection, DomainRule, EmailDomainPolicy, OperatorDomainRules,
+};
+pub use email_frequency::EmailFrequency;
 pub use new_subscriber::NewSubscriber;
 
-pub use subscriber_email::SubscriberEmail;
+pub use subscriber_email::{EmailNormalization, SubscriberEmail};
 pub use subscriber_name::SubscriberName;
+pub use subscription_status::SubscriptionStatus;
@@ -1,27 +1,134 @@
+use unicode_normalization::UnicodeNormalization;
 use validator::validate_email;
 
+/// RFC 5321 caps the local part at 64 octets, UTF-8 encoded ones included.
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+
+/// How addresses are brought to their canonical form.
+#[derive(Debug, Clone, Copy)]
+pub struct EmailNormalization {
+    /// Strictly speaking, only the receiving server knows whether `Foo` and `foo`
+    /// are the same mailbox, but in practice they always are.
+    pub lowercase_local_part: bool,
+}
+
+impl Default for EmailNormalization {
+    fn default() -> Self {
+        Self {
+            lowercase_local_part: true,
+        }
+    }
+}
+
+/// A valid email address, as typed by the subscriber, along with the canonical form
+/// used to tell subscribers apart: trimmed, with the domain lowercased and converted
+/// to punycode.
+///
+/// Local parts may contain non-ASCII characters (RFC 6531). Such addresses can only
+/// be delivered over transports that speak SMTPUTF8.
 #[derive(Debug)]
-pub struct SubscriberEmail(String);
+pub struct SubscriberEmail {
+    original: String,
+    canonical: String,
+}
 
 impl SubscriberEmail {
     pub fn parse(email: String) -> Result<Self, String> {
-        if validate_email(&email) {
-            Ok(Self(email))
+        Self::parse_with(email, EmailNormalization::default())
+    }
+
+    pub fn parse_with(email: String, normalization: EmailNormalization) -> Result<Self, String> {
+        let original = email.trim();
+        let canonical = match original.rsplit_once('@') {
+            Some((local, domain)) if is_valid(original, local, domain) => {
+                idna::domain_to_ascii(domain).ok().map(|domain| {
+                    // Unicode local parts are compared in NFC, as RFC 6532 recommends.
+                    let local: String = local.nfc().collect();
+                    let local = if normalization.lowercase_local_part {
+                        local.to_lowercase()
+                    } else {
+                        local
+                    };
+                    format!("{}@{}", local, domain)
+                })
+            }
+            _ => None,
+        };
+        match canonical {
+            Some(canonical) => Ok(Self {
+                original: original.to_owned(),
+                canonical,
+            }),
+            None => Err(format!("{} is not a valid subriber email.", email)),
+        }
+    }
+
+    pub fn canonical(&self) -> &str {
+        &self.canonical
+    }
+
+    /// The canonical domain, everything after the last `@`.
+    pub fn domain(&self) -> &str {
+        self.canonical.rsplit('@').next().unwrap_or_default()
+    }
+
+    /// Whether delivering to this address needs the SMTPUTF8 extension.
+    /// Non-ASCII domains do not: they can be sent in their punycode form.
+    pub fn requires_smtputf8(&self) -> bool {
+        !self.local_part().is_ascii()
+    }
+
+    /// The address to hand over to a transport: as typed, except for the domain
+    /// which is in punycode whenever that spares the need for SMTPUTF8.
+    pub fn delivery_address(&self) -> String {
+        if self.requires_smtputf8() {
+            self.original.clone()
         } else {
-            Err(format!("{} is not a valid subriber email.", email))
+            format!("{}@{}", self.local_part(), self.domain())
         }
     }
+
+    fn local_part(&self) -> &str {
+        self.original
+            .rsplit_once('@')
+            .map(|(local, _)| local)
+            .unwrap_or_default()
+    }
 }
 
+fn is_valid(email: &str, local: &str, domain: &str) -> bool {
+    if local.is_ascii() {
+        return validate_email(email);
+    }
+    // `validate_email` only knows about ASCII local parts: check the domain on its own,
+    // behind a placeholder local part, and the Unicode local part here.
+    is_valid_utf8_local_part(local) && validate_email(format!("user@{}", domain))
+}
+
+/// An RFC 6531 dot-atom: ASCII `atext` or any non-ASCII character, in non-empty
+/// dot-separated atoms. Quoted local parts are not accepted, as for ASCII addresses.
+fn is_valid_utf8_local_part(local: &str) -> bool {
+    local.len() <= MAX_LOCAL_PART_LENGTH
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom.chars().all(|c| {
+                    c.is_ascii_alphanumeric()
+                        || "!#$%&'*+-/=?^_`{|}~".contains(c)
+                        || (!c.is_ascii() && !c.is_control() && !c.is_whitespace())
+                })
+        })
+}
+
+/// The address as typed, which is what we display and deliver to.
 impl AsRef<str> for SubscriberEmail {
     fn as_ref(&self) -> &str {
-        &self.0
+        &self.original
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::SubscriberEmail;
+    use super::{EmailNormalization, SubscriberEmail};
     use claim::assert_err;
     use fake::faker::internet::en::SafeEmail;
     use fake::Fake;
@@ -44,6 +151,71 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn the_canonical_form_is_trimmed_and_lowercased() {
+        let email = SubscriberEmail::parse(" Ursula.Le.Guin@Example.COM ".into()).unwrap();
+
+        assert_eq!(email.canonical(), "ursula.le.guin@example.com");
+        assert_eq!(email.as_ref(), "Ursula.Le.Guin@Example.COM");
+    }
+
+    #[test]
+    fn the_local_part_can_keep_its_case() {
+        let normalization = EmailNormalization {
+            lowercase_local_part: false,
+        };
+        let email =
+            SubscriberEmail::parse_with("Ursula@Example.com".into(), normalization).unwrap();
+
+        assert_eq!(email.canonical(), "Ursula@example.com");
+    }
+
+    #[test]
+    fn unicode_domains_are_converted_to_punycode() {
+        let email = SubscriberEmail::parse("ursula@BÜCHER.example".into()).unwrap();
+
+        assert_eq!(email.canonical(), "ursula@xn--bcher-kva.example");
+        assert_eq!(email.domain(), "xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn unicode_local_parts_are_accepted_and_round_trip() {
+        let email = SubscriberEmail::parse("Пётр.Ильич@例え.jp".into()).unwrap();
+
+        assert_eq!(email.as_ref(), "Пётр.Ильич@例え.jp");
+        assert_eq!(email.canonical(), "пётр.ильич@xn--r8jz45g.jp");
+        assert!(email.requires_smtputf8());
+        assert_eq!(email.delivery_address(), "Пётр.Ильич@例え.jp");
+    }
+
+    #[test]
+    fn unicode_domains_alone_do_not_require_smtputf8() {
+        let email = SubscriberEmail::parse("ursula@bücher.example".into()).unwrap();
+
+        assert!(!email.requires_smtputf8());
+        assert_eq!(email.delivery_address(), "ursula@xn--bcher-kva.example");
+    }
+
+    #[test]
+    fn unicode_local_parts_are_compared_in_nfc() {
+        let composed = SubscriberEmail::parse("jos\u{e9}@example.com".into()).unwrap();
+        let decomposed = SubscriberEmail::parse("jose\u{301}@example.com".into()).unwrap();
+
+        assert_eq!(composed.canonical(), decomposed.canonical());
+    }
+
+    #[test]
+    fn malformed_unicode_local_parts_are_rejected() {
+        assert_err!(SubscriberEmail::parse(".пётр@example.com".into()));
+        assert_err!(SubscriberEmail::parse("пётр..ильич@example.com".into()));
+        assert_err!(SubscriberEmail::parse("пётр ильич@example.com".into()));
+        assert_err!(SubscriberEmail::parse("пётр@".into()));
+        assert_err!(SubscriberEmail::parse(format!(
+            "{}@example.com",
+            "ж".repeat(33)
+        )));
+    }
+
     #[derive(Debug, Clone)]
     struct ValidEmailFixture(pub String);
 
@@ -0,0 +1,62 @@
+/// The values stored in `subscriptions.status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+}
+
+impl SubscriptionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubscriptionStatus::PendingConfirmation => "pending_confirmation",
+            SubscriptionStatus::Confirmed => "confirmed",
+            SubscriptionStatus::Unsubscribed => "unsubscribed",
+        }
+    }
+}
+
+impl std::fmt::Display for SubscriptionStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl TryFrom<String> for SubscriptionStatus {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "pending_confirmation" => Ok(SubscriptionStatus::PendingConfirmation),
+            "confirmed" => Ok(SubscriptionStatus::Confirmed),
+            "unsubscribed" => Ok(SubscriptionStatus::Unsubscribed),
+            other => Err(format!("{} is not a valid subscription status.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionStatus;
+    use claim::assert_err;
+
+    #[test]
+    fn every_status_round_trips_through_its_database_representation() {
+        for status in [
+            SubscriptionStatus::PendingConfirmation,
+            SubscriptionStatus::Confirmed,
+            SubscriptionStatus::Unsubscribed,
+        ] {
+            assert_eq!(
+                SubscriptionStatus::try_from(status.as_str().to_string()),
+                Ok(status)
+            );
+        }
+    }
+
+    #[test]
+    fn an_unknown_status_is_rejected() {
+        assert_err!(SubscriptionStatus::try_from("banned".to_string()));
+    }
+}
@@ -0,0 +1,71 @@
+use super::{mime_message, Email, EmailError, EmailTransport};
+use chrono::Utc;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Writes every email as an `.eml` file in a directory instead of sending it.
+/// Meant for local development.
+pub struct FileSinkTransport {
+    directory: PathBuf,
+}
+
+impl FileSinkTransport {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for FileSinkTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = mime_message(email)?;
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let file_name = format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%d%H%M%S"),
+            Uuid::new_v4()
+        );
+        let path = self.directory.join(file_name);
+        tokio::fs::write(&path, message.formatted()).await?;
+        tracing::info!("Email written to {}", path.display());
+        Ok(())
+    }
+
+    fn supports_smtputf8(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, FileSinkTransport};
+    use claim::assert_ok;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn send_email_writes_an_eml_file_to_the_directory() {
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let email_client = EmailClient::new(
+            SubscriberEmail::parse("sender@example.com".into()).unwrap(),
+            Box::new(FileSinkTransport::new(directory.clone())),
+        );
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        let outcome = email_client
+            .send_email(recipient, "Welcome", "<p>Hello</p>", "Hello", &[])
+            .await;
+
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let content = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(content.contains("To: ursula@example.com"));
+        assert!(content.contains("Subject: Welcome"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
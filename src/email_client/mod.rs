@@ -0,0 +1,205 @@
+mod file_sink;
+mod postmark;
+mod smtp;
+
+pub use file_sink::FileSinkTransport;
+pub use postmark::{PostmarkTransport, RetryPolicy};
+pub use smtp::SmtpTransport;
+
+use crate::domain::SubscriberEmail;
+use lettre::message::header::{HeaderName, HeaderValue};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+
+/// A way of getting an email out of the door.
+#[async_trait::async_trait]
+pub trait EmailTransport: Send + Sync {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError>;
+
+    /// Whether addresses with a non-ASCII local part can go through this transport.
+    fn supports_smtputf8(&self) -> bool;
+}
+
+#[derive(Debug)]
+pub struct Email<'a> {
+    pub from: &'a SubscriberEmail,
+    pub to: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_body: &'a str,
+    pub text_body: &'a str,
+    pub headers: &'a [EmailHeader],
+}
+
+/// A custom header to attach to an outgoing email, e.g. `List-Unsubscribe`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmailError {
+    #[error("Failed to send the email through the Postmark API")]
+    Postmark(#[from] reqwest::Error),
+    #[error("Failed to send the email over SMTP")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Failed to build the email message")]
+    Message(#[from] lettre::error::Error),
+    #[error("The email address is not a valid mailbox")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("The email header name is not valid")]
+    HeaderName(#[from] lettre::message::header::InvalidHeaderName),
+    #[error("Failed to write the email to disk")]
+    Io(#[from] std::io::Error),
+    #[error("No configured transport can deliver to an address that requires SMTPUTF8")]
+    Smtputf8Unsupported,
+}
+
+pub struct EmailClient {
+    sender: SubscriberEmail,
+    transport: Box<dyn EmailTransport>,
+    smtputf8_transport: Option<Box<dyn EmailTransport>>,
+}
+
+impl EmailClient {
+    pub fn new(sender: SubscriberEmail, transport: Box<dyn EmailTransport>) -> Self {
+        EmailClient {
+            sender,
+            transport,
+            smtputf8_transport: None,
+        }
+    }
+
+    /// Route emails that require SMTPUTF8 through `transport` whenever the main
+    /// transport cannot carry them.
+    pub fn with_smtputf8_transport(mut self, transport: Box<dyn EmailTransport>) -> Self {
+        self.smtputf8_transport = Some(transport);
+        self
+    }
+
+    pub async fn send_email(
+        &self,
+        recepient: SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        headers: &[EmailHeader],
+    ) -> Result<(), EmailError> {
+        let email = Email {
+            from: &self.sender,
+            to: &recepient,
+            subject,
+            html_body: html_content,
+            text_body: text_content,
+            headers,
+        };
+        if !(self.sender.requires_smtputf8() || recepient.requires_smtputf8()) {
+            return self.transport.send(&email).await;
+        }
+        let transport = std::iter::once(&self.transport)
+            .chain(&self.smtputf8_transport)
+            .find(|t| t.supports_smtputf8())
+            .ok_or(EmailError::Smtputf8Unsupported)?;
+        transport.send(&email).await
+    }
+}
+
+/// Render an email as a MIME message with both a plain text and an HTML alternative.
+fn mime_message(email: &Email<'_>) -> Result<Message, EmailError> {
+    let mut builder = Message::builder()
+        .from(email.from.delivery_address().parse::<Mailbox>()?)
+        .to(email.to.delivery_address().parse::<Mailbox>()?)
+        .subject(email.subject);
+    for header in email.headers {
+        let name = HeaderName::new_from_ascii(header.name.clone())?;
+        builder = builder.raw_header(HeaderValue::new(name, header.value.clone()));
+    }
+    let message = builder.multipart(MultiPart::alternative_plain_html(
+        email.text_body.to_string(),
+        email.html_body.to_string(),
+    ))?;
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Email, EmailClient, EmailError, EmailTransport};
+    use crate::domain::SubscriberEmail;
+    use claim::assert_ok;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingTransport {
+        smtputf8: bool,
+        sent: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl EmailTransport for CountingTransport {
+        async fn send(&self, _email: &Email<'_>) -> Result<(), EmailError> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn supports_smtputf8(&self) -> bool {
+            self.smtputf8
+        }
+    }
+
+    fn transport(smtputf8: bool) -> (Box<dyn EmailTransport>, Arc<AtomicUsize>) {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let transport = CountingTransport {
+            smtputf8,
+            sent: sent.clone(),
+        };
+        (Box::new(transport), sent)
+    }
+
+    fn email(s: &str) -> SubscriberEmail {
+        SubscriberEmail::parse(s.into()).unwrap()
+    }
+
+    async fn send_to(client: &EmailClient, recipient: &str) -> Result<(), EmailError> {
+        client
+            .send_email(email(recipient), "Welcome", "<p>Hi</p>", "Hi", &[])
+            .await
+    }
+
+    #[tokio::test]
+    async fn ascii_recipients_go_through_the_main_transport() {
+        let (main, main_sent) = transport(false);
+        let (fallback, fallback_sent) = transport(true);
+        let client =
+            EmailClient::new(email("sender@example.com"), main).with_smtputf8_transport(fallback);
+
+        assert_ok!(send_to(&client, "ursula@bücher.example").await);
+
+        assert_eq!(main_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(fallback_sent.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn smtputf8_recipients_are_routed_to_a_transport_that_supports_it() {
+        let (main, main_sent) = transport(false);
+        let (fallback, fallback_sent) = transport(true);
+        let client =
+            EmailClient::new(email("sender@example.com"), main).with_smtputf8_transport(fallback);
+
+        assert_ok!(send_to(&client, "пётр@example.com").await);
+
+        assert_eq!(main_sent.load(Ordering::SeqCst), 0);
+        assert_eq!(fallback_sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn smtputf8_recipients_are_refused_without_a_capable_transport() {
+        let (main, main_sent) = transport(false);
+        let client = EmailClient::new(email("sender@example.com"), main);
+
+        let outcome = send_to(&client, "пётр@example.com").await;
+
+        assert!(matches!(outcome, Err(EmailError::Smtputf8Unsupported)));
+        assert_eq!(main_sent.load(Ordering::SeqCst), 0);
+    }
+}
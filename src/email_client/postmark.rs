@@ -0,0 +1,368 @@
+use super::{Email, EmailError, EmailHeader, EmailTransport};
+use rand::{thread_rng, Rng};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode};
+use secrecy::{ExposeSecret, Secret};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Delivers emails through Postmark's `/email` HTTP API.
+pub struct PostmarkTransport {
+    http_client: Client,
+    base_url: String,
+    authorization_token: Secret<String>,
+    retry_policy: RetryPolicy,
+}
+
+/// How `PostmarkTransport` retries requests that failed for transient reasons.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: bool,
+    pub retryable_status_codes: Vec<StatusCode>,
+}
+
+impl RetryPolicy {
+    /// Exponential backoff for the given (1-based) attempt, capped at `max_delay`.
+    /// With jitter enabled, a random delay between zero and the backoff is picked instead.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = exponential.min(self.max_delay);
+        if self.jitter {
+            delay.mul_f64(thread_rng().gen_range(0.0..=1.0))
+        } else {
+            delay
+        }
+    }
+
+    fn is_retryable(&self, status: StatusCode) -> bool {
+        self.retryable_status_codes.contains(&status)
+    }
+}
+
+impl PostmarkTransport {
+    pub fn new(
+        base_url: String,
+        authorization_token: Secret<String>,
+        timeout: Duration,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        PostmarkTransport {
+            http_client: Client::builder().timeout(timeout).build().unwrap(),
+            base_url,
+            authorization_token,
+            retry_policy,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for PostmarkTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let url = format!("{}/email", self.base_url);
+        let (from, to) = (email.from.delivery_address(), email.to.delivery_address());
+        let request_body = SendEmailRequest {
+            from: &from,
+            to: &to,
+            subject: email.subject,
+            html_body: email.html_body,
+            text_body: email.text_body,
+            headers: email.headers,
+        };
+        let mut attempt = 1;
+        loop {
+            let outcome = self
+                .http_client
+                .post(&url)
+                .header(
+                    "X-Postmark-Server-Token",
+                    self.authorization_token.expose_secret(),
+                )
+                .json(&request_body)
+                .send()
+                .await;
+            let is_last_attempt = attempt >= self.retry_policy.max_attempts;
+            let delay = match outcome {
+                Ok(response) => {
+                    let error = match response.error_for_status_ref() {
+                        Ok(_) => return Ok(()),
+                        Err(e) => e,
+                    };
+                    if is_last_attempt || !self.retry_policy.is_retryable(response.status()) {
+                        return Err(error.into());
+                    }
+                    match retry_after(&response) {
+                        // The provider asked us to back off for longer than we are
+                        // willing to wait: give up and let the caller retry later.
+                        Some(delay) if delay > self.retry_policy.max_delay => {
+                            return Err(error.into())
+                        }
+                        Some(delay) => delay,
+                        None => self.retry_policy.backoff(attempt),
+                    }
+                }
+                Err(e) => {
+                    if is_last_attempt || !(e.is_timeout() || e.is_connect()) {
+                        return Err(e.into());
+                    }
+                    self.retry_policy.backoff(attempt)
+                }
+            };
+            tracing::warn!(
+                "Email delivery attempt {} failed, retrying in {:?}",
+                attempt,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// We have no guarantee that Postmark accepts non-ASCII mailbox names.
+    fn supports_smtputf8(&self) -> bool {
+        false
+    }
+}
+
+/// Parse a `Retry-After` header, given either as a number of seconds or as an HTTP date.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+        .to_std()
+        .ok()
+        .or(Some(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, PostmarkTransport, RetryPolicy};
+    use claim::{assert_err, assert_ok};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use reqwest::StatusCode;
+    use secrecy::Secret;
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &wiremock::Request) -> bool {
+            match serde_json::from_slice::<serde_json::Value>(&request.body) {
+                Ok(body) => ["From", "To", "Subject", "HtmlBody", "TextBody", "Headers"]
+                    .iter()
+                    .all(|field| body.get(field).is_some()),
+                Err(_) => false,
+            }
+        }
+    }
+
+    /// Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+    /// Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+    /// Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+    /// Get a test instance of `EmailClient` backed by Postmark.
+    fn email_client(base_url: String) -> EmailClient {
+        let transport = PostmarkTransport::new(
+            base_url,
+            Secret::new(Faker.fake()),
+            std::time::Duration::from_millis(200),
+            RetryPolicy {
+                max_attempts: 3,
+                base_delay: std::time::Duration::from_millis(10),
+                max_delay: std::time::Duration::from_secs(2),
+                jitter: true,
+                retryable_status_codes: vec![
+                    StatusCode::TOO_MANY_REQUESTS,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                ],
+            },
+        );
+        EmailClient::new(email(), Box::new(transport))
+    }
+
+    #[tokio::test]
+    async fn send_email_fires_a_request_to_base_url() {
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .and(header("Content-Type", "application/json"))
+            .and(header_exists("X-PostMark-Server-Token"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_keeps_returning_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            // Not a 200 anymore!
+            .respond_with(ResponseTemplate::new(500))
+            // One request per allowed attempt
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let response = ResponseTemplate::new(200)
+            // 3 minutes!
+            .set_delay(std::time::Duration::from_secs(180));
+
+        Mock::given(any())
+            .respond_with(response)
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_recovers_after_a_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_non_retryable_status_codes() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_waits_for_retry_after_on_429() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let start = std::time::Instant::now();
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+        // Assert
+        assert_ok!(outcome);
+        assert!(start.elapsed() >= std::time::Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_if_retry_after_exceeds_the_max_delay() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content(), &[])
+            .await;
+        // Assert
+        assert_err!(outcome);
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    headers: &'a [EmailHeader],
+}
@@ -0,0 +1,165 @@
+use super::{mime_message, Email, EmailError, EmailTransport};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+use std::time::Duration;
+
+/// Delivers emails to an SMTP relay.
+pub struct SmtpTransport {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    smtputf8: bool,
+}
+
+impl SmtpTransport {
+    pub fn new(
+        host: &str,
+        port: u16,
+        credentials: Option<(String, Secret<String>)>,
+        starttls: bool,
+        smtputf8: bool,
+        timeout: Duration,
+    ) -> Result<Self, EmailError> {
+        let builder = if starttls {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        };
+        let mut builder = builder.port(port).timeout(Some(timeout));
+        if let Some((username, password)) = credentials {
+            builder = builder.credentials(Credentials::new(
+                username,
+                password.expose_secret().to_owned(),
+            ));
+        }
+        Ok(Self {
+            mailer: builder.build(),
+            smtputf8,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl EmailTransport for SmtpTransport {
+    async fn send(&self, email: &Email<'_>) -> Result<(), EmailError> {
+        let message = mime_message(email)?;
+        self.mailer.send(message).await?;
+        Ok(())
+    }
+
+    /// Only the relay knows for sure, by advertising SMTPUTF8: this is our configured
+    /// expectation. Should the relay turn out not to support it, sending fails.
+    fn supports_smtputf8(&self) -> bool {
+        self.smtputf8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{EmailClient, EmailHeader, SmtpTransport};
+    use claim::{assert_err, assert_ok};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// A bare-bones SMTP server accepting every message it is handed.
+    /// Returns its port and the raw `DATA` payloads it received.
+    async fn smtp_stand_in(reject_data: bool) -> (u16, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        let received = messages.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let received = received.clone();
+                tokio::spawn(async move {
+                    let (reader, mut writer) = stream.into_split();
+                    let mut lines = BufReader::new(reader).lines();
+                    writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let command = line.to_ascii_uppercase();
+                        let reply: &[u8] = if command.starts_with("EHLO") {
+                            b"250 localhost\r\n"
+                        } else if command.starts_with("DATA") {
+                            writer
+                                .write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")
+                                .await
+                                .unwrap();
+                            let mut data = String::new();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                if line == "." {
+                                    break;
+                                }
+                                data.push_str(&line);
+                                data.push('\n');
+                            }
+                            if reject_data {
+                                b"554 Transaction failed\r\n"
+                            } else {
+                                received.lock().unwrap().push(data);
+                                b"250 OK\r\n"
+                            }
+                        } else if command.starts_with("QUIT") {
+                            writer.write_all(b"221 Bye\r\n").await.unwrap();
+                            break;
+                        } else {
+                            b"250 OK\r\n"
+                        };
+                        writer.write_all(reply).await.unwrap();
+                    }
+                });
+            }
+        });
+        (port, messages)
+    }
+
+    fn email_client(port: u16) -> EmailClient {
+        let transport = SmtpTransport::new(
+            "127.0.0.1",
+            port,
+            None,
+            false,
+            false,
+            std::time::Duration::from_secs(2),
+        )
+        .unwrap();
+        EmailClient::new(
+            SubscriberEmail::parse("sender@example.com".into()).unwrap(),
+            Box::new(transport),
+        )
+    }
+
+    #[tokio::test]
+    async fn send_email_delivers_a_multipart_message_to_the_smtp_server() {
+        let (port, messages) = smtp_stand_in(false).await;
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+        let headers = [EmailHeader {
+            name: "List-Unsubscribe-Post".into(),
+            value: "List-Unsubscribe=One-Click".into(),
+        }];
+
+        let outcome = email_client(port)
+            .send_email(recipient, "Welcome", "<p>Hello</p>", "Hello", &headers)
+            .await;
+
+        assert_ok!(outcome);
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("To: ursula@example.com"));
+        assert!(messages[0].contains("Subject: Welcome"));
+        assert!(messages[0].contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+        assert!(messages[0].contains("multipart/alternative"));
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_smtp_server_rejects_the_message() {
+        let (port, _) = smtp_stand_in(true).await;
+        let recipient = SubscriberEmail::parse("ursula@example.com".into()).unwrap();
+
+        let outcome = email_client(port)
+            .send_email(recipient, "Welcome", "<p>Hello</p>", "Hello", &[])
+            .await;
+
+        assert_err!(outcome);
+    }
+}
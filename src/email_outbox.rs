@@ -0,0 +1,252 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, EmailError, EmailHeader};
+use chrono::{DateTime, Utc};
+use sqlx::types::Json;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::field::display;
+use tracing::Span;
+use uuid::Uuid;
+
+/// Number of delivery attempts after which an email is dropped from the outbox.
+const MAX_ATTEMPTS: i16 = 5;
+/// Delay before the first retry, doubled on every subsequent failure.
+const RETRY_BASE_DELAY_SECONDS: i64 = 30;
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// How an email left the outbox, as kept in `email_deliveries`.
+#[derive(Debug, Clone, Copy)]
+enum DeliveryOutcome {
+    Delivered,
+    Dropped,
+}
+
+impl DeliveryOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryOutcome::Delivered => "delivered",
+            DeliveryOutcome::Dropped => "dropped",
+        }
+    }
+}
+
+struct OutboxEmail {
+    id: Uuid,
+    /// `None` for the emails queued before the outbox recorded it.
+    subscriber_id: Option<Uuid>,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    headers: Json<Vec<EmailHeader>>,
+    n_retries: i16,
+    created_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(
+    name = "Queue an email in the outbox",
+    skip(transaction, recipient, subject, html_body, text_body, headers)
+)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_body: &str,
+    text_body: &str,
+    headers: &[EmailHeader],
+) -> Result<Uuid, sqlx::Error> {
+    let email_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_outbox
+            (id, subscriber_id, recipient, subject, html_body, text_body, headers)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        email_id,
+        subscriber_id,
+        recipient.as_ref(),
+        subject,
+        html_body,
+        text_body,
+        Json(headers) as _
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(email_id)
+}
+
+/// Drain the outbox forever, sleeping whenever there is nothing left to deliver.
+pub async fn run_worker_until_stopped(pool: PgPool, email_client: Arc<EmailClient>) {
+    loop {
+        match try_execute_task(&pool, &email_client).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+            Err(_) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Deliver an email from the outbox",
+    skip(pool, email_client),
+    fields(email_id = tracing::field::Empty)
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (transaction, email) = match dequeue_task(pool).await? {
+        Some(task) => task,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+    Span::current().record("email_id", display(email.id));
+
+    match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => {
+            match email_client
+                .send_email(
+                    recipient,
+                    &email.subject,
+                    &email.html_body,
+                    &email.text_body,
+                    &email.headers,
+                )
+                .await
+            {
+                Ok(()) => delete_task(transaction, &email, DeliveryOutcome::Delivered).await?,
+                // Retrying cannot help until the email transports are reconfigured.
+                Err(e @ EmailError::Smtputf8Unsupported) => {
+                    tracing::error!("Dropping outbox email: {}", e);
+                    delete_task(transaction, &email, DeliveryOutcome::Dropped).await?;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to deliver email from the outbox: {:?}", e);
+                    reschedule_task(transaction, &email).await?;
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!("Dropping outbox email with an invalid recipient: {}", e);
+            delete_task(transaction, &email, DeliveryOutcome::Dropped).await?;
+        }
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+#[tracing::instrument(name = "Dequeue an email from the outbox", skip(pool))]
+async fn dequeue_task(
+    pool: &PgPool,
+) -> Result<Option<(Transaction<'static, Postgres>, OutboxEmail)>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"
+        SELECT
+            id,
+            subscriber_id,
+            recipient,
+            subject,
+            html_body,
+            text_body,
+            headers AS "headers: Json<Vec<EmailHeader>>",
+            n_retries,
+            created_at
+        FROM email_outbox
+        WHERE execute_after <= now()
+        ORDER BY created_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(email.map(|email| (transaction, email)))
+}
+
+/// Moves the email from the outbox to the delivery history, without its body.
+async fn delete_task(
+    mut transaction: Transaction<'static, Postgres>,
+    email: &OutboxEmail,
+    outcome: DeliveryOutcome,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO email_deliveries
+            (id, subscriber_id, recipient, subject, outcome, n_attempts, queued_at, finished_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, now())
+        "#,
+        email.id,
+        email.subscriber_id,
+        email.recipient,
+        email.subject,
+        outcome.as_str(),
+        email.n_retries + 1,
+        email.created_at,
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    sqlx::query!(r#"DELETE FROM email_outbox WHERE id = $1"#, email.id)
+        .execute(&mut transaction)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+    transaction.commit().await
+}
+
+async fn reschedule_task(
+    mut transaction: Transaction<'static, Postgres>,
+    email: &OutboxEmail,
+) -> Result<(), sqlx::Error> {
+    let n_retries = email.n_retries + 1;
+    if n_retries >= MAX_ATTEMPTS {
+        tracing::error!(
+            "Giving up on outbox email {} after {} attempts",
+            email.id,
+            n_retries
+        );
+        return delete_task(transaction, email, DeliveryOutcome::Dropped).await;
+    }
+    sqlx::query!(
+        r#"UPDATE email_outbox SET n_retries = $2, execute_after = $3 WHERE id = $1"#,
+        email.id,
+        n_retries,
+        next_attempt_at(n_retries)
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    transaction.commit().await
+}
+
+fn next_attempt_at(n_retries: i16) -> DateTime<Utc> {
+    let delay = RETRY_BASE_DELAY_SECONDS * 2_i64.pow(n_retries as u32 - 1);
+    Utc::now() + chrono::Duration::seconds(delay)
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+use tera::{Context, Tera};
+
+/// The locale every email exists in, used whenever the subscriber's is not available.
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Email templates, compiled once at startup.
+///
+/// Each locale is a directory holding, for every email, `<name>.subject.txt`,
+/// `<name>.html` and `<name>.txt`. Values are HTML-escaped in `.html` templates only.
+pub struct EmailTemplates {
+    tera: Tera,
+    locales: BTreeSet<String>,
+}
+
+/// The three parts of an email, rendered.
+#[derive(Debug)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+}
+
+impl EmailTemplates {
+    pub fn load(directory: impl AsRef<Path>) -> Result<Self, tera::Error> {
+        let glob = directory.as_ref().join("**").join("*");
+        let tera = Tera::new(&glob.to_string_lossy())?;
+        let templates = Self::from_tera(tera);
+        if !templates.locales.contains(DEFAULT_LOCALE) {
+            return Err(tera::Error::msg(format!(
+                "No `{}` email templates in {}",
+                DEFAULT_LOCALE,
+                directory.as_ref().display()
+            )));
+        }
+        Ok(templates)
+    }
+
+    fn from_tera(tera: Tera) -> Self {
+        let locales = tera
+            .get_template_names()
+            .filter_map(|name| name.split_once('/').map(|(locale, _)| locale.to_owned()))
+            .collect();
+        Self { tera, locales }
+    }
+
+    /// The supported locale that best matches `requested`, ignoring case:
+    /// the exact tag, or else its primary language (`fr` for `fr-CA`).
+    pub fn supported_locale(&self, requested: &str) -> Option<&str> {
+        let requested = requested.trim().to_lowercase();
+        let primary = requested.split(['-', '_']).next().unwrap_or_default();
+        let locale = self
+            .locales
+            .get(requested.as_str())
+            .or_else(|| self.locales.get(primary))?;
+        Some(locale.as_str())
+    }
+
+    /// The first of `requested`, in order of preference, that we have templates for,
+    /// or English.
+    pub fn negotiate_locale<'a>(&self, requested: impl IntoIterator<Item = &'a str>) -> &str {
+        requested
+            .into_iter()
+            .find_map(|locale| self.supported_locale(locale))
+            .unwrap_or(DEFAULT_LOCALE)
+    }
+
+    /// Render the `name` email in `locale`, falling back to English for the
+    /// locales we have no templates for.
+    pub fn render(
+        &self,
+        name: &str,
+        locale: &str,
+        context: &Context,
+    ) -> Result<RenderedEmail, tera::Error> {
+        let locale = self.supported_locale(locale).unwrap_or(DEFAULT_LOCALE);
+        let render = |extension: &str| {
+            let template = format!("{}/{}.{}", locale, name, extension);
+            // Fall back template by template, so that a partial translation still works.
+            match self.tera.get_template_names().any(|n| n == template) {
+                true => self.tera.render(&template, context),
+                false => self.tera.render(
+                    &format!("{}/{}.{}", DEFAULT_LOCALE, name, extension),
+                    context,
+                ),
+            }
+        };
+        Ok(RenderedEmail {
+            subject: render("subject.txt")?.trim().to_owned(),
+            html_body: render("html")?,
+            text_body: render("txt")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EmailTemplates;
+    use claim::{assert_none, assert_some_eq};
+    use tera::{Context, Tera};
+
+    fn templates() -> EmailTemplates {
+        let mut tera = Tera::default();
+        tera.add_raw_templates(vec![
+            ("en/greeting.subject.txt", "Hello {{ name }}\n"),
+            ("en/greeting.html", "<p>Hello {{ name }}</p>"),
+            ("en/greeting.txt", "Hello {{ name }}"),
+            ("fr/greeting.subject.txt", "Bonjour {{ name }}"),
+            ("fr/greeting.html", "<p>Bonjour {{ name }}</p>"),
+        ])
+        .unwrap();
+        EmailTemplates::from_tera(tera)
+    }
+
+    fn context() -> Context {
+        let mut context = Context::new();
+        context.insert("name", "<Ursula>");
+        context
+    }
+
+    #[test]
+    fn locales_are_matched_on_their_primary_language() {
+        let templates = templates();
+
+        assert_some_eq!(templates.supported_locale("FR-ca"), "fr");
+        assert_some_eq!(templates.supported_locale("en"), "en");
+        assert_none!(templates.supported_locale("de"));
+    }
+
+    #[test]
+    fn the_first_supported_locale_is_negotiated() {
+        let templates = templates();
+
+        assert_eq!(templates.negotiate_locale(["de", "fr-BE", "en"]), "fr");
+        assert_eq!(templates.negotiate_locale(["de"]), "en");
+        assert_eq!(templates.negotiate_locale([]), "en");
+    }
+
+    #[test]
+    fn only_html_templates_are_escaped() {
+        let email = templates().render("greeting", "en", &context()).unwrap();
+
+        assert_eq!(email.subject, "Hello <Ursula>");
+        assert_eq!(email.html_body, "<p>Hello &lt;Ursula&gt;</p>");
+        assert_eq!(email.text_body, "Hello <Ursula>");
+    }
+
+    #[test]
+    fn unknown_locales_fall_back_to_english() {
+        let email = templates().render("greeting", "de", &context()).unwrap();
+
+        assert_eq!(email.subject, "Hello <Ursula>");
+    }
+
+    #[test]
+    fn missing_translations_fall_back_to_english() {
+        let email = templates().render("greeting", "fr", &context()).unwrap();
+
+        assert_eq!(email.subject, "Bonjour <Ursula>");
+        assert_eq!(email.text_body, "Hello <Ursula>");
+    }
+
+    #[test]
+    fn the_bundled_templates_load() {
+        let templates = EmailTemplates::load("templates/emails").unwrap();
+
+        for locale in ["en", "fr"] {
+            for name in [
+                "confirmation",
+                "already_subscribed",
+                "email_change_confirmation",
+                "email_change_notice",
+            ] {
+                let mut context = Context::new();
+                context.insert("name", "Ursula");
+                context.insert("confirmation_link", "https://example.com/confirm");
+                context.insert("preferences_link", "https://example.com/preferences");
+                context.insert("new_email", "ursula@example.org");
+                assert!(templates.render(name, locale, &context).is_ok());
+            }
+        }
+    }
+}
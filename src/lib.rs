@@ -1,6 +1,19 @@
+pub mod authentication;
 pub mod configuration;
 pub mod domain;
 pub mod email_client;
+pub mod email_outbox;
+pub mod email_templates;
+pub mod newsletter_digest;
+pub mod pending_cleanup;
+pub mod preferences_token;
+pub mod problem_details;
+pub mod rate_limit;
 pub mod routes;
+pub mod session_state;
+pub mod session_store;
+pub mod spam_protection;
 pub mod startup;
+pub mod subscriber_data;
+pub mod subscriber_import;
 pub mod telemetry;
@@ -1,14 +1,147 @@
+use clap::{Parser, Subcommand};
+use secrecy::ExposeSecret;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use zero2prod::authentication::create_user;
 use zero2prod::configuration::get_configuration;
-use zero2prod::startup::Application;
+use zero2prod::domain::SubscriptionStatus;
+use zero2prod::pending_cleanup::{clean_up_pending_subscriptions, CleanupMode};
+use zero2prod::startup::{get_connection_pool, Application};
+use zero2prod::subscriber_import::{import_subscribers, IMPORT_BATCH_SIZE};
 use zero2prod::telemetry;
 
+#[derive(Parser)]
+#[command(name = "zero2prod", about = "Newsletter delivery service")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the API. This is what runs when no subcommand is given.
+    Serve,
+    /// Create an operator account for the /admin area and print its generated password.
+    /// The password is not stored anywhere else: note it down.
+    CreateUser { username: String },
+    /// Import subscribers from a CSV file with `name` and `email` columns.
+    ImportSubscribers {
+        /// The CSV file to import.
+        file: PathBuf,
+        /// Status given to the imported subscribers: `confirmed` or `unsubscribed`.
+        /// Rows are committed in batches: a batch that cannot be stored is reported
+        /// and the import goes on with the next one.
+        #[arg(long, value_parser = parse_status)]
+        status: SubscriptionStatus,
+        /// Where to write the CSV report of rejected rows. Defaults to stdout.
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Purge or archive subscriptions left pending confirmation, once, then exit.
+    /// The age, mode and batch size default to the `pending_cleanup` configuration.
+    CleanUpPendingSubscriptions {
+        /// Clean up subscriptions left unconfirmed for at least this many hours.
+        #[arg(long)]
+        older_than_hours: Option<u32>,
+        #[arg(long, value_parser = parse_cleanup_mode)]
+        mode: Option<CleanupMode>,
+        #[arg(long)]
+        batch_size: Option<NonZeroU32>,
+    },
+}
+
+fn parse_status(s: &str) -> Result<SubscriptionStatus, String> {
+    SubscriptionStatus::try_from(s.to_string())
+}
+
+fn parse_cleanup_mode(s: &str) -> Result<CleanupMode, String> {
+    CleanupMode::try_from(s.to_string())
+}
+
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    let subsciber = telemetry::get_subscriber("zero2prod".into(), "info", std::io::stdout);
-    telemetry::init_subscriber(subsciber);
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    // Logs go to stderr when a subcommand may be writing its output to stdout.
+    if let None | Some(Command::Serve) = cli.command {
+        let subsciber = telemetry::get_subscriber("zero2prod".into(), "info", std::io::stdout);
+        telemetry::init_subscriber(subsciber);
+    } else {
+        let subsciber = telemetry::get_subscriber("zero2prod".into(), "info", std::io::stderr);
+        telemetry::init_subscriber(subsciber);
+    }
 
     let config = get_configuration().expect("Failed to retrieve configuration");
-    let app = Application::build(config).await?;
-    app.run_until_stopped().await?;
+    match cli.command {
+        None | Some(Command::Serve) => {
+            let app = Application::build(config).await?;
+            app.run_until_stopped().await?;
+        }
+        Some(Command::CreateUser { username }) => {
+            let pool = get_connection_pool(&config.database);
+            let password = create_user(&pool, &username).await?;
+            println!("{}", password.expose_secret());
+        }
+        Some(Command::ImportSubscribers {
+            file,
+            status,
+            report,
+        }) => {
+            let pool = get_connection_pool(&config.database);
+            let csv = tokio::fs::File::open(&file).await?;
+            let outcome = import_subscribers(
+                &pool,
+                csv,
+                config.email_normalization.normalization(),
+                &config
+                    .email_domain_policy
+                    .policy()
+                    .map_err(anyhow::Error::msg)?,
+                &config.application.tombstone_secret,
+                status,
+                IMPORT_BATCH_SIZE,
+            )
+            .await?;
+            for batch in &outcome.failed_batches {
+                tracing::warn!(
+                    error = %batch.error,
+                    "Lines {} to {} could not be stored",
+                    batch.first_line,
+                    batch.last_line
+                );
+            }
+            tracing::info!(
+                imported = outcome.imported,
+                rejected = outcome.rejected.len(),
+                failed_batches = outcome.failed_batches.len(),
+                "Import of {} completed",
+                file.display()
+            );
+            let rejected_rows = outcome.rejected_rows_csv().await?;
+            match report {
+                Some(path) => tokio::fs::write(path, rejected_rows).await?,
+                None => {
+                    use tokio::io::AsyncWriteExt;
+                    tokio::io::stdout().write_all(&rejected_rows).await?;
+                }
+            }
+        }
+        Some(Command::CleanUpPendingSubscriptions {
+            older_than_hours,
+            mode,
+            batch_size,
+        }) => {
+            let settings = config.pending_cleanup;
+            let pool = get_connection_pool(&config.database);
+            clean_up_pending_subscriptions(
+                &pool,
+                older_than_hours
+                    .map(|hours| chrono::Duration::hours(hours.into()))
+                    .unwrap_or_else(|| settings.max_age()),
+                mode.unwrap_or(settings.mode),
+                batch_size.unwrap_or(settings.batch_size),
+            )
+            .await?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,226 @@
+use crate::configuration::DigestSettings;
+use crate::domain::{EmailFrequency, SubscriberEmail};
+use crate::email_outbox::enqueue_email;
+use crate::preferences_token::preferences_link;
+use crate::routes::{issue_unsubscribe_token, list_unsubscribe_headers};
+use chrono::{DateTime, Utc};
+use htmlescape::encode_minimal;
+use secrecy::Secret;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::time::Duration;
+use uuid::Uuid;
+
+struct DueSubscriber {
+    id: Uuid,
+    email: String,
+    topics: Option<Vec<String>>,
+    frequency: String,
+    since: DateTime<Utc>,
+}
+
+struct Issue {
+    title: String,
+    html_content: String,
+    text_content: String,
+}
+
+/// Sends their digest to every weekly or monthly subscriber whose period is over,
+/// with the issues published since their previous one. Returns how many went out.
+///
+/// A subscriber with nothing new gets no email, but starts a new period all the same.
+#[tracing::instrument(
+    name = "Send the due newsletter digests",
+    skip(pool, base_url, preferences_link_ttl, secret)
+)]
+pub async fn send_due_digests(
+    pool: &PgPool,
+    base_url: &str,
+    preferences_link_ttl: chrono::Duration,
+    secret: &Secret<String>,
+) -> Result<u64, sqlx::Error> {
+    let mut sent = 0;
+    loop {
+        let mut transaction = pool.begin().await?;
+        let subscriber = match next_due_subscriber(&mut transaction).await? {
+            Some(subscriber) => subscriber,
+            None => break,
+        };
+        let issues = get_issues_since(&mut transaction, &subscriber).await?;
+        if !issues.is_empty() {
+            match SubscriberEmail::parse(subscriber.email.clone()) {
+                Ok(email) => {
+                    let preferences_link =
+                        preferences_link(base_url, subscriber.id, preferences_link_ttl, secret);
+                    let (subject, html_body, text_body) =
+                        digest_email(&subscriber.frequency, &issues, &preferences_link);
+                    let unsubscribe_token =
+                        issue_unsubscribe_token(&mut transaction, subscriber.id, secret).await?;
+                    enqueue_email(
+                        &mut transaction,
+                        subscriber.id,
+                        &email,
+                        &subject,
+                        &html_body,
+                        &text_body,
+                        &list_unsubscribe_headers(base_url, &unsubscribe_token),
+                    )
+                    .await?;
+                    sent += 1;
+                }
+                Err(e) => tracing::warn!(
+                    subscriber_id = %subscriber.id,
+                    "Skipping a digest. The stored email address is invalid: {}",
+                    e
+                ),
+            }
+        }
+        sqlx::query!(
+            r#"UPDATE subscriptions SET last_digest_at = now() WHERE id = $1"#,
+            subscriber.id,
+        )
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+    }
+    tracing::info!(sent, "Sent the due newsletter digests");
+    Ok(sent)
+}
+
+/// Send the due digests every `interval_minutes`, starting right away.
+pub async fn run_digests_until_stopped(
+    pool: PgPool,
+    settings: DigestSettings,
+    base_url: String,
+    preferences_link_ttl: chrono::Duration,
+    secret: Secret<String>,
+) {
+    let interval = Duration::from_secs(u64::from(settings.interval_minutes) * 60);
+    loop {
+        if let Err(e) = send_due_digests(&pool, &base_url, preferences_link_ttl, &secret).await {
+            tracing::error!("Failed to send the due newsletter digests: {:?}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Subscribers being served by another worker are skipped.
+async fn next_due_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Option<DueSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        DueSubscriber,
+        r#"
+        SELECT
+            id,
+            email,
+            topics,
+            frequency,
+            COALESCE(last_digest_at, subscribed_at) AS "since!"
+        FROM subscriptions
+        WHERE status = 'confirmed'
+            AND (
+                (frequency = $1 AND COALESCE(last_digest_at, subscribed_at) <= now() - interval '7 days')
+                OR (frequency = $2 AND COALESCE(last_digest_at, subscribed_at) <= now() - interval '1 month')
+            )
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+        "#,
+        EmailFrequency::Weekly.as_str(),
+        EmailFrequency::Monthly.as_str(),
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+async fn get_issues_since(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber: &DueSubscriber,
+) -> Result<Vec<Issue>, sqlx::Error> {
+    sqlx::query_as!(
+        Issue,
+        r#"
+        SELECT title, html_content, text_content
+        FROM newsletter_issues
+        WHERE published_at > $1
+            AND (topic IS NULL OR $2::text[] IS NULL OR topic = ANY($2))
+        ORDER BY published_at
+        "#,
+        subscriber.since,
+        subscriber.topics.as_deref(),
+    )
+    .fetch_all(transaction)
+    .await
+}
+
+/// The subject, HTML body and text body of a digest.
+fn digest_email(
+    frequency: &str,
+    issues: &[Issue],
+    preferences_link: &str,
+) -> (String, String, String) {
+    let subject = if frequency == EmailFrequency::Monthly.as_str() {
+        "Your monthly newsletter digest"
+    } else {
+        "Your weekly newsletter digest"
+    };
+    let html_issues: String = issues
+        .iter()
+        .map(|issue| {
+            format!(
+                "<h2>{}</h2>{}",
+                encode_minimal(&issue.title),
+                issue.html_content
+            )
+        })
+        .collect();
+    let text_issues = issues
+        .iter()
+        .map(|issue| format!("{}\n\n{}", issue.title, issue.text_content))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    (
+        subject.to_owned(),
+        format!(
+            "{}<p><a href=\"{}\">Manage your preferences</a></p>",
+            html_issues, preferences_link
+        ),
+        format!(
+            "{}\n\nManage your preferences: {}",
+            text_issues, preferences_link
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest_email, Issue};
+
+    fn issue(title: &str) -> Issue {
+        Issue {
+            title: title.into(),
+            html_content: format!("<p>{} body</p>", title),
+            text_content: format!("{} body", title),
+        }
+    }
+
+    #[test]
+    fn a_digest_lists_every_issue_in_order() {
+        let (subject, html, text) = digest_email(
+            "monthly",
+            &[issue("First"), issue("Second")],
+            "https://example.com/preferences",
+        );
+
+        assert_eq!(subject, "Your monthly newsletter digest");
+        assert!(html.find("First body").unwrap() < html.find("Second body").unwrap());
+        assert!(text.find("First body").unwrap() < text.find("Second body").unwrap());
+        assert!(text.ends_with("Manage your preferences: https://example.com/preferences"));
+    }
+
+    #[test]
+    fn issue_titles_are_escaped_in_the_html_body() {
+        let (_, html, _) = digest_email("weekly", &[issue("Q&A")], "https://example.com");
+
+        assert!(html.contains("<h2>Q&amp;A</h2>"));
+    }
+}
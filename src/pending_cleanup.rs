@@ -0,0 +1,202 @@
+use crate::configuration::PendingCleanupSettings;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::num::NonZeroU32;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// What happens to the subscriptions that were never confirmed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanupMode {
+    /// Deleted for good.
+    Purge,
+    /// Moved to `archived_pending_subscriptions`.
+    Archive,
+}
+
+impl TryFrom<String> for CleanupMode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "purge" => Ok(Self::Purge),
+            "archive" => Ok(Self::Archive),
+            other => Err(format!(
+                "{} is not a cleanup mode. Use either `purge` or `archive`.",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CleanupReport {
+    pub subscribers: u64,
+    pub tokens: u64,
+}
+
+/// Removes the subscriptions still pending confirmation that were created before
+/// `now - max_age` and got no confirmation email since, `batch_size` per transaction.
+///
+/// A pending subscriber holding a working confirmation link is left alone, even
+/// when `max_age` is shorter than the confirmation token TTL.
+#[tracing::instrument(name = "Clean up stale pending subscriptions", skip(pool))]
+pub async fn clean_up_pending_subscriptions(
+    pool: &PgPool,
+    max_age: chrono::Duration,
+    mode: CleanupMode,
+    batch_size: NonZeroU32,
+) -> Result<CleanupReport, sqlx::Error> {
+    let cutoff = Utc::now() - max_age;
+    let mut report = CleanupReport::default();
+    loop {
+        let mut transaction = pool.begin().await?;
+        let ids = select_stale_batch(&mut transaction, cutoff, batch_size).await?;
+        if ids.is_empty() {
+            break;
+        }
+        if mode == CleanupMode::Archive {
+            archive_subscribers(&mut transaction, &ids).await?;
+        }
+        let tokens = delete_subscribers(&mut transaction, &ids).await?;
+        transaction.commit().await?;
+
+        report.subscribers += ids.len() as u64;
+        report.tokens += tokens;
+        tracing::debug!(
+            subscribers = ids.len(),
+            tokens,
+            "Cleaned up a batch of stale pending subscriptions"
+        );
+    }
+    tracing::info!(
+        subscribers = report.subscribers,
+        tokens = report.tokens,
+        mode = ?mode,
+        "Cleaned up stale pending subscriptions"
+    );
+    Ok(report)
+}
+
+/// Run the cleanup every `interval_minutes`, starting right away.
+pub async fn run_cleanup_until_stopped(pool: PgPool, settings: PendingCleanupSettings) {
+    let interval = Duration::from_secs(u64::from(settings.interval_minutes) * 60);
+    loop {
+        if let Err(e) = clean_up_pending_subscriptions(
+            &pool,
+            settings.max_age(),
+            settings.mode,
+            settings.batch_size,
+        )
+        .await
+        {
+            tracing::error!("Failed to clean up stale pending subscriptions: {:?}", e);
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Rows locked by a signup or a confirmation in progress are skipped until the next run.
+async fn select_stale_batch(
+    transaction: &mut Transaction<'_, Postgres>,
+    cutoff: DateTime<Utc>,
+    batch_size: NonZeroU32,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id
+        FROM subscriptions s
+        WHERE status = 'pending_confirmation'
+            AND subscribed_at < $1
+            AND NOT EXISTS (
+                SELECT 1 FROM subscription_tokens t
+                WHERE t.subscriber_id = s.id
+                    AND (t.created_at >= $1 OR (t.expires_at > now() AND t.consumed_at IS NULL))
+            )
+        ORDER BY subscribed_at
+        LIMIT $2
+        FOR UPDATE SKIP LOCKED
+        "#,
+        cutoff,
+        i64::from(batch_size.get()),
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(rows.into_iter().map(|r| r.id).collect())
+}
+
+async fn archive_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    ids: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO archived_pending_subscriptions (id, email, name, subscribed_at, archived_at)
+        SELECT id, email, name, subscribed_at, now()
+        FROM subscriptions
+        WHERE id = ANY($1)
+        ON CONFLICT (id) DO NOTHING
+        "#,
+        ids,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Deletes the subscribers along with every row pointing at them.
+/// Returns how many confirmation tokens went with them.
+async fn delete_subscribers(
+    transaction: &mut Transaction<'_, Postgres>,
+    ids: &[Uuid],
+) -> Result<u64, sqlx::Error> {
+    let tokens = sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)"#,
+        ids,
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        r#"DELETE FROM unsubscribe_tokens WHERE subscriber_id = ANY($1)"#,
+        ids,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM subscriber_preference_changes WHERE subscriber_id = ANY($1)"#,
+        ids,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = ANY($1)"#,
+        ids,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(r#"DELETE FROM subscriptions WHERE id = ANY($1)"#, ids)
+        .execute(transaction)
+        .await?;
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CleanupMode;
+    use claim::assert_err;
+
+    #[test]
+    fn modes_are_parsed_from_their_names() {
+        assert_eq!(
+            CleanupMode::try_from("purge".to_string()),
+            Ok(CleanupMode::Purge)
+        );
+        assert_eq!(
+            CleanupMode::try_from("archive".to_string()),
+            Ok(CleanupMode::Archive)
+        );
+        assert_err!(CleanupMode::try_from("shred".to_string()));
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// Why a preferences token was not accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreferencesTokenError {
+    /// The token was not issued by us, or was tampered with.
+    Invalid,
+    Expired,
+}
+
+/// A token granting access to a subscriber's preferences until `expires_at`, as
+/// `<subscriber id>.<unix timestamp>.<hex HMAC-SHA256 of both>`.
+///
+/// Being signed rather than stored, it is cheap to put a fresh one in every email.
+pub fn issue_preferences_token(
+    subscriber_id: Uuid,
+    expires_at: DateTime<Utc>,
+    secret: &Secret<String>,
+) -> String {
+    let subscriber_id = subscriber_id.to_simple().to_string();
+    let timestamp = expires_at.timestamp().to_string();
+    let signature = hex::encode(
+        preferences_token_mac(&subscriber_id, &timestamp, secret)
+            .finalize()
+            .into_bytes(),
+    );
+    format!("{}.{}.{}", subscriber_id, timestamp, signature)
+}
+
+/// The subscriber the token was issued for, if it is genuine and still valid at `now`.
+pub fn verify_preferences_token(
+    token: &str,
+    secret: &Secret<String>,
+    now: DateTime<Utc>,
+) -> Result<Uuid, PreferencesTokenError> {
+    let mut parts = token.trim().splitn(3, '.');
+    let (subscriber_id, timestamp, signature) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(subscriber_id), Some(timestamp), Some(signature)) => {
+            (subscriber_id, timestamp, signature)
+        }
+        _ => return Err(PreferencesTokenError::Invalid),
+    };
+    let signature = hex::decode(signature).map_err(|_| PreferencesTokenError::Invalid)?;
+    preferences_token_mac(subscriber_id, timestamp, secret)
+        .verify_slice(&signature)
+        .map_err(|_| PreferencesTokenError::Invalid)?;
+
+    let expires_at = timestamp
+        .parse()
+        .ok()
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+        .ok_or(PreferencesTokenError::Invalid)?;
+    if now >= expires_at {
+        return Err(PreferencesTokenError::Expired);
+    }
+    Uuid::parse_str(subscriber_id).map_err(|_| PreferencesTokenError::Invalid)
+}
+
+/// The link to the preferences page of `subscriber_id`, valid for `ttl`.
+pub fn preferences_link(
+    base_url: &str,
+    subscriber_id: Uuid,
+    ttl: chrono::Duration,
+    secret: &Secret<String>,
+) -> String {
+    format!(
+        "{}/preferences?token={}",
+        base_url,
+        issue_preferences_token(subscriber_id, Utc::now() + ttl, secret)
+    )
+}
+
+fn preferences_token_mac(
+    subscriber_id: &str,
+    timestamp: &str,
+    secret: &Secret<String>,
+) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(b"preferences:");
+    mac.update(subscriber_id.as_bytes());
+    mac.update(b".");
+    mac.update(timestamp.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{issue_preferences_token, verify_preferences_token, PreferencesTokenError};
+    use chrono::{Duration, Utc};
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn secret() -> Secret<String> {
+        Secret::new("secret".into())
+    }
+
+    #[test]
+    fn a_token_is_valid_until_it_expires() {
+        let subscriber_id = Uuid::new_v4();
+        let now = Utc::now();
+        let token = issue_preferences_token(subscriber_id, now + Duration::hours(1), &secret());
+
+        assert_eq!(
+            verify_preferences_token(&token, &secret(), now),
+            Ok(subscriber_id)
+        );
+        assert_eq!(
+            verify_preferences_token(&token, &secret(), now + Duration::hours(2)),
+            Err(PreferencesTokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn a_token_cannot_be_moved_to_another_subscriber() {
+        let now = Utc::now();
+        let token = issue_preferences_token(Uuid::new_v4(), now + Duration::hours(1), &secret());
+        let (_, rest) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", Uuid::new_v4().to_simple(), rest);
+
+        assert_eq!(
+            verify_preferences_token(&forged, &secret(), now),
+            Err(PreferencesTokenError::Invalid)
+        );
+    }
+
+    #[test]
+    fn a_token_cannot_be_extended() {
+        let now = Utc::now();
+        let subscriber_id = Uuid::new_v4();
+        let token = issue_preferences_token(subscriber_id, now + Duration::hours(1), &secret());
+        let signature = token.rsplit('.').next().unwrap();
+        let forged = format!(
+            "{}.{}.{}",
+            subscriber_id.to_simple(),
+            (now + Duration::days(365)).timestamp(),
+            signature
+        );
+
+        assert_eq!(
+            verify_preferences_token(&forged, &secret(), now),
+            Err(PreferencesTokenError::Invalid)
+        );
+    }
+
+    #[test]
+    fn a_token_signed_with_another_key_is_rejected() {
+        let now = Utc::now();
+        let token = issue_preferences_token(
+            Uuid::new_v4(),
+            now + Duration::hours(1),
+            &Secret::new("other".into()),
+        );
+
+        assert_eq!(
+            verify_preferences_token(&token, &secret(), now),
+            Err(PreferencesTokenError::Invalid)
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// An RFC 7807 problem document describing why a request failed.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+    /// Set when clients need to tell apart reasons for rejecting the same field.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+            code: None,
+        }
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+}
+
+impl ProblemDetails {
+    pub fn new(status: StatusCode, detail: impl Into<String>) -> Self {
+        Self {
+            problem_type: "about:blank".into(),
+            title: status.canonical_reason().unwrap_or_default().into(),
+            status: status.as_u16(),
+            detail: detail.into(),
+            errors: Vec::new(),
+        }
+    }
+
+    pub fn with_errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// An empty response carrying the problem as an extension.
+    /// The body is only rendered by [`render_problem_details`] when the client asked for it.
+    pub fn into_response(self) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = HttpResponse::new(status);
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl std::fmt::Display for ProblemDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.detail)
+    }
+}
+
+impl ResponseError for ProblemDetails {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.clone().into_response()
+    }
+}
+
+/// Flattens `validator` failures into one entry per failed rule.
+pub fn field_errors(errors: &validator::ValidationErrors) -> Vec<FieldError> {
+    let mut field_errors: Vec<FieldError> = errors
+        .field_errors()
+        .into_iter()
+        .flat_map(|(field, errors)| {
+            errors.iter().map(move |e| {
+                let message = e
+                    .message
+                    .as_ref()
+                    .map(|m| m.to_string())
+                    .unwrap_or_else(|| e.code.to_string());
+                FieldError::new(field, message)
+            })
+        })
+        .collect();
+    field_errors.sort_by(|a, b| a.field.cmp(&b.field));
+    field_errors
+}
+
+/// Error handler for the `Form`, `Query` and `Json` extractors, so that malformed
+/// payloads are reported the same way as the errors raised by the handlers.
+pub fn extractor_error_handler<E: ResponseError>(err: E, _req: &HttpRequest) -> actix_web::Error {
+    ProblemDetails::new(err.status_code(), err.to_string()).into()
+}
+
+/// Renders the problem attached to an error response as `application/problem+json`,
+/// for clients opting in through their `Accept` header. Other clients keep getting empty bodies.
+pub async fn render_problem_details(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let wants_problem_details = req
+        .headers()
+        .get_all(header::ACCEPT)
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains(PROBLEM_JSON));
+    let response = next.call(req).await?;
+    if !wants_problem_details {
+        return Ok(response.map_into_boxed_body());
+    }
+    let problem = response
+        .response()
+        .extensions()
+        .get::<ProblemDetails>()
+        .cloned();
+    match problem {
+        Some(problem) => Ok(response.map_body(|head, _| {
+            head.headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+            BoxBody::new(serde_json::to_string(&problem).unwrap_or_default())
+        })),
+        None => Ok(response.map_into_boxed_body()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{field_errors, FieldError, ProblemDetails};
+    use actix_web::http::StatusCode;
+    use validator::Validate;
+
+    #[derive(Validate)]
+    struct Parameters {
+        #[validate(length(equal = 3))]
+        token: String,
+    }
+
+    #[test]
+    fn problem_details_serialize_the_rfc_7807_members() {
+        let problem = ProblemDetails::new(StatusCode::BAD_REQUEST, "Invalid input")
+            .with_errors(vec![FieldError::new("email", "Not an email")]);
+
+        let json = serde_json::to_value(&problem).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "Invalid input",
+                "errors": [{"field": "email", "message": "Not an email"}],
+            })
+        );
+    }
+
+    #[test]
+    fn errors_are_omitted_when_there_are_none() {
+        let problem = ProblemDetails::new(StatusCode::INTERNAL_SERVER_ERROR, "Oops");
+
+        let json = serde_json::to_value(&problem).unwrap();
+
+        assert!(json.get("errors").is_none());
+    }
+
+    #[test]
+    fn validator_failures_become_field_errors() {
+        let errors = Parameters {
+            token: "abcd".into(),
+        }
+        .validate()
+        .unwrap_err();
+
+        assert_eq!(
+            field_errors(&errors),
+            vec![FieldError::new("token", "length")]
+        );
+    }
+}
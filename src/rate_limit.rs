@@ -0,0 +1,265 @@
+use crate::configuration::{RateLimitSettings, TokenBucketSettings};
+use crate::domain::{EmailNormalization, SubscriberEmail};
+use crate::problem_details::ProblemDetails;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::RETRY_AFTER;
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::web::{Bytes, Data};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Past this many tracked keys, buckets that have refilled completely are dropped:
+/// forgetting them does not change the outcome of later requests.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token buckets keyed by an arbitrary string, all sharing the same limits.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    buckets: Mutex<HashMap<String, TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: &TokenBucketSettings) -> Self {
+        Self {
+            capacity: settings.capacity.into(),
+            refill_per_second: f64::from(settings.refill_per_hour.get()) / 3600.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `key`'s bucket, or returns how long to wait for the next one.
+    pub fn check(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.capacity);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(TokenBucket {
+            tokens: self.capacity,
+            last_refill: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.last_refill = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (1.0 - bucket.tokens) / self.refill_per_second,
+        ))
+    }
+
+    fn refill(&self, bucket: &TokenBucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.last_refill);
+        (bucket.tokens + elapsed.as_secs_f64() * self.refill_per_second).min(self.capacity)
+    }
+}
+
+/// The limits applied to `POST /subscriptions`, to confirmation email resends and to
+/// email change requests: every one of them sends an email to the submitted address.
+pub struct SubscriptionRateLimiter {
+    per_ip: RateLimiter,
+    per_email: RateLimiter,
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl SubscriptionRateLimiter {
+    pub fn new(settings: &RateLimitSettings) -> Self {
+        Self {
+            per_ip: RateLimiter::new(&settings.per_ip),
+            per_email: RateLimiter::new(&settings.per_email),
+            trusted_proxies: settings.trusted_proxies.clone(),
+        }
+    }
+}
+
+/// The address of the client, as reported by the trusted proxies in front of us.
+///
+/// `X-Forwarded-For` is only honoured when the peer is a trusted proxy. It is then
+/// read right to left, skipping the trusted proxies: the first other address is the
+/// client, since everything left of it could have been made up by the client.
+pub fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let mut client = peer;
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !trusted_proxies.contains(&ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    client
+}
+
+#[derive(serde::Deserialize)]
+struct EmailField {
+    email: String,
+}
+
+/// Rate limits subscription attempts by client IP, then by target email address.
+/// The form is buffered to read the email, and handed back to the handler untouched.
+///
+/// Addresses are keyed on their canonical form, so that every spelling the subscribe
+/// handler would treat as the same subscriber shares a bucket.
+pub async fn limit_subscription_attempts(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    // Fetching the form is not an attempt.
+    if req.method() != Method::POST {
+        return next.call(req).await.map(|r| r.map_into_boxed_body());
+    }
+    let limiter = req
+        .app_data::<Data<SubscriptionRateLimiter>>()
+        .expect("The subscription rate limiter is not registered")
+        .clone();
+    let now = Instant::now();
+
+    if let Some(peer) = req.peer_addr() {
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|v| v.to_str().ok());
+        let ip = client_ip(peer.ip(), forwarded_for, &limiter.trusted_proxies);
+        if let Err(retry_after) = limiter.per_ip.check(&ip.to_string(), now) {
+            tracing::warn!(client_ip = %ip, "Too many subscription attempts from this address");
+            return Ok(req.into_response(too_many_requests(retry_after)));
+        }
+    }
+
+    let body = req.extract::<Bytes>().await?;
+    if let Ok(form) = serde_urlencoded::from_bytes::<EmailField>(&body) {
+        let normalization = ***req
+            .app_data::<Data<EmailNormalization>>()
+            .expect("The email normalization is not registered");
+        // Invalid addresses are turned down by the handler, but still count.
+        let email = match SubscriberEmail::parse_with(form.email.clone(), normalization) {
+            Ok(email) => email.canonical().to_owned(),
+            Err(_) => form.email.trim().to_lowercase(),
+        };
+        if let Err(retry_after) = limiter.per_email.check(&email, now) {
+            tracing::warn!("Too many subscription attempts for the same email address");
+            return Ok(req.into_response(too_many_requests(retry_after)));
+        }
+    }
+    req.set_payload(Payload::from(body));
+
+    next.call(req).await.map(|r| r.map_into_boxed_body())
+}
+
+fn too_many_requests(retry_after: Duration) -> actix_web::HttpResponse {
+    // Round up, so that a client waiting as told finds a token.
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let mut response = ProblemDetails::new(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many subscription attempts, try again later",
+    )
+    .into_response();
+    response.headers_mut().insert(RETRY_AFTER, seconds.into());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, RateLimiter};
+    use crate::configuration::TokenBucketSettings;
+    use claim::{assert_err, assert_ok};
+    use std::net::IpAddr;
+    use std::num::NonZeroU32;
+    use std::time::{Duration, Instant};
+
+    fn limiter(capacity: u32, refill_per_hour: u32) -> RateLimiter {
+        RateLimiter::new(&TokenBucketSettings {
+            capacity,
+            refill_per_hour: NonZeroU32::new(refill_per_hour).unwrap(),
+        })
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn a_bucket_allows_a_burst_up_to_its_capacity() {
+        let limiter = limiter(3, 60);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_ok!(limiter.check("key", now));
+        }
+        assert_err!(limiter.check("key", now));
+    }
+
+    #[test]
+    fn the_wait_is_the_time_to_refill_one_token() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        limiter.check("key", now).unwrap();
+
+        let retry_after = limiter.check("key", now).unwrap_err();
+
+        assert_eq!(retry_after.as_secs(), 60);
+    }
+
+    #[test]
+    fn tokens_come_back_over_time() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        limiter.check("key", now).unwrap();
+
+        assert_ok!(limiter.check("key", now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn keys_have_separate_buckets() {
+        let limiter = limiter(1, 60);
+        let now = Instant::now();
+        limiter.check("a", now).unwrap();
+
+        assert_ok!(limiter.check("b", now));
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let client = client_ip(ip("203.0.113.7"), Some("198.51.100.1"), &[]);
+
+        assert_eq!(client, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn forwarded_for_is_honoured_from_trusted_proxies() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        let client = client_ip(
+            ip("10.0.0.1"),
+            Some("198.51.100.9, 198.51.100.1, 10.0.0.2"),
+            &proxies,
+        );
+
+        assert_eq!(client, ip("198.51.100.1"));
+    }
+
+    #[test]
+    fn a_trusted_proxy_without_forwarded_for_is_the_client() {
+        let client = client_ip(ip("10.0.0.1"), None, &[ip("10.0.0.1")]);
+
+        assert_eq!(client, ip("10.0.0.1"));
+    }
+}
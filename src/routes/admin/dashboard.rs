@@ -0,0 +1,44 @@
+use crate::authentication::UserId;
+use actix_web::http::header::ContentType;
+use actix_web::web::{Data, ReqData};
+use actix_web::HttpResponse;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "Admin dashboard", skip(pool, user_id), fields(user_id = %*user_id))]
+pub async fn admin_dashboard(
+    pool: Data<PgPool>,
+    user_id: ReqData<UserId>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let username = get_username(**user_id, &pool)
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Admin dashboard</title>
+</head>
+<body>
+    <p>Welcome {}!</p>
+    <p><a href="/admin/subscribers">Subscribers</a></p>
+    <form action="/admin/logout" method="post">
+        <input type="submit" value="Logout">
+    </form>
+</body>
+</html>"#,
+            htmlescape::encode_minimal(&username)
+        )))
+}
+
+#[tracing::instrument(name = "Get username", skip(pool))]
+async fn get_username(user_id: Uuid, pool: &PgPool) -> Result<String, sqlx::Error> {
+    let row = sqlx::query!(r#"SELECT username FROM users WHERE user_id = $1"#, user_id,)
+        .fetch_one(pool)
+        .await?;
+    Ok(row.username)
+}
@@ -0,0 +1,12 @@
+use crate::session_state::TypedSession;
+use actix_web::http::header::LOCATION;
+use actix_web::HttpResponse;
+
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "Log out", skip(session))]
+pub async fn log_out(session: TypedSession) -> HttpResponse {
+    session.log_out();
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .finish()
+}
@@ -0,0 +1,15 @@
+mod dashboard;
+mod logout;
+mod newsletters;
+mod subscriber_data;
+mod subscriber_export;
+mod subscriber_import;
+mod subscribers;
+
+pub use dashboard::*;
+pub use logout::*;
+pub use newsletters::*;
+pub use subscriber_data::*;
+pub use subscriber_export::*;
+pub use subscriber_import::*;
+pub use subscribers::*;
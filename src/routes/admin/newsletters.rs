@@ -0,0 +1,197 @@
+use crate::domain::{EmailFrequency, SubscriberEmail};
+use crate::email_outbox::enqueue_email;
+use crate::preferences_token::preferences_link;
+use crate::problem_details::ProblemDetails;
+use crate::routes::{issue_unsubscribe_token, list_unsubscribe_headers};
+use crate::startup::{ApplicationBaseUrl, HmacSecret, PreferenceTopics, PreferencesLinkTtl};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Json};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::routes::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    /// Only subscribers who chose this topic, or never picked any, get the issue.
+    #[serde(default)]
+    topic: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+struct ConfirmedSubscriber {
+    id: Uuid,
+    email: SubscriberEmail,
+}
+
+#[derive(thiserror::Error)]
+pub enum PublishError {
+    #[error("{0} is not a newsletter topic")]
+    UnknownTopic(String),
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the confirmed subscribers")]
+    GetSubscribersError(#[source] sqlx::Error),
+    #[error("Failed to store the newsletter issue for the digests")]
+    StoreIssueError(#[source] sqlx::Error),
+    #[error("Failed to queue the newsletter issue for delivery")]
+    SendEmailError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to queue a newsletter issue")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for PublishError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PublishError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PublishError::UnknownTopic(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code(), self.to_string()).into_response()
+    }
+}
+
+/// Queues the issue for the subscribers who get every issue, and keeps it for the
+/// weekly and monthly digests.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Publishing a newsletter issue",
+    skip(body, pool, base_url, secret, preferences_link_ttl, topics),
+    fields(issue_title = %body.title)
+)]
+pub async fn publish_newsletter(
+    body: Json<BodyData>,
+    pool: Data<PgPool>,
+    base_url: Data<ApplicationBaseUrl>,
+    secret: Data<HmacSecret>,
+    preferences_link_ttl: Data<PreferencesLinkTtl>,
+    topics: Data<PreferenceTopics>,
+) -> Result<HttpResponse, PublishError> {
+    if let Some(topic) = &body.topic {
+        if !topics.0.contains(topic) {
+            return Err(PublishError::UnknownTopic(topic.clone()));
+        }
+    }
+    let subscribers = get_confirmed_subscribers(&pool, body.topic.as_deref())
+        .await
+        .map_err(PublishError::GetSubscribersError)?;
+
+    let mut transaction = pool.begin().await.map_err(PublishError::PoolError)?;
+    store_issue(&mut transaction, &body)
+        .await
+        .map_err(PublishError::StoreIssueError)?;
+
+    for subscriber in subscribers {
+        match subscriber {
+            Ok(subscriber) => {
+                let preferences_link = preferences_link(
+                    &base_url.0,
+                    subscriber.id,
+                    preferences_link_ttl.0,
+                    &secret.0,
+                );
+                let unsubscribe_token =
+                    issue_unsubscribe_token(&mut transaction, subscriber.id, &secret.0)
+                        .await
+                        .map_err(PublishError::SendEmailError)?;
+                enqueue_email(
+                    &mut transaction,
+                    subscriber.id,
+                    &subscriber.email,
+                    &body.title,
+                    &format!(
+                        "{}<p><a href=\"{}\">Manage your preferences</a></p>",
+                        body.content.html, preferences_link
+                    ),
+                    &format!(
+                        "{}\n\nManage your preferences: {}",
+                        body.content.text, preferences_link
+                    ),
+                    &list_unsubscribe_headers(&base_url.0, &unsubscribe_token),
+                )
+                .await
+                .map_err(PublishError::SendEmailError)?;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Skipping a confirmed subscriber. Their stored contact details are invalid: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    transaction
+        .commit()
+        .await
+        .map_err(PublishError::TransactionCommitError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(name = "Store a newsletter issue", skip(transaction, body))]
+async fn store_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    body: &BodyData,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO newsletter_issues
+            (id, title, html_content, text_content, topic, published_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        Uuid::new_v4(),
+        body.title,
+        body.content.html,
+        body.content.text,
+        body.topic,
+        Utc::now(),
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// The confirmed subscribers getting every issue as it is published.
+#[tracing::instrument(name = "Get confirmed subscribers", skip(pool))]
+async fn get_confirmed_subscribers(
+    pool: &PgPool,
+    topic: Option<&str>,
+) -> Result<Vec<Result<ConfirmedSubscriber, String>>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT id, email
+        FROM subscriptions
+        WHERE status = 'confirmed'
+            AND frequency = $2
+            AND ($1::text IS NULL OR topics IS NULL OR $1 = ANY(topics))
+        "#,
+        topic,
+        EmailFrequency::EveryIssue.as_str(),
+    )
+    .fetch_all(pool)
+    .await?;
+    let confirmed_subscribers = rows
+        .into_iter()
+        .map(|r| {
+            SubscriberEmail::parse(r.email).map(|email| ConfirmedSubscriber { id: r.id, email })
+        })
+        .collect();
+    Ok(confirmed_subscribers)
+}
@@ -0,0 +1,76 @@
+use crate::domain::EmailNormalization;
+use crate::problem_details::ProblemDetails;
+use crate::routes::{error_chain_fmt, subscriber_data_response};
+use crate::startup::TombstoneSecret;
+use crate::subscriber_data::{erase_subscriber, export_subscriber_data};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Path};
+use actix_web::{HttpResponse, ResponseError};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(thiserror::Error)]
+pub enum SubscriberDataError {
+    #[error("No subscriber has this id")]
+    UnknownSubscriber,
+    #[error("Failed to export the data of the subscriber")]
+    ExportError(#[source] sqlx::Error),
+    #[error("Failed to erase the data of the subscriber")]
+    EraseError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for SubscriberDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscriberDataError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscriberDataError::UnknownSubscriber => StatusCode::NOT_FOUND,
+            SubscriberDataError::ExportError(_) | SubscriberDataError::EraseError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code(), self.to_string()).into_response()
+    }
+}
+
+/// Answers an access request: everything we hold about the subscriber, as JSON.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "Export the data of a subscriber for an admin", skip(pool))]
+pub async fn admin_export_subscriber_data(
+    subscriber_id: Path<Uuid>,
+    pool: Data<PgPool>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let export = export_subscriber_data(&pool, *subscriber_id)
+        .await
+        .map_err(SubscriberDataError::ExportError)?
+        .ok_or(SubscriberDataError::UnknownSubscriber)?;
+    Ok(subscriber_data_response(&export))
+}
+
+/// Answers an erasure request. Imports skip the address from then on.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Erase the data of a subscriber for an admin",
+    skip(pool, normalization, tombstone_secret)
+)]
+pub async fn admin_erase_subscriber_data(
+    subscriber_id: Path<Uuid>,
+    pool: Data<PgPool>,
+    normalization: Data<EmailNormalization>,
+    tombstone_secret: Data<TombstoneSecret>,
+) -> Result<HttpResponse, SubscriberDataError> {
+    let erased = erase_subscriber(&pool, *subscriber_id, **normalization, &tombstone_secret.0)
+        .await
+        .map_err(SubscriberDataError::EraseError)?;
+    if !erased {
+        return Err(SubscriberDataError::UnknownSubscriber);
+    }
+    Ok(HttpResponse::Ok().finish())
+}
@@ -0,0 +1,170 @@
+use crate::domain::SubscriptionStatus;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::web::{Bytes, Data, Query};
+use actix_web::HttpResponse;
+use chrono::{DateTime, Utc};
+use csv_async::AsyncWriterBuilder;
+use sqlx::{PgPool, Postgres, Transaction};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use super::subscribers::empty_as_none;
+
+/// How many rows are fetched from the cursor at a time.
+const EXPORT_BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    status: Option<SubscriptionStatus>,
+}
+
+#[derive(sqlx::FromRow, serde::Serialize)]
+struct ExportedSubscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+/// Streams the subscribers, oldest first.
+///
+/// Rows are read through a server-side cursor by a background task, which hands
+/// them over in batches through a bounded channel: memory use does not depend on
+/// the size of the list, and a slow client slows the reads down. Rows are exported
+/// as stored, without validation, so that nothing is lost from a backup.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "Export subscribers", skip(pool))]
+pub async fn export_subscribers(pool: Data<PgPool>, query: Query<ExportQuery>) -> HttpResponse {
+    let ExportQuery { format, status } = query.into_inner();
+    let (sender, receiver) = mpsc::channel(2);
+    let pool = pool.get_ref().clone();
+    tokio::spawn(
+        async move {
+            if let Err(e) = stream_subscribers(&pool, format, status, &sender).await {
+                tracing::error!("Failed to export the subscribers: {:?}", e);
+                let _ = sender.send(Err(std::io::Error::other(e))).await;
+            }
+        }
+        .instrument(tracing::Span::current()),
+    );
+
+    let body = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    let (content_type, file_name) = match format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "subscribers.csv"),
+        ExportFormat::Ndjson => ("application/x-ndjson", "subscribers.ndjson"),
+    };
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(file_name.into())],
+        })
+        .streaming(body)
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ExportError {
+    #[error("Failed to read the subscribers")]
+    Database(#[from] sqlx::Error),
+    #[error("Failed to serialize the subscribers")]
+    Csv(#[from] csv_async::Error),
+    #[error("Failed to serialize the subscribers")]
+    Json(#[from] serde_json::Error),
+}
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+/// Returns early, without error, if the client went away.
+async fn stream_subscribers(
+    pool: &PgPool,
+    format: ExportFormat,
+    status: Option<SubscriptionStatus>,
+    sender: &mpsc::Sender<Chunk>,
+) -> Result<(), ExportError> {
+    let mut transaction = pool.begin().await?;
+    // The cursor only lives as long as the transaction; dropping the
+    // transaction on an early return closes it.
+    sqlx::query(
+        r#"
+        DECLARE subscribers_export NO SCROLL CURSOR FOR
+        SELECT id, email, name, status, subscribed_at
+        FROM subscriptions
+        WHERE $1::text IS NULL OR status = $1
+        ORDER BY subscribed_at, id
+        "#,
+    )
+    .bind(status.map(|s| s.as_str()))
+    .execute(&mut transaction)
+    .await?;
+
+    if let ExportFormat::Csv = format {
+        let header = Bytes::from_static(b"id,email,name,status,subscribed_at\n");
+        if sender.send(Ok(header)).await.is_err() {
+            return Ok(());
+        }
+    }
+    loop {
+        let batch = fetch_batch(&mut transaction).await?;
+        if batch.is_empty() {
+            break;
+        }
+        let chunk = match format {
+            ExportFormat::Csv => csv_chunk(&batch).await?,
+            ExportFormat::Ndjson => ndjson_chunk(&batch)?,
+        };
+        if sender.send(Ok(chunk)).await.is_err() {
+            return Ok(());
+        }
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+async fn fetch_batch(
+    transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<ExportedSubscriber>, sqlx::Error> {
+    sqlx::query_as::<_, ExportedSubscriber>(&format!(
+        "FETCH {} FROM subscribers_export",
+        EXPORT_BATCH_SIZE
+    ))
+    .fetch_all(transaction)
+    .await
+}
+
+async fn csv_chunk(batch: &[ExportedSubscriber]) -> Result<Bytes, ExportError> {
+    let mut writer = AsyncWriterBuilder::new()
+        .has_headers(false)
+        .create_serializer(Vec::new());
+    for subscriber in batch {
+        writer.serialize(subscriber).await?;
+    }
+    let buffer = writer
+        .into_inner()
+        .await
+        .map_err(|e| csv_async::Error::from(e.into_error()))?;
+    Ok(Bytes::from(buffer))
+}
+
+fn ndjson_chunk(batch: &[ExportedSubscriber]) -> Result<Bytes, ExportError> {
+    let mut buffer = Vec::new();
+    for subscriber in batch {
+        serde_json::to_writer(&mut buffer, subscriber)?;
+        buffer.push(b'\n');
+    }
+    Ok(Bytes::from(buffer))
+}
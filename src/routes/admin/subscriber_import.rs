@@ -0,0 +1,130 @@
+use crate::domain::{EmailDomainPolicy, EmailNormalization, SubscriptionStatus};
+use crate::problem_details::ProblemDetails;
+use crate::routes::error_chain_fmt;
+use crate::startup::TombstoneSecret;
+use crate::subscriber_import::{import_subscribers, ImportError, IMPORT_BATCH_SIZE};
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::web::{Bytes, Data, Payload, Query};
+use actix_web::{HttpResponse, ResponseError};
+use futures_util::StreamExt;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use tokio_util::io::StreamReader;
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImportQuery {
+    status: SubscriptionStatus,
+}
+
+#[derive(thiserror::Error)]
+pub enum ImportSubscribersError {
+    #[error("Failed to receive the uploaded CSV. The batches received in full were imported")]
+    UploadError(#[source] std::io::Error),
+    #[error("Failed to import the subscribers")]
+    ImportError(#[source] ImportError),
+}
+
+impl std::fmt::Debug for ImportSubscribersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ImportSubscribersError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ImportSubscribersError::UploadError(_)
+            | ImportSubscribersError::ImportError(ImportError::PendingConfirmation) => {
+                StatusCode::BAD_REQUEST
+            }
+            ImportSubscribersError::ImportError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code(), self.to_string()).into_response()
+    }
+}
+
+/// Imports the CSV sent as the request body and answers with the rejected rows,
+/// as a CSV attachment. The counts are also returned in `X-Imported-Count`,
+/// `X-Rejected-Count` and `X-Failed-Batch-Count`.
+///
+/// Batches are committed as they come: if the upload breaks off, the request fails
+/// with a 400 and the batches received in full stay imported. Uploading the file
+/// again imports the rest.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Import subscribers from an upload",
+    skip(pool, normalization, domain_policy, tombstone_secret, payload)
+)]
+pub async fn import_subscribers_csv(
+    pool: Data<PgPool>,
+    normalization: Data<EmailNormalization>,
+    domain_policy: Data<EmailDomainPolicy>,
+    tombstone_secret: Data<TombstoneSecret>,
+    query: Query<ImportQuery>,
+    mut payload: Payload,
+) -> Result<HttpResponse, ImportSubscribersError> {
+    // The request payload cannot leave the current thread, while the CSV reader wants
+    // a `Send` source: pipe the upload through a bounded channel, which also bounds
+    // how much of the file is buffered at any time. A broken upload reaches the CSV
+    // reader as an error rather than as the end of the file, so that a truncated
+    // last batch is not imported.
+    let (sender, receiver) = mpsc::channel::<std::io::Result<Bytes>>(16);
+    let upload = async move {
+        while let Some(chunk) = payload.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    // The import stopped reading: its outcome tells why.
+                    if sender.send(Ok(chunk)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = sender
+                        .send(Err(std::io::ErrorKind::ConnectionAborted.into()))
+                        .await;
+                    return Err(std::io::Error::other(e));
+                }
+            }
+        }
+        Ok(())
+    };
+    let chunks = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    });
+    let (upload, report) = tokio::join!(
+        upload,
+        import_subscribers(
+            &pool,
+            StreamReader::new(Box::pin(chunks)),
+            **normalization,
+            &domain_policy,
+            &tombstone_secret.0,
+            query.status,
+            IMPORT_BATCH_SIZE
+        )
+    );
+    upload.map_err(ImportSubscribersError::UploadError)?;
+    let report = report.map_err(ImportSubscribersError::ImportError)?;
+
+    let body = report
+        .rejected_rows_csv()
+        .await
+        .map_err(ImportSubscribersError::ImportError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("rejected-rows.csv".into())],
+        })
+        .insert_header(("X-Imported-Count", report.imported.to_string()))
+        .insert_header(("X-Rejected-Count", report.rejected.len().to_string()))
+        .insert_header((
+            "X-Failed-Batch-Count",
+            report.failed_batches.len().to_string(),
+        ))
+        .body(body))
+}
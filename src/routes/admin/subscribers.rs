@@ -0,0 +1,332 @@
+use crate::domain::{SubscriberEmail, SubscriberName, SubscriptionStatus};
+use crate::problem_details::ProblemDetails;
+use crate::routes::error_chain_fmt;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Query};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use htmlescape::{encode_attribute, encode_minimal};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct SubscribersQuery {
+    #[serde(
+        default,
+        deserialize_with = "empty_as_none",
+        skip_serializing_if = "Option::is_none"
+    )]
+    status: Option<SubscriptionStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    search: Option<String>,
+    /// Opaque cursor pointing at the last subscriber of the previous page.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    after: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    limit: Option<i64>,
+    #[serde(default)]
+    format: ListingFormat,
+}
+
+/// The "any status" choice of the HTML form submits an empty `status`.
+pub(super) fn empty_as_none<'de, D>(deserializer: D) -> Result<Option<SubscriptionStatus>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let status: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    match status {
+        Some(status) if !status.is_empty() => SubscriptionStatus::try_from(status)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        _ => Ok(None),
+    }
+}
+
+#[derive(serde::Serialize)]
+struct SubscriberSummary {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: SubscriptionStatus,
+    subscribed_at: DateTime<Utc>,
+}
+
+#[derive(serde::Serialize)]
+struct SubscribersPage {
+    subscribers: Vec<SubscriberSummary>,
+    next_cursor: Option<String>,
+}
+
+/// Position of a subscriber in the listing order: newest first, ties broken by id.
+#[derive(Debug)]
+struct Cursor {
+    subscribed_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn parse(s: &str) -> Option<Self> {
+        let (micros, id) = s.split_once('.')?;
+        Some(Self {
+            subscribed_at: DateTime::from_timestamp_micros(micros.parse().ok()?)?,
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+
+    fn encode(&self) -> String {
+        format!("{}.{}", self.subscribed_at.timestamp_micros(), self.id)
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ListSubscribersError {
+    #[error("The pagination cursor is malformed")]
+    InvalidCursor,
+    #[error("Failed to retrieve the subscribers")]
+    DatabaseError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ListSubscribersError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ListSubscribersError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ListSubscribersError::InvalidCursor => StatusCode::BAD_REQUEST,
+            ListSubscribersError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code(), self.to_string()).into_response()
+    }
+}
+
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "List subscribers", skip(pool))]
+pub async fn list_subscribers(
+    pool: Data<PgPool>,
+    query: Query<SubscribersQuery>,
+) -> Result<HttpResponse, ListSubscribersError> {
+    let query = query.into_inner();
+    let after = match &query.after {
+        Some(after) => Some(Cursor::parse(after).ok_or(ListSubscribersError::InvalidCursor)?),
+        None => None,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let search = query
+        .search
+        .as_deref()
+        .map(str::trim)
+        .filter(|s| !s.is_empty());
+
+    let (subscribers, next_cursor) = get_subscribers(&pool, query.status, search, after, limit)
+        .await
+        .map_err(ListSubscribersError::DatabaseError)?;
+    let page = SubscribersPage {
+        subscribers,
+        next_cursor: next_cursor.map(|c| c.encode()),
+    };
+
+    Ok(match query.format {
+        ListingFormat::Json => HttpResponse::Ok().json(page),
+        ListingFormat::Html => HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(subscribers_page(&query, &page)),
+    })
+}
+
+/// Returns a page of subscribers, and the cursor of the next page if there is one.
+/// Rows whose stored email or name no longer pass validation are left out.
+#[tracing::instrument(name = "Get subscribers page", skip(pool, after))]
+async fn get_subscribers(
+    pool: &PgPool,
+    status: Option<SubscriptionStatus>,
+    search: Option<&str>,
+    after: Option<Cursor>,
+    limit: i64,
+) -> Result<(Vec<SubscriberSummary>, Option<Cursor>), sqlx::Error> {
+    let mut rows = sqlx::query!(
+        r#"
+        SELECT id, email, name, status, subscribed_at
+        FROM subscriptions
+        WHERE ($1::text IS NULL OR status = $1)
+            AND ($2::text IS NULL OR email ILIKE $2 OR name ILIKE $2)
+            AND ($3::timestamptz IS NULL OR (subscribed_at, id) < ($3, $4::uuid))
+        ORDER BY subscribed_at DESC, id DESC
+        LIMIT $5
+        "#,
+        status.map(|s| s.as_str()),
+        search.map(like_pattern),
+        after.as_ref().map(|c| c.subscribed_at),
+        after.as_ref().map(|c| c.id),
+        limit + 1,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|r| Cursor {
+            subscribed_at: r.subscribed_at,
+            id: r.id,
+        })
+    } else {
+        None
+    };
+
+    let subscribers = rows
+        .into_iter()
+        .filter_map(|r| {
+            let summary = SubscriberEmail::parse(r.email).and_then(|email| {
+                Ok(SubscriberSummary {
+                    id: r.id,
+                    email: email.as_ref().to_owned(),
+                    name: SubscriberName::parse(r.name)?.as_ref().to_owned(),
+                    status: SubscriptionStatus::try_from(r.status)?,
+                    subscribed_at: r.subscribed_at,
+                })
+            });
+            match summary {
+                Ok(summary) => Some(summary),
+                Err(e) => {
+                    tracing::warn!(
+                        subscriber_id = %r.id,
+                        "Leaving a subscriber out of the listing. Their stored details are invalid: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+    Ok((subscribers, next_cursor))
+}
+
+/// `ILIKE` pattern matching `search` anywhere, with its wildcards taken literally.
+fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+fn subscribers_page(query: &SubscribersQuery, page: &SubscribersPage) -> String {
+    let rows: String = page
+        .subscribers
+        .iter()
+        .map(|s| {
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                encode_minimal(&s.email),
+                encode_minimal(&s.name),
+                s.status,
+                s.subscribed_at.to_rfc3339()
+            )
+        })
+        .collect();
+    let next_page = match &page.next_cursor {
+        Some(cursor) => {
+            let next = SubscribersQuery {
+                after: Some(cursor.clone()),
+                ..query.clone()
+            };
+            let href = format!(
+                "/admin/subscribers?{}",
+                serde_urlencoded::to_string(&next).unwrap_or_default()
+            );
+            format!(r#"<a href="{}">Next page</a>"#, encode_attribute(&href))
+        }
+        None => String::new(),
+    };
+    let status_option = |status: SubscriptionStatus| {
+        let selected = if query.status == Some(status) {
+            " selected"
+        } else {
+            ""
+        };
+        format!(r#"<option value="{0}"{1}>{0}</option>"#, status, selected)
+    };
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscribers</title>
+</head>
+<body>
+    <form action="/admin/subscribers" method="get">
+        <input type="search" name="search" placeholder="Email or name" value="{}">
+        <select name="status">
+            <option value="">any status</option>
+            {}{}{}
+        </select>
+        <button type="submit">Search</button>
+    </form>
+    <table>
+        <thead><tr><th>Email</th><th>Name</th><th>Status</th><th>Subscribed at</th></tr></thead>
+        <tbody>{}</tbody>
+    </table>
+    {}
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        encode_attribute(query.search.as_deref().unwrap_or_default()),
+        status_option(SubscriptionStatus::PendingConfirmation),
+        status_option(SubscriptionStatus::Confirmed),
+        status_option(SubscriptionStatus::Unsubscribed),
+        rows,
+        next_page
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{like_pattern, Cursor};
+    use chrono::{DateTime, Utc};
+    use claim::assert_none;
+    use uuid::Uuid;
+
+    #[test]
+    fn a_cursor_round_trips_with_microsecond_precision() {
+        let cursor = Cursor {
+            subscribed_at: DateTime::<Utc>::from_timestamp_micros(1_646_000_000_123_456).unwrap(),
+            id: Uuid::new_v4(),
+        };
+
+        let parsed = Cursor::parse(&cursor.encode()).unwrap();
+
+        assert_eq!(parsed.subscribed_at, cursor.subscribed_at);
+        assert_eq!(parsed.id, cursor.id);
+    }
+
+    #[test]
+    fn a_malformed_cursor_is_rejected() {
+        assert_none!(Cursor::parse("not-a-cursor"));
+        assert_none!(Cursor::parse("123.not-a-uuid"));
+    }
+
+    #[test]
+    fn like_wildcards_in_the_search_are_escaped() {
+        assert_eq!(like_pattern("50%_off"), r"%50\%\_off%");
+    }
+}
@@ -0,0 +1,513 @@
+use crate::domain::{candidate_domains, EmailDomainPolicy, EmailNormalization, SubscriberEmail};
+use crate::email_outbox::enqueue_email;
+use crate::email_templates::EmailTemplates;
+use crate::preferences_token::{preferences_link, verify_preferences_token, PreferencesTokenError};
+use crate::problem_details::{field_errors, FieldError, ProblemDetails};
+use crate::startup::{
+    ApplicationBaseUrl, HmacSecret, PreferenceTopics, PreferencesLinkTtl, SubscriptionTokenTtl,
+    TombstoneSecret,
+};
+use crate::subscriber_data::{erased_email_hash, get_erased_email_hashes};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use secrecy::Secret;
+use serde::Deserialize;
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use validator::{Validate, ValidationErrors};
+
+use super::preferences::{
+    get_preferences, preferences_response, record_preference_changes, PreferenceChange,
+};
+use super::{
+    error_chain_fmt, generate_subscription_token, get_email_domain_rules, hash_subscription_token,
+    issue_unsubscribe_token, list_unsubscribe_headers, validate_subscription_token,
+    SUBSCRIPTION_TOKEN_LENGTH,
+};
+
+#[derive(Deserialize)]
+pub struct EmailChangeFormData {
+    token: String,
+    email: String,
+}
+
+#[derive(Debug, Validate, Deserialize)]
+pub struct EmailChangeParameters {
+    #[validate(
+        length(
+            equal = "SUBSCRIPTION_TOKEN_LENGTH",
+            message = "Email change token has the wrong length"
+        ),
+        custom = "validate_subscription_token"
+    )]
+    change_token: String,
+}
+
+struct Subscriber {
+    email: String,
+    email_canonical: String,
+    name: String,
+    locale: String,
+}
+
+struct StoredChangeRequest {
+    id: Uuid,
+    subscriber_id: Uuid,
+    new_email: String,
+    new_email_canonical: String,
+    expires_at: DateTime<Utc>,
+    confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(thiserror::Error)]
+pub enum RequestEmailChangeError {
+    #[error("{0}")]
+    InvalidToken(PreferencesTokenError),
+    #[error("No subscriber is associated with the preferences link")]
+    UnknownSubscriber,
+    #[error("The new email address is invalid")]
+    ValidationError(Vec<FieldError>),
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the email domain rules")]
+    DomainRulesError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber")]
+    GetSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to store the email change request")]
+    StoreRequestError(#[source] sqlx::Error),
+    #[error("Failed to render the email")]
+    TemplateError(#[source] tera::Error),
+    #[error("Failed to queue the email for delivery")]
+    SendEmailError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to store an email change request")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for RequestEmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for RequestEmailChangeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RequestEmailChangeError::InvalidToken(_)
+            | RequestEmailChangeError::UnknownSubscriber => StatusCode::UNAUTHORIZED,
+            RequestEmailChangeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            RequestEmailChangeError::PoolError(_)
+            | RequestEmailChangeError::DomainRulesError(_)
+            | RequestEmailChangeError::GetSubscriberError(_)
+            | RequestEmailChangeError::StoreRequestError(_)
+            | RequestEmailChangeError::TemplateError(_)
+            | RequestEmailChangeError::SendEmailError(_)
+            | RequestEmailChangeError::TransactionCommitError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            RequestEmailChangeError::ValidationError(errors) => problem.with_errors(errors.clone()),
+            _ => problem,
+        }
+        .into_response()
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmEmailChangeError {
+    #[error("The email change token is malformed")]
+    InvalidToken(ValidationErrors),
+    #[error("No email change is associated with the token")]
+    UnknownToken,
+    #[error("The email change has already been confirmed")]
+    AlreadyConfirmed,
+    #[error("The email change token has expired")]
+    ExpiredToken,
+    #[error("The new email address is already subscribed")]
+    EmailTaken,
+    #[error("The new email address was erased at its owner's request")]
+    ErasedEmail,
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the email change request")]
+    GetRequestError(#[source] sqlx::Error),
+    #[error("Failed to look up the tombstones of erased addresses")]
+    ErasedEmailsError(#[source] sqlx::Error),
+    #[error("Failed to change the email address of the subscriber")]
+    ChangeEmailError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to change an email address")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ConfirmEmailChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmEmailChangeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmEmailChangeError::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            ConfirmEmailChangeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmEmailChangeError::AlreadyConfirmed
+            | ConfirmEmailChangeError::EmailTaken
+            | ConfirmEmailChangeError::ErasedEmail => StatusCode::CONFLICT,
+            ConfirmEmailChangeError::ExpiredToken => StatusCode::GONE,
+            ConfirmEmailChangeError::PoolError(_)
+            | ConfirmEmailChangeError::GetRequestError(_)
+            | ConfirmEmailChangeError::ErasedEmailsError(_)
+            | ConfirmEmailChangeError::ChangeEmailError(_)
+            | ConfirmEmailChangeError::TransactionCommitError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            ConfirmEmailChangeError::InvalidToken(errors) => {
+                problem.with_errors(field_errors(errors))
+            }
+            _ => problem,
+        }
+        .into_response()
+    }
+}
+
+/// Starts moving a subscriber to a new address, from the preferences page.
+///
+/// Nothing changes until the link sent to the new address is followed, and the
+/// old address is told about the request in the meantime.
+#[allow(clippy::async_yields_async, clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Request an email address change",
+    skip(form, pool, base_url, secret, token_ttl, preferences_link_ttl, normalization, domain_policy, templates, topics),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn request_email_change(
+    form: Form<EmailChangeFormData>,
+    pool: Data<PgPool>,
+    base_url: Data<ApplicationBaseUrl>,
+    secret: Data<HmacSecret>,
+    token_ttl: Data<SubscriptionTokenTtl>,
+    preferences_link_ttl: Data<PreferencesLinkTtl>,
+    normalization: Data<EmailNormalization>,
+    domain_policy: Data<EmailDomainPolicy>,
+    templates: Data<EmailTemplates>,
+    topics: Data<PreferenceTopics>,
+) -> Result<HttpResponse, RequestEmailChangeError> {
+    let subscriber_id = verify_preferences_token(&form.token, &secret.0, Utc::now())
+        .map_err(RequestEmailChangeError::InvalidToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    let new_email =
+        SubscriberEmail::parse_with(form.email.clone(), **normalization).map_err(|e| {
+            RequestEmailChangeError::ValidationError(vec![
+                FieldError::new("email", e).with_code("invalid")
+            ])
+        })?;
+    let operator_rules =
+        get_email_domain_rules(&**pool, Some(&candidate_domains(new_email.domain())))
+            .await
+            .map_err(RequestEmailChangeError::DomainRulesError)?;
+    if let Err(rejection) = domain_policy.check(&new_email, &operator_rules) {
+        return Err(RequestEmailChangeError::ValidationError(vec![
+            FieldError::new("email", rejection.to_string()).with_code(rejection.code()),
+        ]));
+    }
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(RequestEmailChangeError::PoolError)?;
+    let subscriber = get_subscriber(&mut transaction, subscriber_id)
+        .await
+        .map_err(RequestEmailChangeError::GetSubscriberError)?
+        .ok_or(RequestEmailChangeError::UnknownSubscriber)?;
+    if subscriber.email_canonical == new_email.canonical() {
+        return Err(RequestEmailChangeError::ValidationError(vec![
+            FieldError::new("email", "This is already your email address.").with_code("unchanged"),
+        ]));
+    }
+
+    // Whether the new address is taken is only checked on confirmation, so that
+    // this form does not tell who else is subscribed.
+    let token = generate_subscription_token();
+    store_change_request(
+        &mut transaction,
+        subscriber_id,
+        &new_email,
+        &hash_subscription_token(&token, &secret.0),
+        token_ttl.0,
+    )
+    .await
+    .map_err(RequestEmailChangeError::StoreRequestError)?;
+
+    let preferences_link = preferences_link(
+        &base_url.0,
+        subscriber_id,
+        preferences_link_ttl.0,
+        &secret.0,
+    );
+    let unsubscribe_token = issue_unsubscribe_token(&mut transaction, subscriber_id, &secret.0)
+        .await
+        .map_err(RequestEmailChangeError::SendEmailError)?;
+    let headers = list_unsubscribe_headers(&base_url.0, &unsubscribe_token);
+    let mut context = tera::Context::new();
+    context.insert("name", &subscriber.name);
+    context.insert("new_email", new_email.as_ref());
+    context.insert("preferences_link", &preferences_link);
+    context.insert(
+        "confirmation_link",
+        &format!(
+            "{}/subscriptions/confirm_email_change?change_token={}",
+            base_url.0, token
+        ),
+    );
+    let confirmation = templates
+        .render("email_change_confirmation", &subscriber.locale, &context)
+        .map_err(RequestEmailChangeError::TemplateError)?;
+    enqueue_email(
+        &mut transaction,
+        subscriber_id,
+        &new_email,
+        &confirmation.subject,
+        &confirmation.html_body,
+        &confirmation.text_body,
+        &headers,
+    )
+    .await
+    .map_err(RequestEmailChangeError::SendEmailError)?;
+
+    match SubscriberEmail::parse(subscriber.email) {
+        Ok(old_email) => {
+            let notice = templates
+                .render("email_change_notice", &subscriber.locale, &context)
+                .map_err(RequestEmailChangeError::TemplateError)?;
+            enqueue_email(
+                &mut transaction,
+                subscriber_id,
+                &old_email,
+                &notice.subject,
+                &notice.html_body,
+                &notice.text_body,
+                &headers,
+            )
+            .await
+            .map_err(RequestEmailChangeError::SendEmailError)?;
+        }
+        Err(e) => tracing::warn!(
+            "Not notifying the current address of the change. It is invalid: {}",
+            e
+        ),
+    }
+
+    let preferences = get_preferences(&mut transaction, subscriber_id)
+        .await
+        .map_err(RequestEmailChangeError::GetSubscriberError)?
+        .ok_or(RequestEmailChangeError::UnknownSubscriber)?;
+    transaction
+        .commit()
+        .await
+        .map_err(RequestEmailChangeError::TransactionCommitError)?;
+    Ok(preferences_response(
+        &form.token,
+        &preferences,
+        &topics.0,
+        Some("Follow the link we sent to your new address to complete the change."),
+    ))
+}
+
+/// Moves the subscriber to the new address, following the link sent to it.
+/// An address erased in the meantime is refused, as imports refuse it.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Confirm an email address change",
+    skip(param, pool, secret, tombstone_secret)
+)]
+pub async fn confirm_email_change(
+    param: Query<EmailChangeParameters>,
+    pool: Data<PgPool>,
+    secret: Data<HmacSecret>,
+    tombstone_secret: Data<TombstoneSecret>,
+) -> Result<HttpResponse, ConfirmEmailChangeError> {
+    param
+        .validate()
+        .map_err(ConfirmEmailChangeError::InvalidToken)?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ConfirmEmailChangeError::PoolError)?;
+    let request = get_change_request(&mut transaction, &param.change_token, &secret.0)
+        .await
+        .map_err(ConfirmEmailChangeError::GetRequestError)?;
+    let request = match request {
+        None => return Err(ConfirmEmailChangeError::UnknownToken),
+        Some(r) if r.confirmed_at.is_some() => {
+            return Err(ConfirmEmailChangeError::AlreadyConfirmed)
+        }
+        Some(r) if r.expires_at <= Utc::now() => return Err(ConfirmEmailChangeError::ExpiredToken),
+        Some(r) => r,
+    };
+    let erased = get_erased_email_hashes(
+        &mut transaction,
+        &[erased_email_hash(
+            &request.new_email_canonical,
+            &tombstone_secret.0,
+        )],
+    )
+    .await
+    .map_err(ConfirmEmailChangeError::ErasedEmailsError)?;
+    if !erased.is_empty() {
+        return Err(ConfirmEmailChangeError::ErasedEmail);
+    }
+
+    let old_email = get_subscriber(&mut transaction, request.subscriber_id)
+        .await
+        .map_err(ConfirmEmailChangeError::ChangeEmailError)?
+        .ok_or(ConfirmEmailChangeError::UnknownToken)?
+        .email;
+    change_email(&mut transaction, &request)
+        .await
+        .map_err(|e| match &e {
+            sqlx::Error::Database(db) if db.code().as_deref() == Some("23505") => {
+                ConfirmEmailChangeError::EmailTaken
+            }
+            _ => ConfirmEmailChangeError::ChangeEmailError(e),
+        })?;
+    record_preference_changes(
+        &mut transaction,
+        request.subscriber_id,
+        &[PreferenceChange {
+            field: "email",
+            old_value: Some(old_email),
+            new_value: Some(request.new_email.clone()),
+        }],
+    )
+    .await
+    .map_err(ConfirmEmailChangeError::ChangeEmailError)?;
+    transaction
+        .commit()
+        .await
+        .map_err(ConfirmEmailChangeError::TransactionCommitError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(name = "Get subscriber for an email change", skip(transaction))]
+async fn get_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT email, email_canonical, name, locale
+        FROM subscriptions
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// A new request replaces the ones still pending, so that only the latest link works.
+#[tracing::instrument(
+    name = "Store an email change request",
+    skip(transaction, new_email, token_hash)
+)]
+async fn store_change_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+    token_hash: &str,
+    ttl: chrono::Duration,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM email_change_requests
+        WHERE subscriber_id = $1 AND confirmed_at IS NULL
+        "#,
+        subscriber_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    let requested_at = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests
+            (id, subscriber_id, new_email, new_email_canonical, token_hash, requested_at, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        new_email.as_ref(),
+        new_email.canonical(),
+        token_hash,
+        requested_at,
+        requested_at + ttl,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Locks the request until the transaction ends, so that it cannot be confirmed twice.
+#[tracing::instrument(
+    name = "Get email change request from token",
+    skip(transaction, change_token, secret)
+)]
+async fn get_change_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    change_token: &str,
+    secret: &Secret<String>,
+) -> Result<Option<StoredChangeRequest>, sqlx::Error> {
+    sqlx::query_as!(
+        StoredChangeRequest,
+        r#"
+        SELECT id, subscriber_id, new_email, new_email_canonical, expires_at, confirmed_at
+        FROM email_change_requests
+        WHERE token_hash = $1
+        FOR UPDATE
+        "#,
+        hash_subscription_token(change_token, secret),
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// Fails with a unique violation if another subscriber has the new address.
+#[tracing::instrument(
+    name = "Change the email address of a subscriber",
+    skip(transaction, request)
+)]
+async fn change_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    request: &StoredChangeRequest,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET email = $2, email_canonical = $3 WHERE id = $1"#,
+        request.subscriber_id,
+        request.new_email,
+        request.new_email_canonical,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"UPDATE email_change_requests SET confirmed_at = now() WHERE id = $1"#,
+        request.id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::problem_details::ProblemDetails;
+use crate::session_state::TypedSession;
+use actix_session::SessionInsertError;
+use actix_web::http::header::{ContentType, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form};
+use actix_web::{HttpResponse, ResponseError};
+use secrecy::Secret;
+use sqlx::PgPool;
+
+use super::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct LoginFormData {
+    username: String,
+    password: Secret<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum LoginError {
+    #[error("Authentication failed")]
+    AuthError(#[source] AuthError),
+    #[error("Failed to check the credentials")]
+    UnexpectedError(#[source] AuthError),
+    #[error("Failed to store the user in the session")]
+    SessionError(#[source] SessionInsertError),
+}
+
+impl std::fmt::Debug for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            LoginError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            LoginError::UnexpectedError(_) | LoginError::SessionError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code())
+            .content_type(ContentType::html())
+            .body(login_page(Some(&self.to_string())));
+        response
+            .extensions_mut()
+            .insert(ProblemDetails::new(self.status_code(), self.to_string()));
+        response
+    }
+}
+
+pub async fn login_form() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(login_page(None))
+}
+
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Log in",
+    skip(form, pool, session),
+    fields(username = %form.username, user_id = tracing::field::Empty)
+)]
+pub async fn login(
+    form: Form<LoginFormData>,
+    pool: Data<PgPool>,
+    session: TypedSession,
+) -> Result<HttpResponse, LoginError> {
+    let form = form.into_inner();
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+    let user_id = validate_credentials(credentials, &pool)
+        .await
+        .map_err(|e| match e {
+            AuthError::InvalidCredentials => LoginError::AuthError(e),
+            _ => LoginError::UnexpectedError(e),
+        })?;
+    tracing::Span::current().record("user_id", tracing::field::display(&user_id));
+
+    session.renew();
+    session
+        .insert_user_id(user_id)
+        .map_err(LoginError::SessionError)?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/dashboard"))
+        .finish())
+}
+
+/// `error` is only ever one of our own messages, never user input.
+fn login_page(error: Option<&str>) -> String {
+    let error = error
+        .map(|e| format!("<p><i>{}</i></p>", e))
+        .unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Login</title>
+</head>
+<body>
+    {}
+    <form action="/login" method="post">
+        <label>Username
+            <input type="text" placeholder="Enter Username" name="username">
+        </label>
+        <label>Password
+            <input type="password" placeholder="Enter Password" name="password">
+        </label>
+        <button type="submit">Login</button>
+    </form>
+</body>
+</html>"#,
+        error
+    )
+}
@@ -1,7 +1,23 @@
+mod admin;
+mod email_change;
 mod health_check;
+mod login;
+mod preferences;
+mod resend_confirmation;
+mod subscriber_data;
 mod subscription_confirm;
+mod subscription_form;
+mod subscription_unsubscribe;
 mod subscriptions;
 
+pub use admin::*;
+pub use email_change::*;
 pub use health_check::*;
+pub use login::*;
+pub use preferences::*;
+pub use resend_confirmation::*;
+pub use subscriber_data::*;
 pub use subscription_confirm::*;
+pub use subscription_form::*;
+pub use subscription_unsubscribe::*;
 pub use subscriptions::*;
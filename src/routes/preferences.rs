@@ -0,0 +1,547 @@
+use crate::domain::{EmailFrequency, SubscriberName};
+use crate::preferences_token::{verify_preferences_token, PreferencesTokenError};
+use crate::problem_details::{FieldError, ProblemDetails};
+use crate::startup::{HmacSecret, PreferenceTopics};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::Utc;
+use htmlescape::{encode_attribute, encode_minimal};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesParameters {
+    token: String,
+}
+
+/// The preferences form, with every checked topic submitted as its own `topics` field.
+#[derive(Default)]
+struct PreferencesForm {
+    token: String,
+    action: Option<String>,
+    name: Option<String>,
+    frequency: Option<String>,
+    topics: Vec<String>,
+}
+
+impl PreferencesForm {
+    fn from_pairs(pairs: Vec<(String, String)>) -> Self {
+        let mut form = Self::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "token" => form.token = value,
+                "action" => form.action = Some(value),
+                "name" => form.name = Some(value),
+                "frequency" => form.frequency = Some(value),
+                "topics" => form.topics.push(value),
+                _ => {}
+            }
+        }
+        form
+    }
+}
+
+pub(super) struct Preferences {
+    email: String,
+    name: String,
+    status: String,
+    /// `None` until the subscriber picks topics, meaning all of them.
+    topics: Option<Vec<String>>,
+    frequency: String,
+}
+
+/// The preferences a subscriber submitted, once validated.
+struct PreferencesUpdate {
+    name: SubscriberName,
+    frequency: EmailFrequency,
+    topics: Vec<String>,
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("{0}")]
+    InvalidToken(PreferencesTokenError),
+    #[error("No subscriber is associated with the preferences link")]
+    UnknownSubscriber,
+    #[error("The preferences form contains invalid fields")]
+    ValidationError(Vec<FieldError>),
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber preferences")]
+    GetPreferencesError(#[source] sqlx::Error),
+    #[error("Failed to store the subscriber preferences")]
+    StorePreferencesError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to store the subscriber preferences")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Display for PreferencesTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PreferencesTokenError::Invalid => f.write_str("The preferences link is not valid"),
+            PreferencesTokenError::Expired => {
+                f.write_str("The preferences link has expired, use the one in a more recent email")
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::InvalidToken(_) | PreferencesError::UnknownSubscriber => {
+                StatusCode::UNAUTHORIZED
+            }
+            PreferencesError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            PreferencesError::PoolError(_)
+            | PreferencesError::GetPreferencesError(_)
+            | PreferencesError::StorePreferencesError(_)
+            | PreferencesError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            PreferencesError::ValidationError(errors) => problem.with_errors(errors.clone()),
+            _ => problem,
+        }
+        .into_response()
+    }
+}
+
+/// The page reached from the preferences link at the bottom of every email.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Show the preferences of a subscriber",
+    skip(param, pool, secret, topics),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn preferences_page(
+    param: Query<PreferencesParameters>,
+    pool: Data<PgPool>,
+    secret: Data<HmacSecret>,
+    topics: Data<PreferenceTopics>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = verify_preferences_token(&param.token, &secret.0, Utc::now())
+        .map_err(PreferencesError::InvalidToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    let mut transaction = pool.begin().await.map_err(PreferencesError::PoolError)?;
+    let preferences = get_preferences(&mut transaction, subscriber_id)
+        .await
+        .map_err(PreferencesError::GetPreferencesError)?
+        .ok_or(PreferencesError::UnknownSubscriber)?;
+    Ok(preferences_response(
+        &param.token,
+        &preferences,
+        &topics.0,
+        None,
+    ))
+}
+
+/// Saves the submitted preferences, or unsubscribes, logging every change.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Update the preferences of a subscriber",
+    skip(form, pool, secret, topics),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn update_preferences(
+    form: Form<Vec<(String, String)>>,
+    pool: Data<PgPool>,
+    secret: Data<HmacSecret>,
+    topics: Data<PreferenceTopics>,
+) -> Result<HttpResponse, PreferencesError> {
+    let form = PreferencesForm::from_pairs(form.into_inner());
+    let subscriber_id = verify_preferences_token(&form.token, &secret.0, Utc::now())
+        .map_err(PreferencesError::InvalidToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    let unsubscribing = form.action.as_deref() == Some("unsubscribe");
+    let update = if unsubscribing {
+        None
+    } else {
+        Some(parse_update(&form, &topics.0).map_err(PreferencesError::ValidationError)?)
+    };
+
+    let mut transaction = pool.begin().await.map_err(PreferencesError::PoolError)?;
+    let current = get_preferences(&mut transaction, subscriber_id)
+        .await
+        .map_err(PreferencesError::GetPreferencesError)?
+        .ok_or(PreferencesError::UnknownSubscriber)?;
+
+    let changes = match &update {
+        Some(update) => preference_changes(&current, update),
+        None if current.status != "unsubscribed" => vec![PreferenceChange {
+            field: "status",
+            old_value: Some(current.status.clone()),
+            new_value: Some("unsubscribed".into()),
+        }],
+        None => Vec::new(),
+    };
+    match &update {
+        Some(update) => store_preferences(&mut transaction, subscriber_id, update).await,
+        None => mark_subscriber_as_unsubscribed(&mut transaction, subscriber_id).await,
+    }
+    .map_err(PreferencesError::StorePreferencesError)?;
+    record_preference_changes(&mut transaction, subscriber_id, &changes)
+        .await
+        .map_err(PreferencesError::StorePreferencesError)?;
+
+    let preferences = get_preferences(&mut transaction, subscriber_id)
+        .await
+        .map_err(PreferencesError::GetPreferencesError)?
+        .ok_or(PreferencesError::UnknownSubscriber)?;
+    transaction
+        .commit()
+        .await
+        .map_err(PreferencesError::TransactionCommitError)?;
+
+    let notice = if unsubscribing {
+        "You have been unsubscribed."
+    } else {
+        "Your preferences have been saved."
+    };
+    Ok(preferences_response(
+        &form.token,
+        &preferences,
+        &topics.0,
+        Some(notice),
+    ))
+}
+
+/// Every field is checked, so that every problem is reported at once.
+fn parse_update(
+    form: &PreferencesForm,
+    available_topics: &[String],
+) -> Result<PreferencesUpdate, Vec<FieldError>> {
+    let mut errors = Vec::new();
+    let name = SubscriberName::parse(form.name.clone().unwrap_or_default())
+        .map_err(|e| errors.push(FieldError::new("name", e)))
+        .ok();
+    let frequency = EmailFrequency::try_from(form.frequency.clone().unwrap_or_default())
+        .map_err(|e| errors.push(FieldError::new("frequency", e)))
+        .ok();
+    let mut topics = Vec::new();
+    for topic in &form.topics {
+        if !available_topics.contains(topic) {
+            errors.push(FieldError::new(
+                "topics",
+                format!("{} is not a newsletter topic.", topic),
+            ));
+        } else if !topics.contains(topic) {
+            topics.push(topic.clone());
+        }
+    }
+    topics.sort();
+
+    match (name, frequency) {
+        (Some(name), Some(frequency)) if errors.is_empty() => Ok(PreferencesUpdate {
+            name,
+            frequency,
+            topics,
+        }),
+        _ => Err(errors),
+    }
+}
+
+pub(super) struct PreferenceChange {
+    pub field: &'static str,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+fn preference_changes(current: &Preferences, update: &PreferencesUpdate) -> Vec<PreferenceChange> {
+    let mut changes = Vec::new();
+    let mut compare = |field, old_value: Option<String>, new_value: Option<String>| {
+        if old_value != new_value {
+            changes.push(PreferenceChange {
+                field,
+                old_value,
+                new_value,
+            });
+        }
+    };
+    compare(
+        "name",
+        Some(current.name.clone()),
+        Some(update.name.as_ref().to_owned()),
+    );
+    compare(
+        "frequency",
+        Some(current.frequency.clone()),
+        Some(update.frequency.as_str().to_owned()),
+    );
+    compare(
+        "topics",
+        current.topics.as_ref().map(|t| t.join(",")),
+        Some(update.topics.join(",")),
+    );
+    changes
+}
+
+#[tracing::instrument(name = "Get subscriber preferences", skip(transaction))]
+pub(super) async fn get_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<Preferences>, sqlx::Error> {
+    sqlx::query_as!(
+        Preferences,
+        r#"
+        SELECT email, name, status, topics, frequency
+        FROM subscriptions
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+#[tracing::instrument(name = "Store subscriber preferences", skip(transaction, update))]
+async fn store_preferences(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    update: &PreferencesUpdate,
+) -> Result<(), sqlx::Error> {
+    // Switching frequency starts a new digest period, so that the first digest does
+    // not repeat the issues already received.
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET name = $2,
+            frequency = $3,
+            topics = $4,
+            last_digest_at = CASE WHEN frequency = $3 THEN last_digest_at ELSE now() END
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        update.name.as_ref(),
+        update.frequency.as_str(),
+        &update.topics,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Unsubscribe from the preferences page", skip(transaction))]
+async fn mark_subscriber_as_unsubscribed(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1"#,
+        subscriber_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Record preference changes", skip(transaction, changes))]
+pub(super) async fn record_preference_changes(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    changes: &[PreferenceChange],
+) -> Result<(), sqlx::Error> {
+    let changed_at = Utc::now();
+    for change in changes {
+        sqlx::query!(
+            r#"
+            INSERT INTO subscriber_preference_changes
+                (id, subscriber_id, field, old_value, new_value, changed_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            Uuid::new_v4(),
+            subscriber_id,
+            change.field,
+            change.old_value,
+            change.new_value,
+            changed_at,
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    Ok(())
+}
+
+pub(super) fn preferences_response(
+    token: &str,
+    preferences: &Preferences,
+    available_topics: &[String],
+    notice: Option<&str>,
+) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(preferences_page_html(
+            token,
+            preferences,
+            available_topics,
+            notice,
+        ))
+}
+
+/// `notice` is only ever one of our own messages, never user input.
+fn preferences_page_html(
+    token: &str,
+    preferences: &Preferences,
+    available_topics: &[String],
+    notice: Option<&str>,
+) -> String {
+    let notice = notice
+        .map(|n| format!("<p><i>{}</i></p>", n))
+        .unwrap_or_default();
+    let status = if preferences.status == "unsubscribed" {
+        "<p>You are not subscribed to the newsletter anymore.</p>"
+    } else {
+        ""
+    };
+    let topics: String = available_topics
+        .iter()
+        .map(|topic| {
+            let checked = preferences
+                .topics
+                .as_ref()
+                .is_none_or(|chosen| chosen.contains(topic));
+            format!(
+                r#"
+            <label><input type="checkbox" name="topics" value="{}"{}> {}</label>"#,
+                encode_attribute(topic),
+                if checked { " checked" } else { "" },
+                encode_minimal(topic)
+            )
+        })
+        .collect();
+    let frequencies: String = EmailFrequency::ALL
+        .iter()
+        .map(|frequency| {
+            let checked = preferences.frequency == frequency.as_str();
+            format!(
+                r#"
+            <label><input type="radio" name="frequency" value="{}"{}> {}</label>"#,
+                frequency.as_str(),
+                if checked { " checked" } else { "" },
+                frequency.label()
+            )
+        })
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    <h1>Preferences of {}</h1>
+    {}{}
+    <form action="/preferences" method="post">
+        <input type="hidden" name="token" value="{}">
+        <label>Name
+            <input type="text" name="name" value="{}">
+        </label>
+        <fieldset>
+            <legend>Topics</legend>{}
+        </fieldset>
+        <fieldset>
+            <legend>How often</legend>{}
+        </fieldset>
+        <button type="submit" name="action" value="save">Save</button>
+        <button type="submit" name="action" value="unsubscribe">Unsubscribe</button>
+    </form>
+    <form action="/preferences/email" method="post">
+        <input type="hidden" name="token" value="{}">
+        <label>New email address
+            <input type="email" name="email">
+        </label>
+        <button type="submit">Change email address</button>
+    </form>
+    <p><a href="/preferences/data?token={}">Download your data</a></p>
+    <form action="/preferences/erase" method="post">
+        <input type="hidden" name="token" value="{}">
+        <button type="submit">Erase your data</button>
+    </form>
+</body>
+</html>"#,
+        encode_minimal(&preferences.email),
+        notice,
+        status,
+        encode_attribute(token),
+        encode_attribute(&preferences.name),
+        topics,
+        frequencies,
+        encode_attribute(token),
+        encode_attribute(token),
+        encode_attribute(token)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_update, PreferencesForm};
+    use crate::domain::EmailFrequency;
+
+    fn available_topics() -> Vec<String> {
+        vec!["articles".into(), "announcements".into()]
+    }
+
+    fn form(name: &str, frequency: &str, topics: &[&str]) -> PreferencesForm {
+        PreferencesForm {
+            name: Some(name.into()),
+            frequency: Some(frequency.into()),
+            topics: topics.iter().map(|t| t.to_string()).collect(),
+            ..PreferencesForm::default()
+        }
+    }
+
+    #[test]
+    fn repeated_topics_are_all_kept() {
+        let form = PreferencesForm::from_pairs(vec![
+            ("token".into(), "t".into()),
+            ("topics".into(), "articles".into()),
+            ("topics".into(), "announcements".into()),
+        ]);
+
+        assert_eq!(form.topics, vec!["articles", "announcements"]);
+    }
+
+    #[test]
+    fn a_valid_form_is_parsed() {
+        let update = parse_update(
+            &form(
+                "Ursula",
+                "weekly",
+                &["articles", "announcements", "articles"],
+            ),
+            &available_topics(),
+        )
+        .unwrap();
+
+        assert_eq!(update.name.as_ref(), "Ursula");
+        assert_eq!(update.frequency, EmailFrequency::Weekly);
+        assert_eq!(update.topics, vec!["announcements", "articles"]);
+    }
+
+    #[test]
+    fn every_invalid_field_is_reported() {
+        let errors = parse_update(&form("", "hourly", &["gossip"]), &available_topics())
+            .err()
+            .unwrap();
+
+        let fields: Vec<_> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["name", "frequency", "topics"]);
+    }
+}
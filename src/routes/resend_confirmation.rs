@@ -0,0 +1,191 @@
+use crate::domain::{EmailNormalization, NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::email_templates::EmailTemplates;
+use crate::preferences_token::preferences_link;
+use crate::problem_details::{FieldError, ProblemDetails};
+use crate::startup::{ApplicationBaseUrl, HmacSecret, PreferencesLinkTtl, SubscriptionTokenTtl};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form};
+use actix_web::{HttpResponse, ResponseError};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{
+    error_chain_fmt, expire_pending_tokens, generate_subscription_token, hash_subscription_token,
+    issue_unsubscribe_token, send_confirmation_email, store_token, SubscribeError,
+};
+
+#[derive(serde::Deserialize)]
+pub struct ResendConfirmationFormData {
+    email: String,
+}
+
+struct PendingSubscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    locale: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ResendConfirmationError {
+    #[error("The email address is invalid")]
+    ValidationError(Vec<FieldError>),
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the pending subscriber")]
+    GetSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to replace the confirmation tokens of the subscriber")]
+    StoreTokenError(#[source] sqlx::Error),
+    #[error("Failed to send the confirmation email")]
+    SendEmailError(#[source] SubscribeError),
+    #[error("Failed to commit SQL transaction to resend a confirmation email")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ResendConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ResendConfirmationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ResendConfirmationError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            ResendConfirmationError::PoolError(_)
+            | ResendConfirmationError::GetSubscriberError(_)
+            | ResendConfirmationError::StoreTokenError(_)
+            | ResendConfirmationError::SendEmailError(_)
+            | ResendConfirmationError::TransactionCommitError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            ResendConfirmationError::ValidationError(errors) => problem.with_errors(errors.clone()),
+            _ => problem,
+        }
+        .into_response()
+    }
+}
+
+/// Sends a new confirmation link to a subscriber who lost the first one.
+///
+/// The response is the same whether or not the address is pending confirmation,
+/// so that the endpoint does not reveal who is subscribed.
+#[allow(clippy::async_yields_async, clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip(
+        form,
+        pool,
+        base_url,
+        token_ttl,
+        secret,
+        normalization,
+        templates,
+        preferences_link_ttl
+    )
+)]
+pub async fn resend_confirmation(
+    form: Form<ResendConfirmationFormData>,
+    pool: Data<PgPool>,
+    base_url: Data<ApplicationBaseUrl>,
+    token_ttl: Data<SubscriptionTokenTtl>,
+    secret: Data<HmacSecret>,
+    normalization: Data<EmailNormalization>,
+    templates: Data<EmailTemplates>,
+    preferences_link_ttl: Data<PreferencesLinkTtl>,
+) -> Result<HttpResponse, ResendConfirmationError> {
+    let email = SubscriberEmail::parse_with(form.0.email, **normalization).map_err(|e| {
+        ResendConfirmationError::ValidationError(vec![
+            FieldError::new("email", e).with_code("invalid")
+        ])
+    })?;
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .map_err(ResendConfirmationError::PoolError)?;
+    let pending = get_pending_subscriber(&mut transaction, &email)
+        .await
+        .map_err(ResendConfirmationError::GetSubscriberError)?;
+    let pending = match pending {
+        Some(pending) => pending,
+        None => return Ok(HttpResponse::Ok().finish()),
+    };
+    let subscriber = match (
+        SubscriberName::parse(pending.name),
+        SubscriberEmail::parse(pending.email),
+    ) {
+        (Ok(name), Ok(email)) => NewSubscriber { name, email },
+        (name, email) => {
+            tracing::warn!(
+                subscriber_id = %pending.id,
+                "Not resending a confirmation email. The stored contact details are invalid: {}",
+                name.err().or(email.err()).unwrap_or_default()
+            );
+            return Ok(HttpResponse::Ok().finish());
+        }
+    };
+
+    expire_pending_tokens(&mut transaction, pending.id)
+        .await
+        .map_err(ResendConfirmationError::StoreTokenError)?;
+    let token = generate_subscription_token();
+    store_token(
+        &mut transaction,
+        pending.id,
+        &hash_subscription_token(&token, &secret.0),
+        token_ttl.0,
+    )
+    .await
+    .map_err(ResendConfirmationError::StoreTokenError)?;
+    let unsubscribe_token = issue_unsubscribe_token(&mut transaction, pending.id, &secret.0)
+        .await
+        .map_err(ResendConfirmationError::StoreTokenError)?;
+    send_confirmation_email(
+        &mut transaction,
+        pending.id,
+        &subscriber,
+        &templates,
+        &pending.locale,
+        &base_url.0,
+        &token,
+        &unsubscribe_token,
+        &preferences_link(&base_url.0, pending.id, preferences_link_ttl.0, &secret.0),
+    )
+    .await
+    .map_err(ResendConfirmationError::SendEmailError)?;
+
+    transaction
+        .commit()
+        .await
+        .map_err(ResendConfirmationError::TransactionCommitError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[tracing::instrument(
+    name = "Looking up a subscriber pending confirmation by email",
+    skip(transaction, email)
+)]
+async fn get_pending_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<PendingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        PendingSubscriber,
+        r#"
+        SELECT id, email, name, locale
+        FROM subscriptions
+        WHERE email_canonical = $1 AND status = 'pending_confirmation'
+        FOR UPDATE
+        "#,
+        email.canonical(),
+    )
+    .fetch_optional(transaction)
+    .await
+}
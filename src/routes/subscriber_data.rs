@@ -0,0 +1,125 @@
+use crate::domain::EmailNormalization;
+use crate::preferences_token::{verify_preferences_token, PreferencesTokenError};
+use crate::problem_details::ProblemDetails;
+use crate::startup::{HmacSecret, TombstoneSecret};
+use crate::subscriber_data::{erase_subscriber, export_subscriber_data, SubscriberDataExport};
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Form, Query};
+use actix_web::{HttpResponse, ResponseError};
+use chrono::Utc;
+use sqlx::PgPool;
+
+use super::error_chain_fmt;
+
+#[derive(serde::Deserialize)]
+pub struct DataRequestParameters {
+    token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum DataRequestError {
+    #[error("{0}")]
+    InvalidToken(PreferencesTokenError),
+    #[error("No subscriber is associated with the preferences link")]
+    UnknownSubscriber,
+    #[error("Failed to export your data")]
+    ExportError(#[source] sqlx::Error),
+    #[error("Failed to erase your data")]
+    EraseError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for DataRequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for DataRequestError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DataRequestError::InvalidToken(_) | DataRequestError::UnknownSubscriber => {
+                StatusCode::UNAUTHORIZED
+            }
+            DataRequestError::ExportError(_) | DataRequestError::EraseError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ProblemDetails::new(self.status_code(), self.to_string()).into_response()
+    }
+}
+
+/// Lets a subscriber download their data from the preferences page.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Export the data of a subscriber on their request",
+    skip(param, pool, secret),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn export_own_data(
+    param: Query<DataRequestParameters>,
+    pool: Data<PgPool>,
+    secret: Data<HmacSecret>,
+) -> Result<HttpResponse, DataRequestError> {
+    let subscriber_id = verify_preferences_token(&param.token, &secret.0, Utc::now())
+        .map_err(DataRequestError::InvalidToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    let export = export_subscriber_data(&pool, subscriber_id)
+        .await
+        .map_err(DataRequestError::ExportError)?
+        .ok_or(DataRequestError::UnknownSubscriber)?;
+    Ok(subscriber_data_response(&export))
+}
+
+/// Lets a subscriber erase their data from the preferences page.
+/// The link stops working along with the subscription.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Erase the data of a subscriber on their request",
+    skip(form, pool, secret, normalization, tombstone_secret),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn erase_own_data(
+    form: Form<DataRequestParameters>,
+    pool: Data<PgPool>,
+    secret: Data<HmacSecret>,
+    normalization: Data<EmailNormalization>,
+    tombstone_secret: Data<TombstoneSecret>,
+) -> Result<HttpResponse, DataRequestError> {
+    let subscriber_id = verify_preferences_token(&form.token, &secret.0, Utc::now())
+        .map_err(DataRequestError::InvalidToken)?;
+    tracing::Span::current().record("subscriber_id", tracing::field::display(&subscriber_id));
+
+    let erased = erase_subscriber(&pool, subscriber_id, **normalization, &tombstone_secret.0)
+        .await
+        .map_err(DataRequestError::EraseError)?;
+    if !erased {
+        return Err(DataRequestError::UnknownSubscriber);
+    }
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your data has been erased</title>
+</head>
+<body>
+    <p>Your subscription and everything we held about you have been erased.</p>
+</body>
+</html>"#,
+    ))
+}
+
+/// The export as a JSON attachment.
+pub(crate) fn subscriber_data_response(export: &SubscriberDataExport) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscriber-data.json".into())],
+        })
+        .json(export)
+}
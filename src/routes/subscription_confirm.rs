@@ -1,80 +1,213 @@
+use crate::problem_details::{field_errors, ProblemDetails};
+use crate::startup::HmacSecret;
+use actix_web::http::StatusCode;
 use actix_web::web::{Data, Query};
-use actix_web::HttpResponse;
+use actix_web::{HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
-use sqlx::PgPool;
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
 use uuid::Uuid;
-use validator::{Validate, ValidationError};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use super::error_chain_fmt;
 
 pub const SUBSCRIPTION_TOKEN_LENGTH: usize = 25;
 
 #[derive(Debug, Validate, Deserialize)]
 pub struct Parameters {
     #[validate(
-        length(equal = "SUBSCRIPTION_TOKEN_LENGTH"),
+        length(
+            equal = "SUBSCRIPTION_TOKEN_LENGTH",
+            message = "Subscription token has the wrong length"
+        ),
         custom = "validate_subscription_token"
     )]
     subscription_token: String,
 }
 
-#[allow(clippy::async_yields_async)]
-#[tracing::instrument(name = "confirm pending subscriber", skip(param, pool))]
-pub async fn confirm(pool: Data<PgPool>, param: Query<Parameters>) -> HttpResponse {
-    if param.validate().is_err() {
-        return HttpResponse::BadRequest().finish();
+struct StoredToken {
+    subscription_token: String,
+    subscriber_id: Uuid,
+    expires_at: DateTime<Utc>,
+    consumed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("The subscription token is malformed")]
+    InvalidToken(ValidationErrors),
+    #[error("No subscriber is associated with the subscription token")]
+    UnknownToken,
+    #[error("The subscription token has already been used")]
+    TokenAlreadyUsed,
+    #[error("The subscription token has expired")]
+    ExpiredToken,
+    #[error("The subscriber is no longer pending confirmation")]
+    NotPending,
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscription token")]
+    GetTokenError(#[source] sqlx::Error),
+    #[error("Failed to mark the subscription token as consumed")]
+    ConsumeTokenError(#[source] sqlx::Error),
+    #[error("Failed to mark the subscriber as confirmed")]
+    ConfirmSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to confirm a subscriber")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
     }
+}
 
-    let id = match get_subscriber_id_from_token(&pool, &param.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            };
-            HttpResponse::Ok().finish()
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            ConfirmError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::TokenAlreadyUsed | ConfirmError::NotPending => StatusCode::CONFLICT,
+            ConfirmError::ExpiredToken => StatusCode::GONE,
+            ConfirmError::PoolError(_)
+            | ConfirmError::GetTokenError(_)
+            | ConfirmError::ConsumeTokenError(_)
+            | ConfirmError::ConfirmSubscriberError(_)
+            | ConfirmError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            ConfirmError::InvalidToken(errors) => problem.with_errors(field_errors(errors)),
+            _ => problem,
+        }
+        .into_response()
+    }
 }
+
 #[allow(clippy::async_yields_async)]
-#[tracing::instrument(name = "Mark subscriber as confirmed", skip(subscriber_id, pool))]
-async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
+#[tracing::instrument(name = "confirm pending subscriber", skip(param, pool, secret))]
+pub async fn confirm(
+    pool: Data<PgPool>,
+    param: Query<Parameters>,
+    secret: Data<HmacSecret>,
+) -> Result<HttpResponse, ConfirmError> {
+    param.validate().map_err(ConfirmError::InvalidToken)?;
+
+    let mut transaction = pool.begin().await.map_err(ConfirmError::PoolError)?;
+
+    let token =
+        get_subscriber_id_from_token(&mut transaction, &param.subscription_token, &secret.0)
+            .await
+            .map_err(ConfirmError::GetTokenError)?;
+    let token = match token {
+        None => return Err(ConfirmError::UnknownToken),
+        Some(token) if token.consumed_at.is_some() => return Err(ConfirmError::TokenAlreadyUsed),
+        Some(token) if token.expires_at <= Utc::now() => return Err(ConfirmError::ExpiredToken),
+        Some(token) => token,
+    };
+
+    consume_token(&mut transaction, &token.subscription_token)
+        .await
+        .map_err(ConfirmError::ConsumeTokenError)?;
+    let confirmed = confirm_subscriber(&mut transaction, token.subscriber_id)
+        .await
+        .map_err(ConfirmError::ConfirmSubscriberError)?;
+    // An unsubscribed subscriber stays so: the token is left unused with the rollback.
+    if !confirmed {
+        return Err(ConfirmError::NotPending);
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(ConfirmError::TransactionCommitError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Returns `false` when the subscriber is not pending confirmation anymore.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(
+    name = "Mark subscriber as confirmed",
+    skip(subscriber_id, transaction)
+)]
+async fn confirm_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let confirmed = sqlx::query!(
+        r#"
+        UPDATE subscriptions SET status = 'confirmed'
+        WHERE id = $1 AND status = 'pending_confirmation'
+        "#,
         subscriber_id,
     )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+    .execute(transaction)
+    .await?
+    .rows_affected();
+    Ok(confirmed == 1)
+}
+
+#[tracing::instrument(
+    name = "Mark subscription token as consumed",
+    skip(subscription_token, transaction)
+)]
+async fn consume_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscription_token: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscription_tokens SET consumed_at = now() WHERE subscription_token = $1"#,
+        subscription_token,
+    )
+    .execute(transaction)
+    .await?;
     Ok(())
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_token, pool))]
+/// Locks the token row until the transaction ends, so that it cannot be consumed twice.
+/// Tokens issued before hashing was introduced are still matched in plaintext until they expire.
+#[tracing::instrument(
+    name = "Get subscriber_id from token",
+    skip(subscription_token, transaction, secret)
+)]
 async fn get_subscriber_id_from_token(
-    pool: &PgPool,
+    transaction: &mut Transaction<'_, Postgres>,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1"#,
+    secret: &Secret<String>,
+) -> Result<Option<StoredToken>, sqlx::Error> {
+    let result = sqlx::query_as!(
+        StoredToken,
+        r#"
+        SELECT subscription_token, subscriber_id, expires_at, consumed_at
+        FROM subscription_tokens
+        WHERE (subscription_token = $1 AND is_hashed)
+            OR (subscription_token = $2 AND NOT is_hashed)
+        FOR UPDATE
+        "#,
+        hash_subscription_token(subscription_token, secret),
         subscription_token,
     )
-    .fetch_optional(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(result.map(|r| r.subscriber_id))
+    .fetch_optional(transaction)
+    .await?;
+    Ok(result)
+}
+
+/// HMAC-SHA256 of the token, hex encoded: what actually gets stored in the database.
+pub(crate) fn hash_subscription_token(token: &str, secret: &Secret<String>) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
 }
 
 #[tracing::instrument(name = "Validate subscription token", skip(token))]
-fn validate_subscription_token(token: &str) -> Result<(), ValidationError> {
+pub(crate) fn validate_subscription_token(token: &str) -> Result<(), ValidationError> {
     if token.chars().any(|c| !c.is_ascii_alphanumeric()) {
-        tracing::error!("Invalid subscription token received: {:?}", token);
         return Err(ValidationError::new(
             "Subscription token contains non ascii alphanumeric char",
         ));
@@ -88,7 +221,8 @@ mod tests {
     use fake::{Fake, StringFaker};
     use validator::Validate;
 
-    use super::Parameters;
+    use super::{hash_subscription_token, Parameters};
+    use secrecy::Secret;
 
     fn valid_string(len: usize) -> String {
         const ASCII_ALPHANNUMERIC: &str =
@@ -101,6 +235,19 @@ mod tests {
         StringFaker::with(Vec::from(NOT_ASCII_ALPHANNUMERIC), len).fake()
     }
 
+    #[test]
+    fn hashing_a_token_is_deterministic_and_depends_on_the_secret() {
+        let token = valid_string(super::SUBSCRIPTION_TOKEN_LENGTH);
+        let secret = Secret::new("a-secret".to_string());
+        let other_secret = Secret::new("another-secret".to_string());
+
+        let hash = hash_subscription_token(&token, &secret);
+
+        assert_ne!(hash, token);
+        assert_eq!(hash, hash_subscription_token(&token, &secret));
+        assert_ne!(hash, hash_subscription_token(&token, &other_secret));
+    }
+
     #[test]
     fn subcription_token_with_valid_length_and_charachters() {
         assert_ok!(Parameters {
@@ -0,0 +1,40 @@
+use crate::spam_protection::{issue_form_token, HONEYPOT_FIELD};
+use crate::startup::HmacSecret;
+use actix_web::http::header::ContentType;
+use actix_web::web::Data;
+use actix_web::HttpResponse;
+use chrono::Utc;
+
+/// The subscription form, stamped with the time it was served at.
+pub async fn subscription_form(secret: Data<HmacSecret>) -> HttpResponse {
+    let form_token = issue_form_token(Utc::now(), &secret.0);
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscribe</title>
+</head>
+<body>
+    <form action="/subscriptions" method="post">
+        <label>Name
+            <input type="text" placeholder="Enter your name" name="name">
+        </label>
+        <label>Email
+            <input type="email" placeholder="Enter your email address" name="email">
+        </label>
+        <div style="position: absolute; left: -10000px;" aria-hidden="true">
+            <label>Leave this field empty
+                <input type="text" name="{}" tabindex="-1" autocomplete="off">
+            </label>
+        </div>
+        <input type="hidden" name="form_token" value="{}">
+        <button type="submit">Subscribe</button>
+    </form>
+</body>
+</html>"#,
+            HONEYPOT_FIELD, form_token
+        ))
+}
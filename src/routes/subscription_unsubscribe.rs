@@ -0,0 +1,202 @@
+use crate::email_client::EmailHeader;
+use crate::problem_details::{field_errors, ProblemDetails};
+use crate::startup::HmacSecret;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{Data, Query};
+use actix_web::{HttpResponse, ResponseError};
+use secrecy::Secret;
+use serde::Deserialize;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+use validator::{Validate, ValidationErrors};
+
+use super::{
+    error_chain_fmt, generate_subscription_token, hash_subscription_token,
+    validate_subscription_token, SUBSCRIPTION_TOKEN_LENGTH,
+};
+
+#[derive(Debug, Validate, Deserialize)]
+pub struct UnsubscribeParameters {
+    #[validate(
+        length(
+            equal = "SUBSCRIPTION_TOKEN_LENGTH",
+            message = "Unsubscribe token has the wrong length"
+        ),
+        custom = "validate_subscription_token"
+    )]
+    unsubscribe_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe token is malformed")]
+    InvalidToken(ValidationErrors),
+    #[error("No subscriber is associated with the unsubscribe token")]
+    UnknownToken,
+    #[error("Failed to look up or unsubscribe the subscriber")]
+    DatabaseError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidToken(_) => StatusCode::BAD_REQUEST,
+            UnsubscribeError::UnknownToken => StatusCode::UNAUTHORIZED,
+            UnsubscribeError::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            UnsubscribeError::InvalidToken(errors) => problem.with_errors(field_errors(errors)),
+            _ => problem,
+        }
+        .into_response()
+    }
+}
+
+/// The page the link in the email leads to. Following a link must not change
+/// anything, as mail scanners follow them too: the button on the page does.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "Show the unsubscribe page", skip(param, pool, secret))]
+pub async fn unsubscribe_page(
+    pool: Data<PgPool>,
+    param: Query<UnsubscribeParameters>,
+    secret: Data<HmacSecret>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    param.validate().map_err(UnsubscribeError::InvalidToken)?;
+
+    get_subscriber_id_from_token(&**pool, &param.unsubscribe_token, &secret.0)
+        .await
+        .map_err(UnsubscribeError::DatabaseError)?
+        .ok_or(UnsubscribeError::UnknownToken)?;
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <form action="/subscriptions/unsubscribe?unsubscribe_token={}" method="post">
+        <p>Stop receiving the newsletter?</p>
+        <button type="submit">Unsubscribe</button>
+    </form>
+</body>
+</html>"#,
+            param.unsubscribe_token
+        )))
+}
+
+/// Handles both the button of the unsubscribe page and the RFC 8058 one-click
+/// `POST` sent by mail clients through the `List-Unsubscribe` header.
+#[allow(clippy::async_yields_async)]
+#[tracing::instrument(name = "Unsubscribe a subscriber", skip(param, pool, secret))]
+pub async fn unsubscribe(
+    pool: Data<PgPool>,
+    param: Query<UnsubscribeParameters>,
+    secret: Data<HmacSecret>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    param.validate().map_err(UnsubscribeError::InvalidToken)?;
+
+    let subscriber_id = get_subscriber_id_from_token(&**pool, &param.unsubscribe_token, &secret.0)
+        .await
+        .map_err(UnsubscribeError::DatabaseError)?
+        .ok_or(UnsubscribeError::UnknownToken)?;
+    mark_subscriber_as_unsubscribed(&pool, subscriber_id)
+        .await
+        .map_err(UnsubscribeError::DatabaseError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Tokens carried over from before hashing are still matched in plaintext.
+#[tracing::instrument(
+    name = "Get subscriber_id from unsubscribe token",
+    skip(executor, unsubscribe_token, secret)
+)]
+async fn get_subscriber_id_from_token(
+    executor: impl PgExecutor<'_>,
+    unsubscribe_token: &str,
+    secret: &Secret<String>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT subscriber_id
+        FROM unsubscribe_tokens
+        WHERE (unsubscribe_token = $1 AND is_hashed)
+            OR (unsubscribe_token = $2 AND NOT is_hashed)
+        "#,
+        hash_subscription_token(unsubscribe_token, secret),
+        unsubscribe_token,
+    )
+    .fetch_optional(executor)
+    .await?;
+    Ok(result.map(|r| r.subscriber_id))
+}
+
+#[tracing::instrument(name = "Mark subscriber as unsubscribed", skip(pool))]
+async fn mark_subscriber_as_unsubscribed(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'unsubscribed' WHERE id = $1"#,
+        subscriber_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// A new token for the unsubscribe link of an email about to be sent to the
+/// subscriber. Only its HMAC-SHA256 hash is stored, so every email gets its own.
+#[tracing::instrument(name = "Issue an unsubscribe token", skip(transaction, secret))]
+pub(crate) async fn issue_unsubscribe_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    secret: &Secret<String>,
+) -> Result<String, sqlx::Error> {
+    let unsubscribe_token = generate_subscription_token();
+    sqlx::query!(
+        r#"
+        INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id, is_hashed)
+        VALUES ($1, $2, true)
+        "#,
+        hash_subscription_token(&unsubscribe_token, secret),
+        subscriber_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(unsubscribe_token)
+}
+
+pub fn unsubscribe_link(base_url: &str, unsubscribe_token: &str) -> String {
+    format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        base_url, unsubscribe_token
+    )
+}
+
+/// The RFC 8058 headers letting mail clients offer a one-click unsubscribe button.
+pub fn list_unsubscribe_headers(base_url: &str, unsubscribe_token: &str) -> Vec<EmailHeader> {
+    vec![
+        EmailHeader {
+            name: "List-Unsubscribe".into(),
+            value: format!("<{}>", unsubscribe_link(base_url, unsubscribe_token)),
+        },
+        EmailHeader {
+            name: "List-Unsubscribe-Post".into(),
+            value: "List-Unsubscribe=One-Click".into(),
+        },
+    ]
+}
@@ -1,171 +1,556 @@
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use crate::email_client::EmailClient;
-use crate::startup::ApplicationBaseUrl;
+use crate::domain::{
+    candidate_domains, DomainRule, EmailDomainPolicy, EmailNormalization, NewSubscriber,
+    OperatorDomainRules, SubscriberEmail, SubscriberName,
+};
+use crate::email_outbox::enqueue_email;
+use crate::email_templates::EmailTemplates;
+use crate::preferences_token::preferences_link;
+use crate::problem_details::{FieldError, ProblemDetails};
+use crate::routes::{issue_unsubscribe_token, list_unsubscribe_headers};
+use crate::spam_protection::{SpamProtection, SpamVerdict};
+use crate::startup::{ApplicationBaseUrl, HmacSecret, PreferencesLinkTtl, SubscriptionTokenTtl};
+use actix_web::http::header::{AcceptLanguage, Header};
+use actix_web::http::StatusCode;
 use actix_web::web::{Data, Form};
-use actix_web::HttpResponse;
+use actix_web::{HttpRequest, HttpResponse, ResponseError};
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use sqlx::{PgPool, Postgres, Transaction};
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
-use super::SUBSCRIPTION_TOKEN_LENGTH;
+use super::{hash_subscription_token, SUBSCRIPTION_TOKEN_LENGTH};
 
 #[derive(serde::Deserialize)]
 pub struct FormData {
     pub name: String,
     pub email: String,
+    /// Honeypot: hidden from humans by the form, so only bots fill it in.
+    #[serde(default)]
+    pub website: Option<String>,
+    /// When the form was served, signed by `spam_protection::issue_form_token`.
+    #[serde(default)]
+    pub form_token: Option<String>,
+    /// The language to send emails in, taking precedence over `Accept-Language`.
+    #[serde(default)]
+    pub locale: Option<String>,
 }
 
-impl TryFrom<FormData> for NewSubscriber {
-    type Error = String;
+struct ExistingSubscriber {
+    id: Uuid,
+    status: String,
+}
+
+impl NewSubscriber {
+    /// Both fields are always checked, so that every problem is reported at once.
+    /// The email domain has to pass `policy` on top of being well formed.
+    pub fn parse_form(
+        value: FormData,
+        normalization: EmailNormalization,
+        policy: &EmailDomainPolicy,
+        operator_rules: &OperatorDomainRules,
+    ) -> Result<Self, Vec<FieldError>> {
+        let name = SubscriberName::parse(value.name);
+        let email = SubscriberEmail::parse_with(value.email, normalization)
+            .map_err(|e| FieldError::new("email", e).with_code("invalid"))
+            .and_then(|email| match policy.check(&email, operator_rules) {
+                Ok(()) => Ok(email),
+                Err(rejection) => {
+                    Err(FieldError::new("email", rejection.to_string()).with_code(rejection.code()))
+                }
+            });
+
+        match (name, email) {
+            (Ok(name), Ok(email)) => Ok(Self { name, email }),
+            (name, email) => {
+                let mut errors = Vec::new();
+                if let Err(e) = name {
+                    errors.push(FieldError::new("name", e));
+                }
+                if let Err(e) = email {
+                    errors.push(e);
+                }
+                Err(errors)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("The subscription form contains invalid fields")]
+    ValidationError(Vec<FieldError>),
+    #[error("Failed to acquire a Postgres connection from the pool")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to record the use of the subscription form token")]
+    FormTokenError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the email domain rules")]
+    DomainRulesError(#[source] sqlx::Error),
+    #[error("Failed to store the subscriber in the database")]
+    StoreSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to store the confirmation token for a new subscriber")]
+    StoreTokenError(#[source] sqlx::Error),
+    #[error("Failed to render the email")]
+    TemplateError(#[source] tera::Error),
+    #[error("Failed to queue the email for delivery")]
+    SendEmailError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to store a new subscriber")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::PoolError(_)
+            | SubscribeError::FormTokenError(_)
+            | SubscribeError::DomainRulesError(_)
+            | SubscribeError::StoreSubscriberError(_)
+            | SubscribeError::StoreTokenError(_)
+            | SubscribeError::TemplateError(_)
+            | SubscribeError::SendEmailError(_)
+            | SubscribeError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 
-    fn try_from(value: FormData) -> Result<Self, Self::Error> {
-        let name = SubscriberName::parse(value.name)?;
-        let email = SubscriberEmail::parse(value.email)?;
+    fn error_response(&self) -> HttpResponse {
+        let problem = ProblemDetails::new(self.status_code(), self.to_string());
+        match self {
+            SubscribeError::ValidationError(errors) => problem.with_errors(errors.clone()),
+            _ => problem,
+        }
+        .into_response()
+    }
+}
 
-        Ok(Self { name, email })
+/// Render an error followed by every error in its `source` chain.
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
     }
+    Ok(())
 }
 
-#[allow(clippy::async_yields_async)]
+#[allow(clippy::async_yields_async, clippy::too_many_arguments)]
 #[tracing::instrument(
     name="Adding a new subsciber",
-    skip(form,pool,email_client,base_url),
+    skip(req,form,pool,base_url,token_ttl,secret,spam_protection,normalization,domain_policy,templates,preferences_link_ttl),
     fields(
         subscriber_email=%form.email,
         subscriber_name=%form.name
     )
 )]
 pub async fn subscribe(
+    req: HttpRequest,
     form: Form<FormData>,
     pool: Data<PgPool>,
-    email_client: Data<EmailClient>,
     base_url: Data<ApplicationBaseUrl>,
-) -> HttpResponse {
-    let subscriber = match NewSubscriber::try_from(form.0) {
-        Ok(x) => x,
-        Err(e) => {
-            tracing::error!("Failed to parse formdata: {:?}", e);
-            return HttpResponse::BadRequest().finish();
+    token_ttl: Data<SubscriptionTokenTtl>,
+    secret: Data<HmacSecret>,
+    spam_protection: Data<SpamProtection>,
+    normalization: Data<EmailNormalization>,
+    domain_policy: Data<EmailDomainPolicy>,
+    templates: Data<EmailTemplates>,
+    preferences_link_ttl: Data<PreferencesLinkTtl>,
+) -> Result<HttpResponse, SubscribeError> {
+    // Bots are told that all went well, so that they have nothing to adapt to.
+    if let Some(verdict) = spam_protection.check(
+        form.website.as_deref(),
+        form.form_token.as_deref(),
+        &secret.0,
+        Utc::now(),
+    ) {
+        spam_protection.record(verdict);
+        return Ok(HttpResponse::Ok().finish());
+    }
+    if let Some(form_token) = form.form_token.as_deref() {
+        let claimed = spam_protection
+            .claim_form_token(&**pool, form_token, Utc::now())
+            .await
+            .map_err(SubscribeError::FormTokenError)?;
+        if !claimed {
+            spam_protection.record(SpamVerdict::ReusedFormToken);
+            return Ok(HttpResponse::Ok().finish());
         }
-    };
+    }
 
-    let mut transaction = match pool.begin().await {
-        Ok(transaction) => transaction,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
+    let accept_language = AcceptLanguage::parse(&req)
+        .map(|header| header.ranked())
+        .unwrap_or_default();
+    let locale = templates
+        .negotiate_locale(
+            form.locale.as_deref().into_iter().chain(
+                accept_language
+                    .iter()
+                    .filter_map(|preference| preference.item())
+                    .map(|tag| tag.as_str()),
+            ),
+        )
+        .to_owned();
+
+    let domains = SubscriberEmail::parse_with(form.email.clone(), **normalization)
+        .map(|email| candidate_domains(email.domain()))
+        .unwrap_or_default();
+    let operator_rules = get_email_domain_rules(&**pool, Some(&domains))
+        .await
+        .map_err(SubscribeError::DomainRulesError)?;
+    let subscriber =
+        NewSubscriber::parse_form(form.0, **normalization, &domain_policy, &operator_rules)
+            .map_err(SubscribeError::ValidationError)?;
+
+    let mut transaction = pool.begin().await.map_err(SubscribeError::PoolError)?;
 
-    let subscriber_id = match insert_subscriber(&subscriber, &mut transaction).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
+    // Whatever the state of an already known address, the response is the same as
+    // for a brand new one, so that the endpoint does not reveal who is subscribed.
+    let subscriber_id = loop {
+        if let Some(subscriber_id) = insert_subscriber(&subscriber, &locale, &mut transaction)
+            .await
+            .map_err(SubscribeError::StoreSubscriberError)?
+        {
+            break subscriber_id;
+        }
+        let existing_subscriber = get_existing_subscriber(&mut transaction, &subscriber.email)
+            .await
+            .map_err(SubscribeError::StoreSubscriberError)?;
+        match existing_subscriber {
+            // Erased since the insert ran into it: try again.
+            None => continue,
+            Some(existing) if existing.status == "confirmed" => {
+                let unsubscribe_token =
+                    issue_unsubscribe_token(&mut transaction, existing.id, &secret.0)
+                        .await
+                        .map_err(SubscribeError::StoreTokenError)?;
+                // Answered in the language asked for, without changing the one on record:
+                // whoever filled in the form may not be the subscriber.
+                send_already_subscribed_email(
+                    &mut transaction,
+                    existing.id,
+                    &subscriber,
+                    &templates,
+                    &locale,
+                    &base_url.0,
+                    &unsubscribe_token,
+                    &preferences_link(&base_url.0, existing.id, preferences_link_ttl.0, &secret.0),
+                )
+                .await?;
+                transaction
+                    .commit()
+                    .await
+                    .map_err(SubscribeError::TransactionCommitError)?;
+                return Ok(HttpResponse::Ok().finish());
+            }
+            Some(existing) => {
+                mark_subscriber_as_pending(&mut transaction, existing.id, &locale)
+                    .await
+                    .map_err(SubscribeError::StoreSubscriberError)?;
+                expire_pending_tokens(&mut transaction, existing.id)
+                    .await
+                    .map_err(SubscribeError::StoreTokenError)?;
+                break existing.id;
+            }
+        }
     };
 
     let token = generate_subscription_token();
-    if store_token(&mut transaction, subscriber_id, &token)
+    store_token(
+        &mut transaction,
+        subscriber_id,
+        &hash_subscription_token(&token, &secret.0),
+        token_ttl.0,
+    )
+    .await
+    .map_err(SubscribeError::StoreTokenError)?;
+    let unsubscribe_token = issue_unsubscribe_token(&mut transaction, subscriber_id, &secret.0)
         .await
-        .is_err()
-    {
-        return HttpResponse::InternalServerError().finish();
-    }
+        .map_err(SubscribeError::StoreTokenError)?;
 
-    if transaction.commit().await.is_err() {
-        return HttpResponse::InternalServerError().finish();
-    };
+    send_confirmation_email(
+        &mut transaction,
+        subscriber_id,
+        &subscriber,
+        &templates,
+        &locale,
+        &base_url.0,
+        &token,
+        &unsubscribe_token,
+        &preferences_link(
+            &base_url.0,
+            subscriber_id,
+            preferences_link_ttl.0,
+            &secret.0,
+        ),
+    )
+    .await?;
 
-    if send_confirmation_email(&email_client, subscriber, &base_url.0, &token)
+    transaction
+        .commit()
         .await
-        .is_err()
-    {
-        tracing::error!("Failed to send confirmation mail");
-        return HttpResponse::InternalServerError().finish();
-    };
-    HttpResponse::Ok().finish()
+        .map_err(SubscribeError::TransactionCommitError)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// The operator rules on `domains`, or all of them when `domains` is `None`.
+#[tracing::instrument(name = "Get email domain rules", skip(executor))]
+pub(crate) async fn get_email_domain_rules(
+    executor: impl PgExecutor<'_>,
+    domains: Option<&[String]>,
+) -> Result<OperatorDomainRules, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT domain, rule
+        FROM email_domain_rules
+        WHERE $1::text[] IS NULL OR domain = ANY($1)
+        "#,
+        domains,
+    )
+    .fetch_all(executor)
+    .await?;
+    let mut rules = OperatorDomainRules::default();
+    for row in rows {
+        match DomainRule::try_from(row.rule) {
+            Ok(rule) => {
+                rules.0.insert(row.domain, rule);
+            }
+            Err(e) => tracing::warn!(domain = %row.domain, "Ignoring an email domain rule: {}", e),
+        }
+    }
+    Ok(rules)
 }
 
+#[tracing::instrument(
+    name = "Looking up an existing subscriber by email",
+    skip(transaction, email)
+)]
+async fn get_existing_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"
+        SELECT id, status
+        FROM subscriptions
+        WHERE email_canonical = $1
+        FOR UPDATE
+        "#,
+        email.canonical(),
+    )
+    .fetch_optional(transaction)
+    .await
+}
+
+/// Subscribing again also records the language of the new signup.
+#[tracing::instrument(
+    name = "Mark subscriber as pending confirmation",
+    skip(transaction, subscriber_id)
+)]
+async fn mark_subscriber_as_pending(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    locale: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions
+        SET status = 'pending_confirmation', locale = $2
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        locale,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Returns `None` if the address is already taken, even by a signup that is still
+/// in flight: the insert waits for it to commit instead of failing on it.
 #[tracing::instrument(
     name = "Saving new subscriber details in the database",
     skip(subscriber, transaction)
 )]
 pub async fn insert_subscriber(
     subscriber: &NewSubscriber,
+    locale: &str,
     transaction: &mut Transaction<'_, Postgres>,
-) -> Result<Uuid, sqlx::Error> {
+) -> Result<Option<Uuid>, sqlx::Error> {
     let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
+    let inserted = sqlx::query!(
         r#"
-        INSERT INTO subscriptions (id, email, name, subscribed_at,status)
-        VALUES ($1, $2, $3, $4,'pending_confirmation')   
+        INSERT INTO subscriptions
+            (id, email, email_canonical, name, subscribed_at, status, locale)
+        VALUES ($1, $2, $3, $4, $5, 'pending_confirmation', $6)
+        ON CONFLICT (email_canonical) DO NOTHING
         "#,
         subscriber_id,
         subscriber.email.as_ref(),
+        subscriber.email.canonical(),
         subscriber.name.as_ref(),
-        Utc::now()
+        Utc::now(),
+        locale,
     )
     .execute(transaction)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(subscriber_id)
+    .await?;
+    Ok((inserted.rows_affected() == 1).then_some(subscriber_id))
 }
 
+/// Queue the confirmation email in the outbox, as part of the transaction that
+/// stores the subscriber, so that it gets delivered even if the email provider is down.
 #[tracing::instrument(
     name = "Sending a confirmation email to a new subscriber",
-    skip(email_client, subscriber, base_url)
+    skip(
+        transaction,
+        subscriber,
+        templates,
+        base_url,
+        token,
+        unsubscribe_token,
+        preferences_link
+    )
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    subscriber: NewSubscriber,
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    subscriber: &NewSubscriber,
+    templates: &EmailTemplates,
+    locale: &str,
     base_url: &str,
     token: &str,
-) -> Result<(), reqwest::Error> {
+    unsubscribe_token: &str,
+    preferences_link: &str,
+) -> Result<(), SubscribeError> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, token
     );
-    let html_body = format!(
-        "Welcome to our newsletter!<br />\
-            Click <a href=\"{}\">here</a> to confirm your subscription",
-        confirmation_link
-    );
-    let text_body = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
+    let mut context = tera::Context::new();
+    context.insert("name", subscriber.name.as_ref());
+    context.insert("confirmation_link", &confirmation_link);
+    context.insert("preferences_link", preferences_link);
+    let email = templates
+        .render("confirmation", locale, &context)
+        .map_err(SubscribeError::TemplateError)?;
 
-    email_client
-        .send_email(subscriber.email, "Welcome", &html_body, &text_body)
-        .await
+    enqueue_email(
+        transaction,
+        subscriber_id,
+        &subscriber.email,
+        &email.subject,
+        &email.html_body,
+        &email.text_body,
+        &list_unsubscribe_headers(base_url, unsubscribe_token),
+    )
+    .await
+    .map_err(SubscribeError::SendEmailError)?;
+    Ok(())
+}
+
+#[tracing::instrument(
+    name = "Sending an already subscribed notice to a confirmed subscriber",
+    skip(
+        transaction,
+        subscriber,
+        templates,
+        base_url,
+        unsubscribe_token,
+        preferences_link
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+async fn send_already_subscribed_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    subscriber: &NewSubscriber,
+    templates: &EmailTemplates,
+    locale: &str,
+    base_url: &str,
+    unsubscribe_token: &str,
+    preferences_link: &str,
+) -> Result<(), SubscribeError> {
+    let mut context = tera::Context::new();
+    context.insert("name", subscriber.name.as_ref());
+    context.insert("preferences_link", preferences_link);
+    let email = templates
+        .render("already_subscribed", locale, &context)
+        .map_err(SubscribeError::TemplateError)?;
+
+    enqueue_email(
+        transaction,
+        subscriber_id,
+        &subscriber.email,
+        &email.subject,
+        &email.html_body,
+        &email.text_body,
+        &list_unsubscribe_headers(base_url, unsubscribe_token),
+    )
+    .await
+    .map_err(SubscribeError::SendEmailError)?;
+    Ok(())
 }
 
-fn generate_subscription_token() -> String {
+pub(crate) fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
         .take(SUBSCRIPTION_TOKEN_LENGTH)
         .collect()
 }
+
+/// Only the link of the latest confirmation email keeps working.
+#[tracing::instrument(name = "Expire pending subscription tokens", skip(transaction))]
+pub(crate) async fn expire_pending_tokens(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET expires_at = now()
+        WHERE subscriber_id = $1 AND consumed_at IS NULL AND expires_at > now()
+        "#,
+        subscriber_id,
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
 #[tracing::instrument(
     name = "store the subscription token in the database",
-    skip(transaction, subscriber_id, subscription_token)
+    skip(transaction, subscriber_id, subscription_token_hash)
 )]
-async fn store_token(
+pub(crate) async fn store_token(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
-    subscription_token: &str,
+    subscription_token_hash: &str,
+    ttl: chrono::Duration,
 ) -> Result<(), sqlx::Error> {
+    let created_at = Utc::now();
     sqlx::query!(
-        r#"INSERT INTO subscription_tokens (subscription_token, subscriber_id) VALUES ($1, $2)"#,
-        subscription_token,
-        subscriber_id
+        r#"
+        INSERT INTO subscription_tokens
+            (subscription_token, subscriber_id, created_at, expires_at, is_hashed)
+        VALUES ($1, $2, $3, $4, true)
+        "#,
+        subscription_token_hash,
+        subscriber_id,
+        created_at,
+        created_at + ttl
     )
     .execute(transaction)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+    .await?;
     Ok(())
 }
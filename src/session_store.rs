@@ -0,0 +1,120 @@
+use actix_session::storage::{LoadError, SaveError, SessionKey, SessionStore, UpdateError};
+use actix_web::cookie::time::Duration;
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::types::Json;
+use sqlx::PgPool;
+use std::collections::HashMap;
+
+type SessionState = HashMap<String, String>;
+
+/// Keeps the session state in the `sessions` table; the cookie only carries the key.
+#[derive(Clone)]
+pub struct PostgresSessionStore {
+    pool: PgPool,
+}
+
+impl PostgresSessionStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+fn expires_at(ttl: &Duration) -> chrono::DateTime<Utc> {
+    Utc::now() + chrono::Duration::seconds(ttl.whole_seconds())
+}
+
+fn generate_session_key() -> Result<SessionKey, anyhow::Error> {
+    let mut rng = thread_rng();
+    let key: String = std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(64)
+        .collect();
+    Ok(SessionKey::try_from(key)?)
+}
+
+impl SessionStore for PostgresSessionStore {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<SessionState>, LoadError> {
+        let row = sqlx::query!(
+            r#"
+            SELECT state AS "state: Json<SessionState>"
+            FROM sessions
+            WHERE session_key = $1 AND expires_at > now()
+            "#,
+            session_key.as_ref(),
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| LoadError::Other(e.into()))?;
+        Ok(row.map(|r| r.state.0))
+    }
+
+    async fn save(
+        &self,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let session_key = generate_session_key().map_err(SaveError::Other)?;
+        sqlx::query!(
+            r#"INSERT INTO sessions (session_key, state, expires_at) VALUES ($1, $2, $3)"#,
+            session_key.as_ref(),
+            Json(session_state) as _,
+            expires_at(ttl),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| SaveError::Other(e.into()))?;
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: SessionState,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let result = sqlx::query!(
+            r#"UPDATE sessions SET state = $2, expires_at = $3 WHERE session_key = $1"#,
+            session_key.as_ref(),
+            Json(&session_state) as _,
+            expires_at(ttl),
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| UpdateError::Other(e.into()))?;
+        if result.rows_affected() > 0 {
+            return Ok(session_key);
+        }
+        // The session expired or was deleted in the meantime: start a new one.
+        self.save(session_state, ttl).await.map_err(|e| match e {
+            SaveError::Serialization(e) => UpdateError::Serialization(e),
+            SaveError::Other(e) => UpdateError::Other(e),
+        })
+    }
+
+    async fn update_ttl(
+        &self,
+        session_key: &SessionKey,
+        ttl: &Duration,
+    ) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"UPDATE sessions SET expires_at = $2 WHERE session_key = $1"#,
+            session_key.as_ref(),
+            expires_at(ttl),
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE session_key = $1"#,
+            session_key.as_ref(),
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+use crate::configuration::SpamProtectionSettings;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::PgExecutor;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Name of the hidden field of the subscription form that only bots fill in.
+pub const HONEYPOT_FIELD: &str = "website";
+
+const FORM_TOKEN_NONCE_LENGTH: usize = 16;
+
+/// Why a submission of the subscription form was taken for spam.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamVerdict {
+    /// The hidden honeypot field was filled in.
+    Honeypot,
+    /// The form came back sooner than a human could have filled it in.
+    TooFast,
+    /// The form token was not issued by us, was tampered with, or has expired.
+    InvalidFormToken,
+    /// The form came back without a token while API clients are not exempted.
+    MissingFormToken,
+    /// The form token was already used for another submission.
+    ReusedFormToken,
+}
+
+/// Checks subscription forms for the signs of a bot, counting how many were caught.
+pub struct SpamProtection {
+    min_fill_time: chrono::Duration,
+    max_form_age: chrono::Duration,
+    allow_tokenless_submissions: bool,
+    honeypot: AtomicU64,
+    too_fast: AtomicU64,
+    invalid_form_token: AtomicU64,
+    missing_form_token: AtomicU64,
+    reused_form_token: AtomicU64,
+}
+
+impl SpamProtection {
+    pub fn new(settings: &SpamProtectionSettings) -> Self {
+        Self {
+            min_fill_time: chrono::Duration::seconds(settings.min_form_fill_seconds.into()),
+            max_form_age: chrono::Duration::minutes(settings.max_form_age_minutes.into()),
+            allow_tokenless_submissions: settings.allow_tokenless_submissions,
+            honeypot: AtomicU64::new(0),
+            too_fast: AtomicU64::new(0),
+            invalid_form_token: AtomicU64::new(0),
+            missing_form_token: AtomicU64::new(0),
+            reused_form_token: AtomicU64::new(0),
+        }
+    }
+
+    /// Submissions without a form token are only checked against the honeypot
+    /// when API clients are exempted by `allow_tokenless_submissions`.
+    /// A valid token still has to be claimed with `claim_form_token`.
+    pub fn check(
+        &self,
+        honeypot: Option<&str>,
+        form_token: Option<&str>,
+        secret: &Secret<String>,
+        now: DateTime<Utc>,
+    ) -> Option<SpamVerdict> {
+        if honeypot.is_some_and(|v| !v.trim().is_empty()) {
+            return Some(SpamVerdict::Honeypot);
+        }
+        let issued_at = match form_token {
+            Some(token) => match verify_form_token(token, secret) {
+                Some(issued_at) => issued_at,
+                None => return Some(SpamVerdict::InvalidFormToken),
+            },
+            None if self.allow_tokenless_submissions => return None,
+            None => return Some(SpamVerdict::MissingFormToken),
+        };
+        if now - issued_at < self.min_fill_time {
+            return Some(SpamVerdict::TooFast);
+        }
+        if now - issued_at > self.max_form_age {
+            return Some(SpamVerdict::InvalidFormToken);
+        }
+        None
+    }
+
+    /// Records that `form_token` was submitted, returning `false` when it already was.
+    /// Expired tokens are forgotten on the way: `check` turns them down anyway.
+    pub async fn claim_form_token(
+        &self,
+        executor: impl PgExecutor<'_> + Copy,
+        form_token: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query!("DELETE FROM used_form_tokens WHERE expires_at < $1", now)
+            .execute(executor)
+            .await?;
+        let claimed = sqlx::query!(
+            r#"
+            INSERT INTO used_form_tokens (form_token, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (form_token) DO NOTHING
+            "#,
+            form_token,
+            now + self.max_form_age
+        )
+        .execute(executor)
+        .await?
+        .rows_affected();
+        Ok(claimed == 1)
+    }
+
+    /// Counts a caught submission and logs the running totals.
+    pub fn record(&self, verdict: SpamVerdict) {
+        let counter = match verdict {
+            SpamVerdict::Honeypot => &self.honeypot,
+            SpamVerdict::TooFast => &self.too_fast,
+            SpamVerdict::InvalidFormToken => &self.invalid_form_token,
+            SpamVerdict::MissingFormToken => &self.missing_form_token,
+            SpamVerdict::ReusedFormToken => &self.reused_form_token,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(
+            verdict = ?verdict,
+            honeypot_total = self.honeypot.load(Ordering::Relaxed),
+            too_fast_total = self.too_fast.load(Ordering::Relaxed),
+            invalid_form_token_total = self.invalid_form_token.load(Ordering::Relaxed),
+            missing_form_token_total = self.missing_form_token.load(Ordering::Relaxed),
+            reused_form_token_total = self.reused_form_token.load(Ordering::Relaxed),
+            "Dropped a subscription that looks like spam"
+        );
+    }
+}
+
+/// A token recording when the subscription form was served, as
+/// `<unix timestamp>.<nonce>.<hex HMAC-SHA256 of both>`.
+/// The nonce tells apart forms served in the same second, so that each can be used once.
+pub fn issue_form_token(issued_at: DateTime<Utc>, secret: &Secret<String>) -> String {
+    let timestamp = issued_at.timestamp().to_string();
+    let nonce: String = thread_rng()
+        .sample_iter(Alphanumeric)
+        .map(char::from)
+        .take(FORM_TOKEN_NONCE_LENGTH)
+        .collect();
+    let signature = hex::encode(
+        form_token_mac(&timestamp, &nonce, secret)
+            .finalize()
+            .into_bytes(),
+    );
+    format!("{}.{}.{}", timestamp, nonce, signature)
+}
+
+fn verify_form_token(token: &str, secret: &Secret<String>) -> Option<DateTime<Utc>> {
+    let mut parts = token.splitn(3, '.');
+    let (timestamp, nonce, signature) = (parts.next()?, parts.next()?, parts.next()?);
+    let signature = hex::decode(signature).ok()?;
+    form_token_mac(timestamp, nonce, secret)
+        .verify_slice(&signature)
+        .ok()?;
+    DateTime::from_timestamp(timestamp.parse().ok()?, 0)
+}
+
+fn form_token_mac(timestamp: &str, nonce: &str, secret: &Secret<String>) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(b"subscription-form:");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{issue_form_token, SpamProtection, SpamVerdict};
+    use crate::configuration::SpamProtectionSettings;
+    use chrono::{Duration, Utc};
+    use claim::{assert_none, assert_some_eq};
+    use secrecy::Secret;
+
+    fn protection() -> SpamProtection {
+        SpamProtection::new(&SpamProtectionSettings {
+            min_form_fill_seconds: 3,
+            max_form_age_minutes: 60,
+            allow_tokenless_submissions: false,
+        })
+    }
+
+    fn secret() -> Secret<String> {
+        Secret::new("secret".into())
+    }
+
+    #[test]
+    fn a_filled_honeypot_is_spam() {
+        let verdict = protection().check(Some("http://spam.example"), None, &secret(), Utc::now());
+
+        assert_some_eq!(verdict, SpamVerdict::Honeypot);
+    }
+
+    #[test]
+    fn a_blank_honeypot_is_fine() {
+        let token = issue_form_token(Utc::now() - Duration::seconds(10), &secret());
+
+        assert_none!(protection().check(Some(" "), Some(&token), &secret(), Utc::now()));
+    }
+
+    #[test]
+    fn a_missing_token_is_spam() {
+        let verdict = protection().check(None, None, &secret(), Utc::now());
+
+        assert_some_eq!(verdict, SpamVerdict::MissingFormToken);
+    }
+
+    #[test]
+    fn a_missing_token_is_fine_when_api_clients_are_exempted() {
+        let protection = SpamProtection::new(&SpamProtectionSettings {
+            min_form_fill_seconds: 3,
+            max_form_age_minutes: 60,
+            allow_tokenless_submissions: true,
+        });
+
+        assert_none!(protection.check(None, None, &secret(), Utc::now()));
+    }
+
+    #[test]
+    fn a_form_sent_back_too_quickly_is_spam() {
+        let now = Utc::now();
+        let token = issue_form_token(now - Duration::seconds(1), &secret());
+
+        let verdict = protection().check(None, Some(&token), &secret(), now);
+
+        assert_some_eq!(verdict, SpamVerdict::TooFast);
+    }
+
+    #[test]
+    fn a_form_filled_at_human_speed_is_fine() {
+        let now = Utc::now();
+        let token = issue_form_token(now - Duration::seconds(10), &secret());
+
+        assert_none!(protection().check(None, Some(&token), &secret(), now));
+    }
+
+    #[test]
+    fn an_expired_token_is_spam() {
+        let now = Utc::now();
+        let token = issue_form_token(now - Duration::minutes(61), &secret());
+
+        let verdict = protection().check(None, Some(&token), &secret(), now);
+
+        assert_some_eq!(verdict, SpamVerdict::InvalidFormToken);
+    }
+
+    #[test]
+    fn a_backdated_token_is_spam() {
+        let now = Utc::now();
+        let token = issue_form_token(now, &secret());
+        let (_, nonce_and_signature) = token.split_once('.').unwrap();
+        let forged = format!(
+            "{}.{}",
+            (now - Duration::minutes(10)).timestamp(),
+            nonce_and_signature
+        );
+
+        let verdict = protection().check(None, Some(&forged), &secret(), now);
+
+        assert_some_eq!(verdict, SpamVerdict::InvalidFormToken);
+    }
+
+    #[test]
+    fn a_token_signed_with_another_key_is_spam() {
+        let now = Utc::now();
+        let token = issue_form_token(now - Duration::seconds(10), &Secret::new("other".into()));
+
+        let verdict = protection().check(None, Some(&token), &secret(), now);
+
+        assert_some_eq!(verdict, SpamVerdict::InvalidFormToken);
+    }
+}
@@ -1,52 +1,98 @@
-use crate::configuration::{DataBaseSettings, Settings};
+use crate::authentication::reject_anonymous_users;
+use crate::configuration::{DataBaseSettings, DigestSettings, PendingCleanupSettings, Settings};
 use crate::email_client::EmailClient;
+use crate::email_outbox::run_worker_until_stopped;
+use crate::newsletter_digest::run_digests_until_stopped;
+use crate::pending_cleanup::run_cleanup_until_stopped;
+use crate::problem_details::{extractor_error_handler, render_problem_details};
+use crate::rate_limit::{limit_subscription_attempts, SubscriptionRateLimiter};
 use crate::routes;
+use crate::session_store::PostgresSessionStore;
+use crate::spam_protection::SpamProtection;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::Key;
 use actix_web::dev::Server;
+use actix_web::middleware::from_fn;
 use actix_web::{web, App, HttpServer};
+use secrecy::{ExposeSecret, Secret};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 
 pub struct Application {
     port: u16,
     server: Server,
+    connection_pool: PgPool,
+    email_client: Arc<EmailClient>,
+    pending_cleanup: PendingCleanupSettings,
+    digests: DigestSettings,
+    base_url: String,
+    preferences_link_ttl: chrono::Duration,
+    hmac_secret: Secret<String>,
 }
 
 impl Application {
     pub async fn build(configuration: Settings) -> Result<Self, std::io::Error> {
         let connection_pool = get_connection_pool(&configuration.database);
 
-        let sender_email = configuration
-            .email_client
-            .sender()
-            .expect("Invalid sender email address");
-        let timeout = configuration.email_client.timeout();
-        let email_client = EmailClient::new(
-            configuration.email_client.base_url,
-            sender_email,
-            configuration.email_client.authorization_token.clone(),
-            timeout,
-        );
+        let email_client = Arc::new(configuration.email_client.clone().client());
+        let pending_cleanup = configuration.pending_cleanup.clone();
+        let digests = configuration.digests.clone();
+        let base_url = configuration.application.base_url.clone();
+        let preferences_link_ttl = configuration.preferences.link_ttl();
+        let hmac_secret = configuration.application.hmac_secret.clone();
 
         let listener = TcpListener::bind(configuration.application.address())?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(
-            listener,
+        let server = run(listener, connection_pool.clone(), configuration)?;
+
+        Ok(Self {
+            port,
+            server,
             connection_pool,
             email_client,
-            configuration.application.base_url,
-        )?;
-
-        Ok(Self { port, server })
+            pending_cleanup,
+            digests,
+            base_url,
+            preferences_link_ttl,
+            hmac_secret,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// Run the HTTP server alongside the worker draining the email outbox, the one
+    /// sending the newsletter digests and, when enabled, the cleanup of stale
+    /// pending subscriptions.
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
-        self.server.await
+        let cleanup = self.pending_cleanup.enabled.then(|| {
+            tokio::spawn(run_cleanup_until_stopped(
+                self.connection_pool.clone(),
+                self.pending_cleanup,
+            ))
+        });
+        let digests = tokio::spawn(run_digests_until_stopped(
+            self.connection_pool.clone(),
+            self.digests,
+            self.base_url,
+            self.preferences_link_ttl,
+            self.hmac_secret,
+        ));
+        let worker = tokio::spawn(run_worker_until_stopped(
+            self.connection_pool,
+            self.email_client,
+        ));
+        let outcome = self.server.await;
+        worker.abort();
+        digests.abort();
+        if let Some(cleanup) = cleanup {
+            cleanup.abort();
+        }
+        outcome
     }
 }
 
@@ -59,21 +105,120 @@ pub fn get_connection_pool(configuration: &DataBaseSettings) -> PgPool {
 fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    configuration: Settings,
 ) -> Result<Server, std::io::Error> {
+    let application = configuration.application;
+    let session_store = PostgresSessionStore::new(db_pool.clone());
+    let session_key = Key::derive_from(application.hmac_secret.expose_secret().as_bytes());
     let db_pool = web::Data::new(db_pool);
-    let email_client = web::Data::new(email_client);
-    let base_url = web::Data::new(ApplicationBaseUrl(base_url));
+    let subscription_token_ttl =
+        web::Data::new(SubscriptionTokenTtl(application.subscription_token_ttl()));
+    let base_url = web::Data::new(ApplicationBaseUrl(application.base_url));
+    let hmac_secret = web::Data::new(HmacSecret(application.hmac_secret));
+    let tombstone_secret = web::Data::new(TombstoneSecret(application.tombstone_secret));
+    // Built outside the factory so that every worker shares the same buckets and counters.
+    let rate_limiter = web::Data::new(SubscriptionRateLimiter::new(&configuration.rate_limit));
+    let spam_protection = web::Data::new(SpamProtection::new(&configuration.spam_protection));
+    let email_domain_policy = web::Data::new(
+        configuration
+            .email_domain_policy
+            .policy()
+            .map_err(std::io::Error::other)?,
+    );
+    let email_normalization = web::Data::new(configuration.email_normalization.normalization());
+    let preferences_link_ttl =
+        web::Data::new(PreferencesLinkTtl(configuration.preferences.link_ttl()));
+    let preference_topics = web::Data::new(PreferenceTopics(configuration.preferences.topics));
+    // A broken template should stop the deployment, not the first signup.
+    let email_templates = web::Data::new(
+        configuration
+            .email_templates
+            .templates()
+            .map_err(std::io::Error::other)?,
+    );
     let server = HttpServer::new(move || {
         App::new()
+            .wrap(from_fn(render_problem_details))
+            .wrap(SessionMiddleware::new(
+                session_store.clone(),
+                session_key.clone(),
+            ))
             .wrap(TracingLogger::default())
             .route("/health_check", web::get().to(routes::health_check))
-            .route("/subscriptions", web::post().to(routes::subscribe))
+            .service(
+                web::resource("/subscriptions")
+                    .wrap(from_fn(limit_subscription_attempts))
+                    .route(web::get().to(routes::subscription_form))
+                    .route(web::post().to(routes::subscribe)),
+            )
+            .service(
+                web::resource("/subscriptions/resend_confirmation")
+                    .wrap(from_fn(limit_subscription_attempts))
+                    .route(web::post().to(routes::resend_confirmation)),
+            )
             .route("/subscriptions/confirm", web::get().to(routes::confirm))
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(routes::unsubscribe_page),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::post().to(routes::unsubscribe),
+            )
+            .route("/preferences", web::get().to(routes::preferences_page))
+            .route("/preferences", web::post().to(routes::update_preferences))
+            .service(
+                web::resource("/preferences/email")
+                    .wrap(from_fn(limit_subscription_attempts))
+                    .route(web::post().to(routes::request_email_change)),
+            )
+            .route("/preferences/data", web::get().to(routes::export_own_data))
+            .route("/preferences/erase", web::post().to(routes::erase_own_data))
+            .route(
+                "/subscriptions/confirm_email_change",
+                web::get().to(routes::confirm_email_change),
+            )
+            .route("/login", web::get().to(routes::login_form))
+            .route("/login", web::post().to(routes::login))
+            .service(
+                web::scope("/admin")
+                    .wrap(from_fn(reject_anonymous_users))
+                    .route("/dashboard", web::get().to(routes::admin_dashboard))
+                    .route("/newsletters", web::post().to(routes::publish_newsletter))
+                    .route("/subscribers", web::get().to(routes::list_subscribers))
+                    .route(
+                        "/subscribers/export",
+                        web::get().to(routes::export_subscribers),
+                    )
+                    .route(
+                        "/subscribers/import",
+                        web::post().to(routes::import_subscribers_csv),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/data",
+                        web::get().to(routes::admin_export_subscriber_data),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/erase",
+                        web::post().to(routes::admin_erase_subscriber_data),
+                    )
+                    .route("/logout", web::post().to(routes::log_out)),
+            )
+            .app_data(web::FormConfig::default().error_handler(extractor_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(extractor_error_handler))
+            .app_data(web::JsonConfig::default().error_handler(extractor_error_handler))
             .app_data(db_pool.clone())
-            .app_data(email_client.clone())
             .app_data(base_url.clone())
+            .app_data(subscription_token_ttl.clone())
+            .app_data(hmac_secret.clone())
+            .app_data(tombstone_secret.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(spam_protection.clone())
+            .app_data(email_domain_policy.clone())
+            .app_data(email_normalization.clone())
+            .app_data(email_templates.clone())
+            .app_data(preferences_link_ttl.clone())
+            .app_data(preference_topics.clone())
     })
     .listen(listener)?
     .run();
@@ -81,3 +226,15 @@ fn run(
 }
 
 pub struct ApplicationBaseUrl(pub String);
+
+pub struct SubscriptionTokenTtl(pub chrono::Duration);
+
+pub struct HmacSecret(pub Secret<String>);
+
+/// Keys the tombstones of erased addresses, see `erased_email_hash`.
+pub struct TombstoneSecret(pub Secret<String>);
+
+pub struct PreferencesLinkTtl(pub chrono::Duration);
+
+/// The topics subscribers can choose from, as configured.
+pub struct PreferenceTopics(pub Vec<String>);
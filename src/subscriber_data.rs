@@ -0,0 +1,363 @@
+use crate::domain::{EmailNormalization, SubscriberEmail};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+/// Everything we hold about a subscriber, as handed over on an access request.
+///
+/// Token values and hashes are left out: they are credentials, not personal data.
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriberDataExport {
+    pub exported_at: DateTime<Utc>,
+    pub subscription: SubscriptionRecord,
+    pub confirmation_tokens: Vec<ConfirmationTokenRecord>,
+    pub preference_changes: Vec<PreferenceChangeRecord>,
+    pub email_change_requests: Vec<EmailChangeRecord>,
+    pub deliveries: Vec<DeliveryRecord>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriptionRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub status: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub locale: String,
+    /// `None` until the subscriber picks topics, meaning all of them.
+    pub topics: Option<Vec<String>>,
+    pub frequency: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ConfirmationTokenRecord {
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PreferenceChangeRecord {
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub changed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EmailChangeRecord {
+    pub new_email: String,
+    pub requested_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub confirmed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct DeliveryRecord {
+    pub recipient: String,
+    pub subject: String,
+    pub outcome: String,
+    pub n_attempts: i16,
+    pub queued_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// Gathers the data of `subscriber_id`, or `None` if there is no such subscriber.
+///
+/// Emails queued before they were tied to a subscriber are matched on every address
+/// the subscriber used or asked to move to, whatever their case.
+#[tracing::instrument(name = "Export the data of a subscriber", skip(pool))]
+pub async fn export_subscriber_data(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriberDataExport>, sqlx::Error> {
+    // One snapshot, so that the parts of the bundle agree with each other.
+    let mut transaction = pool.begin().await?;
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut transaction)
+        .await?;
+    let subscription = sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+        SELECT id, email, name, status, subscribed_at, locale, topics, frequency
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let subscription = match subscription {
+        Some(subscription) => subscription,
+        None => return Ok(None),
+    };
+    let confirmation_tokens = sqlx::query_as!(
+        ConfirmationTokenRecord,
+        r#"
+        SELECT created_at, expires_at, consumed_at
+        FROM subscription_tokens
+        WHERE subscriber_id = $1
+        ORDER BY created_at
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let preference_changes = sqlx::query_as!(
+        PreferenceChangeRecord,
+        r#"
+        SELECT field, old_value, new_value, changed_at
+        FROM subscriber_preference_changes
+        WHERE subscriber_id = $1
+        ORDER BY changed_at
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let email_change_requests = sqlx::query_as!(
+        EmailChangeRecord,
+        r#"
+        SELECT new_email, requested_at, expires_at, confirmed_at
+        FROM email_change_requests
+        WHERE subscriber_id = $1
+        ORDER BY requested_at
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    let addresses = known_addresses(&mut transaction, subscriber_id).await?;
+    let deliveries = sqlx::query_as!(
+        DeliveryRecord,
+        r#"
+        SELECT recipient, subject, outcome, n_attempts, queued_at, finished_at
+        FROM email_deliveries
+        WHERE subscriber_id = $1 OR (subscriber_id IS NULL AND lower(recipient) = ANY($2))
+        ORDER BY finished_at
+        "#,
+        subscriber_id,
+        &addresses,
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(Some(SubscriberDataExport {
+        exported_at: Utc::now(),
+        subscription,
+        confirmation_tokens,
+        preference_changes,
+        email_change_requests,
+        deliveries,
+    }))
+}
+
+/// Deletes the subscriber and everything that points at them, emails still in the
+/// outbox and the delivery history included, in a single transaction.
+///
+/// Only a tombstone of every address the subscriber has held is kept, so that
+/// imports can skip them. Returns `false` if there is no such subscriber.
+#[tracing::instrument(
+    name = "Erase the data of a subscriber",
+    skip(pool, normalization, tombstone_secret)
+)]
+pub async fn erase_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+    normalization: EmailNormalization,
+    tombstone_secret: &Secret<String>,
+) -> Result<bool, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let subscriber = sqlx::query!(
+        r#"SELECT id FROM subscriptions WHERE id = $1 FOR UPDATE"#,
+        subscriber_id,
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    if subscriber.is_none() {
+        return Ok(false);
+    }
+    let addresses = known_addresses(&mut transaction, subscriber_id).await?;
+    let email_hashes: Vec<String> =
+        held_canonical_addresses(&mut transaction, subscriber_id, normalization)
+            .await?
+            .iter()
+            .map(|email_canonical| erased_email_hash(email_canonical, tombstone_secret))
+            .collect();
+
+    sqlx::query!(
+        r#"
+        DELETE FROM email_outbox
+        WHERE subscriber_id = $1 OR (subscriber_id IS NULL AND lower(recipient) = ANY($2))
+        "#,
+        subscriber_id,
+        &addresses,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        DELETE FROM email_deliveries
+        WHERE subscriber_id = $1 OR (subscriber_id IS NULL AND lower(recipient) = ANY($2))
+        "#,
+        subscriber_id,
+        &addresses,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM archived_pending_subscriptions WHERE lower(email) = ANY($1)"#,
+        &addresses,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM subscription_tokens WHERE subscriber_id = $1"#,
+        subscriber_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM unsubscribe_tokens WHERE subscriber_id = $1"#,
+        subscriber_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM subscriber_preference_changes WHERE subscriber_id = $1"#,
+        subscriber_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM email_change_requests WHERE subscriber_id = $1"#,
+        subscriber_id,
+    )
+    .execute(&mut transaction)
+    .await?;
+    sqlx::query!(r#"DELETE FROM subscriptions WHERE id = $1"#, subscriber_id)
+        .execute(&mut transaction)
+        .await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO erased_subscribers (email_hash, erased_at)
+        SELECT email_hash, now() FROM unnest($1::text[]) AS email_hash
+        ON CONFLICT (email_hash) DO NOTHING
+        "#,
+        &email_hashes,
+    )
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(true)
+}
+
+/// The tombstone of an erased address: HMAC-SHA256 of its canonical form.
+///
+/// Keyed with a secret of its own, so that the address cannot be recovered by
+/// hashing candidates. Rotating that secret forgets every tombstone.
+pub fn erased_email_hash(email_canonical: &str, secret: &Secret<String>) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.expose_secret().as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(b"erased:");
+    mac.update(email_canonical.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// The tombstones among `email_hashes`.
+pub async fn get_erased_email_hashes(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_hashes: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"SELECT email_hash FROM erased_subscribers WHERE email_hash = ANY($1)"#,
+        email_hashes,
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(rows.into_iter().map(|r| r.email_hash).collect())
+}
+
+/// The current address of the subscriber and the ones found in their history,
+/// lowercased.
+async fn known_addresses(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT lower(email) AS "email!" FROM subscriptions WHERE id = $1
+        UNION
+        SELECT lower(new_email) FROM email_change_requests WHERE subscriber_id = $1
+        UNION
+        SELECT lower(old_value) FROM subscriber_preference_changes
+        WHERE subscriber_id = $1 AND field = 'email' AND old_value IS NOT NULL
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(transaction)
+    .await?;
+    Ok(rows.into_iter().map(|r| r.email).collect())
+}
+
+/// The canonical forms of every address the subscriber has held: the current one,
+/// the ones they asked to move to, and the ones they moved away from.
+async fn held_canonical_addresses(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    normalization: EmailNormalization,
+) -> Result<BTreeSet<String>, sqlx::Error> {
+    let canonical = sqlx::query!(
+        r#"
+        SELECT email_canonical AS "email_canonical!" FROM subscriptions WHERE id = $1
+        UNION
+        SELECT new_email_canonical FROM email_change_requests WHERE subscriber_id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    // The history records the addresses moved away from as they were typed.
+    let moved_from = sqlx::query!(
+        r#"
+        SELECT old_value AS "old_value!" FROM subscriber_preference_changes
+        WHERE subscriber_id = $1 AND field = 'email' AND old_value IS NOT NULL
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    Ok(canonical
+        .into_iter()
+        .map(|r| r.email_canonical)
+        .chain(moved_from.into_iter().filter_map(|r| {
+            SubscriberEmail::parse_with(r.old_value, normalization)
+                .ok()
+                .map(|email| email.canonical().to_owned())
+        }))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::erased_email_hash;
+    use secrecy::Secret;
+
+    #[test]
+    fn the_tombstone_depends_on_the_address_and_the_secret() {
+        let secret = Secret::new("secret".to_string());
+        let other_secret = Secret::new("other secret".to_string());
+
+        let hash = erased_email_hash("ursula@example.com", &secret);
+
+        assert_eq!(hash, erased_email_hash("ursula@example.com", &secret));
+        assert_ne!(hash, erased_email_hash("octavia@example.com", &secret));
+        assert_ne!(hash, erased_email_hash("ursula@example.com", &other_secret));
+        assert!(!hash.contains("ursula"));
+    }
+}
@@ -0,0 +1,360 @@
+use crate::domain::{EmailDomainPolicy, EmailNormalization, NewSubscriber, SubscriptionStatus};
+use crate::routes::{get_email_domain_rules, FormData};
+use crate::subscriber_data::{erased_email_hash, get_erased_email_hashes};
+use chrono::Utc;
+use csv_async::{AsyncReaderBuilder, AsyncWriter, Trim};
+use futures_util::StreamExt;
+use secrecy::Secret;
+use sqlx::PgPool;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use tokio::io::AsyncRead;
+use uuid::Uuid;
+
+/// How many valid rows are inserted per transaction.
+pub const IMPORT_BATCH_SIZE: usize = 500;
+
+/// A CSV row that was not imported, and why.
+#[derive(Debug)]
+pub struct RejectedRow {
+    pub line: u64,
+    pub name: String,
+    pub email: String,
+    pub reason: String,
+}
+
+/// A batch whose transaction failed: none of its rows were imported.
+#[derive(Debug)]
+pub struct FailedBatch {
+    pub first_line: u64,
+    pub last_line: u64,
+    pub error: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: u64,
+    pub rejected: Vec<RejectedRow>,
+    /// The rows of these batches are also listed in `rejected`.
+    pub failed_batches: Vec<FailedBatch>,
+}
+
+impl ImportReport {
+    /// The rejected rows as a CSV document, header included.
+    ///
+    /// Cells are escaped against formula injection, since the report is meant to be
+    /// opened in a spreadsheet and echoes what the uploaded file contained.
+    pub async fn rejected_rows_csv(&self) -> Result<Vec<u8>, ImportError> {
+        let mut writer = AsyncWriter::from_writer(Vec::new());
+        writer
+            .write_record(&["line", "name", "email", "reason"])
+            .await
+            .map_err(ImportError::CsvError)?;
+        for row in &self.rejected {
+            writer
+                .write_record(
+                    [
+                        Cow::Owned(row.line.to_string()),
+                        escape_formula(&row.name),
+                        escape_formula(&row.email),
+                        escape_formula(&row.reason),
+                    ]
+                    .iter()
+                    .map(|cell| cell.as_bytes()),
+                )
+                .await
+                .map_err(ImportError::CsvError)?;
+        }
+        writer
+            .into_inner()
+            .await
+            .map_err(|e| ImportError::CsvError(e.into()))
+    }
+}
+
+/// Prefixes with `'` the cells a spreadsheet would evaluate as a formula.
+fn escape_formula(cell: &str) -> Cow<'_, str> {
+    if cell.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        Cow::Owned(format!("'{}", cell))
+    } else {
+        Cow::Borrowed(cell)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImportError {
+    #[error("Failed to read or write the CSV")]
+    CsvError(#[source] csv_async::Error),
+    #[error("Failed to store a batch of imported subscribers")]
+    DatabaseError(#[source] sqlx::Error),
+    #[error("Subscribers cannot be imported as pending confirmation: they would never be sent a confirmation email")]
+    PendingConfirmation,
+}
+
+struct PendingRow {
+    line: u64,
+    subscriber: NewSubscriber,
+}
+
+/// Streams a CSV with `name` and `email` columns into `subscriptions`.
+///
+/// Every row goes through the same validation as `POST /subscriptions`, email domain
+/// policy included; valid rows are inserted with `status` in transactions of
+/// `batch_size` rows, while invalid rows, addresses that are already subscribed and
+/// addresses erased at their owner's request end up in the report. A batch that fails
+/// is reported as well, and the import goes on with the next one.
+///
+/// If `reader` fails, the batches before stay imported: importing the file again
+/// reports them as already subscribed. No email is sent, hence `pending_confirmation`
+/// is refused: those subscribers would never get a link.
+#[tracing::instrument(
+    name = "Import subscribers",
+    skip(pool, reader, domain_policy, tombstone_secret)
+)]
+pub async fn import_subscribers<R>(
+    pool: &PgPool,
+    reader: R,
+    normalization: EmailNormalization,
+    domain_policy: &EmailDomainPolicy,
+    tombstone_secret: &Secret<String>,
+    status: SubscriptionStatus,
+    batch_size: usize,
+) -> Result<ImportReport, ImportError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    if status == SubscriptionStatus::PendingConfirmation {
+        return Err(ImportError::PendingConfirmation);
+    }
+    let mut report = ImportReport::default();
+    let operator_rules = get_email_domain_rules(pool, None)
+        .await
+        .map_err(ImportError::DatabaseError)?;
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut deserializer = AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .create_deserializer(reader);
+    let mut records = deserializer.deserialize_with_pos::<FormData>();
+
+    while let Some((record, position)) = records.next().await {
+        let line = position.line();
+        let form = match record {
+            Ok(form) => form,
+            Err(e) if e.is_io_error() => return Err(ImportError::CsvError(e)),
+            Err(e) => {
+                report.rejected.push(RejectedRow {
+                    line,
+                    name: String::new(),
+                    email: String::new(),
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let (name, email) = (form.name.clone(), form.email.clone());
+        match NewSubscriber::parse_form(form, normalization, domain_policy, &operator_rules) {
+            Ok(subscriber) => batch.push(PendingRow { line, subscriber }),
+            Err(errors) => report.rejected.push(RejectedRow {
+                line,
+                name,
+                email,
+                reason: errors
+                    .into_iter()
+                    .map(|e| e.message)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            }),
+        }
+        if batch.len() >= batch_size {
+            let batch = std::mem::take(&mut batch);
+            import_batch(pool, batch, tombstone_secret, status, &mut report).await;
+        }
+    }
+    if !batch.is_empty() {
+        import_batch(pool, batch, tombstone_secret, status, &mut report).await;
+    }
+    report.rejected.sort_by_key(|r| r.line);
+    Ok(report)
+}
+
+/// Inserts `batch`, or reports its failure along with its rows.
+async fn import_batch(
+    pool: &PgPool,
+    batch: Vec<PendingRow>,
+    tombstone_secret: &Secret<String>,
+    status: SubscriptionStatus,
+    report: &mut ImportReport,
+) {
+    let rows: Vec<RejectedRow> = batch
+        .iter()
+        .map(|row| RejectedRow {
+            line: row.line,
+            name: row.subscriber.name.as_ref().to_owned(),
+            email: row.subscriber.email.as_ref().to_owned(),
+            reason: String::new(),
+        })
+        .collect();
+    match insert_batch(pool, batch, tombstone_secret, status).await {
+        Ok(outcome) => {
+            report.imported += outcome.imported;
+            report.rejected.extend(outcome.rejected);
+        }
+        Err(e) => {
+            tracing::error!("Failed to import a batch of subscribers: {:?}", e);
+            let error = e.to_string();
+            report.failed_batches.push(FailedBatch {
+                first_line: rows.first().map_or(0, |r| r.line),
+                last_line: rows.last().map_or(0, |r| r.line),
+                error: error.clone(),
+            });
+            report
+                .rejected
+                .extend(rows.into_iter().map(|row| RejectedRow {
+                    reason: format!("Its batch could not be stored: {}", error),
+                    ..row
+                }));
+        }
+    }
+}
+
+/// The outcome of the batch, once committed.
+#[tracing::instrument(name = "Insert a batch of imported subscribers", skip_all, fields(batch_size = batch.len()))]
+async fn insert_batch(
+    pool: &PgPool,
+    batch: Vec<PendingRow>,
+    tombstone_secret: &Secret<String>,
+    status: SubscriptionStatus,
+) -> Result<ImportReport, sqlx::Error> {
+    let mut report = ImportReport::default();
+    let mut transaction = pool.begin().await?;
+    let email_hashes: Vec<String> = batch
+        .iter()
+        .map(|row| erased_email_hash(row.subscriber.email.canonical(), tombstone_secret))
+        .collect();
+    let erased: HashSet<String> = get_erased_email_hashes(&mut transaction, &email_hashes)
+        .await?
+        .into_iter()
+        .collect();
+    let (batch, erased_rows): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .zip(email_hashes)
+        .partition(|(_, email_hash)| !erased.contains(email_hash));
+    for (row, _) in erased_rows {
+        report.rejected.push(RejectedRow {
+            line: row.line,
+            name: row.subscriber.name.as_ref().to_owned(),
+            email: row.subscriber.email.as_ref().to_owned(),
+            reason: "This email address was erased at its owner's request.".into(),
+        });
+    }
+    let batch: Vec<PendingRow> = batch.into_iter().map(|(row, _)| row).collect();
+
+    let mut ids = Vec::with_capacity(batch.len());
+    let mut emails = Vec::with_capacity(batch.len());
+    let mut canonical_emails = Vec::with_capacity(batch.len());
+    let mut names = Vec::with_capacity(batch.len());
+    for row in &batch {
+        ids.push(Uuid::new_v4());
+        emails.push(row.subscriber.email.as_ref().to_owned());
+        canonical_emails.push(row.subscriber.email.canonical().to_owned());
+        names.push(row.subscriber.name.as_ref().to_owned());
+    }
+
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO subscriptions
+            (id, email, email_canonical, name, subscribed_at, status)
+        SELECT id, email, email_canonical, name, $5, $6
+        FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::text[])
+            AS batch(id, email, email_canonical, name)
+        ON CONFLICT (email_canonical) DO NOTHING
+        RETURNING email_canonical
+        "#,
+        &ids,
+        &emails,
+        &canonical_emails,
+        &names,
+        Utc::now(),
+        status.as_str(),
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+
+    let mut inserted: HashSet<String> = inserted.into_iter().map(|r| r.email_canonical).collect();
+    for row in batch {
+        // `remove` so that a second occurrence of the same address in the file is reported.
+        if inserted.remove(row.subscriber.email.canonical()) {
+            report.imported += 1;
+        } else {
+            report.rejected.push(RejectedRow {
+                line: row.line,
+                name: row.subscriber.name.as_ref().to_owned(),
+                email: row.subscriber.email.as_ref().to_owned(),
+                reason: "This email address is already subscribed.".into(),
+            });
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_formula, ImportReport, RejectedRow};
+
+    #[tokio::test]
+    async fn the_report_lists_rejected_rows_with_a_header() {
+        let report = ImportReport {
+            imported: 1,
+            rejected: vec![RejectedRow {
+                line: 3,
+                name: "".into(),
+                email: "ursula@example.com".into(),
+                reason: " is not a valid subscriber name.".into(),
+            }],
+            ..ImportReport::default()
+        };
+
+        let csv = String::from_utf8(report.rejected_rows_csv().await.unwrap()).unwrap();
+
+        assert_eq!(
+            csv,
+            "line,name,email,reason\n3,,ursula@example.com, is not a valid subscriber name.\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn cells_a_spreadsheet_would_evaluate_are_escaped() {
+        let report = ImportReport {
+            rejected: vec![RejectedRow {
+                line: 2,
+                name: "=HYPERLINK(\"http://evil.example\")".into(),
+                email: "@SUM(A1)".into(),
+                reason: "-1+1 is not valid.".into(),
+            }],
+            ..ImportReport::default()
+        };
+
+        let csv = String::from_utf8(report.rejected_rows_csv().await.unwrap()).unwrap();
+
+        assert_eq!(
+            csv.lines().nth(1).unwrap(),
+            r#"2,"'=HYPERLINK(""http://evil.example"")",'@SUM(A1),'-1+1 is not valid."#
+        );
+    }
+
+    #[test]
+    fn ordinary_cells_are_left_alone() {
+        for cell in ["ursula@example.com", "Ursula Le Guin", "", "a=b"] {
+            assert_eq!(escape_formula(cell), cell);
+        }
+    }
+
+    #[tokio::test]
+    async fn an_empty_report_still_has_a_header() {
+        let csv = ImportReport::default().rejected_rows_csv().await.unwrap();
+
+        assert_eq!(String::from_utf8(csv).unwrap(), "line,name,email,reason\n");
+    }
+}
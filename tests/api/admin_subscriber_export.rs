@@ -0,0 +1,119 @@
+use crate::common::{assert_is_redirect_to, spawn_app, TestApp};
+
+/// `n` subscribers named `user<i>`, subscribed `i` minutes after the first one.
+async fn insert_subscribers(app: &TestApp, n: i32, status: &str) {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions
+            (id, email, email_canonical, name, subscribed_at, status)
+        SELECT
+            gen_random_uuid(),
+            'user' || i || '-' || $2 || '@example.com',
+            'user' || i || '-' || $2 || '@example.com',
+            'user' || i,
+            '2022-03-01T00:00:00Z'::timestamptz + make_interval(mins => i),
+            $2
+        FROM generate_series(1, $1) AS i
+        "#,
+        n,
+        status,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn get_export(app: &TestApp, query: &str) -> reqwest::Response {
+    app.api_client
+        .get(format!(
+            "{}/admin/subscribers/export?{}",
+            app.address, query
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_export_subscribers() {
+    let app = spawn_app().await;
+
+    let response = get_export(&app, "format=csv").await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn the_csv_export_lists_every_subscriber_oldest_first() {
+    let app = spawn_app().await;
+    insert_subscribers(&app, 2, "confirmed").await;
+    app.login_as_test_user().await;
+
+    let response = get_export(&app, "format=csv").await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/csv"));
+    assert!(response.headers()["Content-Disposition"]
+        .to_str()
+        .unwrap()
+        .contains("subscribers.csv"));
+    let body = response.text().await.unwrap();
+    let lines: Vec<_> = body.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "id,email,name,status,subscribed_at");
+    assert!(lines[1].contains(",user1-confirmed@example.com,user1,confirmed,2022-03-01T00:01:00"));
+    assert!(lines[2].contains(",user2-confirmed@example.com,user2,confirmed,2022-03-01T00:02:00"));
+}
+
+#[tokio::test]
+async fn the_ndjson_export_can_be_filtered_by_status() {
+    let app = spawn_app().await;
+    insert_subscribers(&app, 2, "confirmed").await;
+    insert_subscribers(&app, 3, "unsubscribed").await;
+    app.login_as_test_user().await;
+
+    let response = get_export(&app, "format=ndjson&status=unsubscribed").await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(response.headers()["Content-Type"], "application/x-ndjson");
+    let body = response.text().await.unwrap();
+    let rows: Vec<serde_json::Value> = body
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+    assert_eq!(rows.len(), 3);
+    for row in rows {
+        assert_eq!(row["status"], "unsubscribed");
+        assert!(row["subscribed_at"].is_string());
+        assert!(row["id"].is_string());
+    }
+}
+
+#[tokio::test]
+async fn exports_larger_than_a_cursor_batch_are_complete() {
+    let app = spawn_app().await;
+    insert_subscribers(&app, 2500, "confirmed").await;
+    app.login_as_test_user().await;
+
+    let body = get_export(&app, "format=csv").await.text().await.unwrap();
+
+    assert_eq!(body.lines().count(), 2501);
+    assert!(body
+        .lines()
+        .last()
+        .unwrap()
+        .contains("user2500-confirmed@example.com"));
+}
+
+#[tokio::test]
+async fn an_unknown_format_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = get_export(&app, "format=xml").await;
+
+    assert_eq!(400, response.status().as_u16());
+}
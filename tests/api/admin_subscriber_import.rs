@@ -0,0 +1,234 @@
+use crate::common::{assert_is_redirect_to, spawn_app, TestApp};
+use secrecy::Secret;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use zero2prod::domain::{EmailDomainPolicy, EmailNormalization, SubscriptionStatus};
+use zero2prod::subscriber_import::{import_subscribers, ImportError, ImportReport};
+
+async fn post_import(app: &TestApp, status: &str, csv: &'static str) -> reqwest::Response {
+    app.api_client
+        .post(format!(
+            "{}/admin/subscribers/import?status={}",
+            app.address, status
+        ))
+        .header("Content-Type", "text/csv")
+        .body(csv)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn stored_subscribers(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!("SELECT email, status FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| (r.email, r.status))
+        .collect()
+}
+
+/// Imports `reader` with batches of 10 rows.
+async fn import<R>(app: &TestApp, reader: R) -> Result<ImportReport, ImportError>
+where
+    R: AsyncRead + Unpin + Send,
+{
+    import_subscribers(
+        &app.db_pool,
+        reader,
+        EmailNormalization::default(),
+        &EmailDomainPolicy::new(true, &[], &[]).unwrap(),
+        &Secret::new("secret".to_string()),
+        SubscriptionStatus::Confirmed,
+        10,
+    )
+    .await
+}
+
+/// A source that breaks off, like an interrupted upload.
+struct BrokenReader;
+
+impl AsyncRead for BrokenReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+    }
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_import_subscribers() {
+    let app = spawn_app().await;
+
+    let response = post_import(&app, "confirmed", "name,email\nursula,ursula@example.com\n").await;
+
+    assert_is_redirect_to(&response, "/login");
+    assert!(stored_subscribers(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn valid_rows_are_imported_with_the_chosen_status() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = post_import(
+        &app,
+        "confirmed",
+        "name,email\nUrsula Le Guin,ursula@example.com\nOctavia Butler,octavia@example.com\n",
+    )
+    .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(response.headers()["X-Imported-Count"], "2");
+    assert_eq!(response.headers()["X-Rejected-Count"], "0");
+    assert_eq!(
+        stored_subscribers(&app).await,
+        [
+            ("octavia@example.com".to_string(), "confirmed".to_string()),
+            ("ursula@example.com".to_string(), "confirmed".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rejected_rows_are_reported_as_a_csv_attachment() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = post_import(
+        &app,
+        "unsubscribed",
+        "name,email\n\
+         Ursula Le Guin,ursula@example.com\n\
+         ,blank-name@example.com\n\
+         Octavia Butler,not-an-email\n\
+         Ursula Again,ursula@example.com\n\
+         only-one-column\n",
+    )
+    .await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(response.headers()["X-Imported-Count"], "1");
+    assert_eq!(response.headers()["X-Rejected-Count"], "4");
+    assert!(response.headers()["Content-Type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/csv"));
+    assert!(response.headers()["Content-Disposition"]
+        .to_str()
+        .unwrap()
+        .contains("attachment"));
+    let report = response.text().await.unwrap();
+    let lines: Vec<_> = report.lines().collect();
+    assert_eq!(lines[0], "line,name,email,reason");
+    assert!(lines[1].starts_with("3,,blank-name@example.com,"));
+    assert!(lines[1].contains("is not a valid subscriber name"));
+    assert!(lines[2].starts_with("4,Octavia Butler,not-an-email,"));
+    assert!(lines[2].contains("is not a valid subriber email"));
+    assert!(lines[3].starts_with("5,Ursula Again,ursula@example.com,"));
+    assert!(lines[3].contains("already subscribed"));
+    assert!(lines[4].starts_with("6,"));
+    assert_eq!(
+        stored_subscribers(&app).await,
+        [("ursula@example.com".to_string(), "unsubscribed".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn an_unknown_status_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = post_import(&app, "banned", "name,email\nursula,ursula@example.com\n").await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(stored_subscribers(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn large_files_are_imported_in_several_batches() {
+    let app = spawn_app().await;
+    let mut csv = String::from("name,email\n");
+    for i in 0..25 {
+        csv.push_str(&format!("user {0},user{0}@example.com\n", i));
+    }
+    csv.push_str("user 3,user3@example.com\n");
+
+    let report = import(&app, csv.as_bytes()).await.unwrap();
+
+    assert_eq!(report.imported, 25);
+    assert_eq!(report.rejected.len(), 1);
+    assert_eq!(report.rejected[0].line, 27);
+    assert_eq!(stored_subscribers(&app).await.len(), 25);
+}
+
+#[tokio::test]
+async fn case_variants_of_known_addresses_are_reported_as_already_subscribed() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    post_import(&app, "confirmed", "name,email\nursula,ursula@example.com\n").await;
+
+    let response = post_import(&app, "confirmed", "name,email\nursula,URSULA@Example.com\n").await;
+
+    assert_eq!(response.headers()["X-Imported-Count"], "0");
+    let report = response.text().await.unwrap();
+    assert!(report.contains("URSULA@Example.com,This email address is already subscribed."));
+    assert_eq!(stored_subscribers(&app).await.len(), 1);
+}
+
+#[tokio::test]
+async fn subscribers_cannot_be_imported_as_pending_confirmation() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = post_import(
+        &app,
+        "pending_confirmation",
+        "name,email\nursula,ursula@example.com\n",
+    )
+    .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(stored_subscribers(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn the_batches_read_in_full_are_kept_when_the_file_breaks_off() {
+    let app = spawn_app().await;
+    let mut csv = String::from("name,email\n");
+    for i in 0..25 {
+        csv.push_str(&format!("user {0},user{0}@example.com\n", i));
+    }
+
+    let outcome = import(&app, csv.as_bytes().chain(BrokenReader)).await;
+
+    assert!(matches!(outcome, Err(ImportError::CsvError(_))));
+    assert_eq!(stored_subscribers(&app).await.len(), 20);
+}
+
+#[tokio::test]
+async fn a_batch_that_cannot_be_stored_is_reported_and_the_import_goes_on() {
+    let app = spawn_app().await;
+    sqlx::query!(
+        "ALTER TABLE subscriptions ADD CONSTRAINT no_user13 CHECK (email <> 'user13@example.com')"
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let mut csv = String::from("name,email\n");
+    for i in 0..25 {
+        csv.push_str(&format!("user {0},user{0}@example.com\n", i));
+    }
+
+    let report = import(&app, csv.as_bytes()).await.unwrap();
+
+    assert_eq!(report.imported, 15);
+    assert_eq!(report.failed_batches.len(), 1);
+    assert_eq!(report.failed_batches[0].first_line, 12);
+    assert_eq!(report.failed_batches[0].last_line, 21);
+    assert_eq!(report.rejected.len(), 10);
+    assert_eq!(stored_subscribers(&app).await.len(), 15);
+}
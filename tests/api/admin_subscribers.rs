@@ -0,0 +1,157 @@
+use crate::common::{assert_is_redirect_to, spawn_app, TestApp};
+use uuid::Uuid;
+
+async fn insert_subscriber(app: &TestApp, name: &str, email: &str, status: &str, minutes_ago: i32) {
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions
+            (id, email, email_canonical, name, subscribed_at, status)
+        VALUES ($1, $2, lower($2), $3, now() - make_interval(mins => $4), $5)
+        "#,
+        Uuid::new_v4(),
+        email,
+        name,
+        minutes_ago,
+        status,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn get_subscribers_json(app: &TestApp, query: &str) -> serde_json::Value {
+    let response = app
+        .api_client
+        .get(format!(
+            "{}/admin/subscribers?format=json&{}",
+            app.address, query
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(200, response.status().as_u16());
+    response.json().await.unwrap()
+}
+
+fn emails(page: &serde_json::Value) -> Vec<&str> {
+    page["subscribers"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|s| s["email"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_list_subscribers() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/admin/subscribers", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn subscribers_are_paginated_newest_first() {
+    let app = spawn_app().await;
+    for i in 0..5 {
+        let email = format!("user{}@example.com", i);
+        insert_subscriber(&app, "user", &email, "confirmed", i).await;
+    }
+    app.login_as_test_user().await;
+
+    let first = get_subscribers_json(&app, "limit=2").await;
+    assert_eq!(emails(&first), ["user0@example.com", "user1@example.com"]);
+    let cursor = first["next_cursor"].as_str().unwrap();
+
+    let second = get_subscribers_json(&app, &format!("limit=2&after={}", cursor)).await;
+    assert_eq!(emails(&second), ["user2@example.com", "user3@example.com"]);
+    let cursor = second["next_cursor"].as_str().unwrap();
+
+    let third = get_subscribers_json(&app, &format!("limit=2&after={}", cursor)).await;
+    assert_eq!(emails(&third), ["user4@example.com"]);
+    assert!(third["next_cursor"].is_null());
+}
+
+#[tokio::test]
+async fn subscribers_can_be_filtered_by_status() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "a", "pending@example.com", "pending_confirmation", 1).await;
+    insert_subscriber(&app, "b", "confirmed@example.com", "confirmed", 2).await;
+    app.login_as_test_user().await;
+
+    let page = get_subscribers_json(&app, "status=confirmed").await;
+
+    assert_eq!(emails(&page), ["confirmed@example.com"]);
+    assert_eq!(page["subscribers"][0]["status"], "confirmed");
+}
+
+#[tokio::test]
+async fn search_is_case_insensitive_on_email_and_name() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "Ursula Le Guin", "ursula@example.com", "confirmed", 1).await;
+    insert_subscriber(&app, "Octavia Butler", "ob@EXAMPLE.com", "confirmed", 2).await;
+    insert_subscriber(&app, "Someone Else", "else@example.org", "confirmed", 3).await;
+    app.login_as_test_user().await;
+
+    let by_name = get_subscribers_json(&app, "search=le%20GUIN").await;
+    let by_email = get_subscribers_json(&app, "search=example.COM").await;
+
+    assert_eq!(emails(&by_name), ["ursula@example.com"]);
+    assert_eq!(emails(&by_email), ["ursula@example.com", "ob@EXAMPLE.com"]);
+}
+
+#[tokio::test]
+async fn subscribers_with_invalid_stored_details_are_left_out() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "valid", "valid@example.com", "confirmed", 1).await;
+    insert_subscriber(&app, "<script>", "script@example.com", "confirmed", 2).await;
+    insert_subscriber(&app, "broken", "not-an-email", "confirmed", 3).await;
+    app.login_as_test_user().await;
+
+    let page = get_subscribers_json(&app, "").await;
+
+    assert_eq!(emails(&page), ["valid@example.com"]);
+}
+
+#[tokio::test]
+async fn the_html_page_lists_subscribers_and_links_to_the_next_page() {
+    let app = spawn_app().await;
+    insert_subscriber(&app, "first", "first@example.com", "confirmed", 1).await;
+    insert_subscriber(&app, "second", "second@example.com", "confirmed", 2).await;
+    app.login_as_test_user().await;
+
+    let html = app
+        .api_client
+        .get(format!("{}/admin/subscribers?limit=1&status=", app.address))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    assert!(html.contains("first@example.com"));
+    assert!(!html.contains("second@example.com"));
+    assert!(html.contains("Next page"));
+}
+
+#[tokio::test]
+async fn a_malformed_cursor_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/admin/subscribers?after=garbage", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+}
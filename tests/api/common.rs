@@ -1,8 +1,13 @@
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DataBaseSettings};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{get_configuration, DataBaseSettings, EmailProvider, Settings};
+use zero2prod::email_client::EmailClient;
+use zero2prod::email_outbox::{try_execute_task, ExecutionOutcome};
 use zero2prod::startup::{get_connection_pool, Application};
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
@@ -24,13 +29,16 @@ pub struct TestApp {
     pub port: u16,
     pub db_pool: PgPool,
     pub email_server: MockServer,
+    pub email_client: EmailClient,
+    pub api_client: reqwest::Client,
+    pub test_user: TestUser,
 }
 
 impl TestApp {
     #[allow(dead_code)]
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -38,6 +46,125 @@ impl TestApp {
             .expect("Failed to send formdata")
     }
 
+    /// Deliver every email that is currently due in the outbox, waiting for
+    /// the ones the background worker may be in the middle of sending.
+    /// Accepts every email sent for the rest of the test.
+    pub async fn mock_email_server(&self) {
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&self.email_server)
+            .await;
+    }
+
+    /// Subscribes `email`, and returns the request of its confirmation email.
+    /// The email server must have been mocked.
+    pub async fn subscribe(&self, name: &str, email: &str) -> wiremock::Request {
+        let body = serde_urlencoded::to_string([("name", name), ("email", email)]).unwrap();
+        self.post_subscriptions(body)
+            .await
+            .error_for_status()
+            .unwrap();
+        self.dispatch_all_pending_emails().await;
+        self.email_server
+            .received_requests()
+            .await
+            .unwrap()
+            .pop()
+            .unwrap()
+    }
+
+    /// Subscribes `email` and confirms it. Only the confirmation email is mocked.
+    pub async fn create_confirmed_subscriber(&self, email: &str) -> ConfirmedSubscriber {
+        let _mock_guard = Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount_as_scoped(&self.email_server)
+            .await;
+        let email_request = self.subscribe("le guin", email).await;
+        reqwest::get(self.get_confirmation_links(&email_request).html)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+        let subscriber = sqlx::query!("SELECT id FROM subscriptions WHERE email = $1", email)
+            .fetch_one(&self.db_pool)
+            .await
+            .unwrap();
+        ConfirmedSubscriber {
+            id: subscriber.id,
+            preferences_link: self.get_preferences_link(&email_request),
+        }
+    }
+
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_task(&self.db_pool, &self.email_client)
+                    .await
+                    .unwrap()
+            {
+                let pending = sqlx::query!(
+                    "SELECT COUNT(*) AS \"count!\" FROM email_outbox WHERE execute_after <= now()"
+                )
+                .fetch_one(&self.db_pool)
+                .await
+                .unwrap();
+                if pending.count == 0 {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn login_as_test_user(&self) {
+        let response = self
+            .post_login(&serde_json::json!({
+                "username": &self.test_user.username,
+                "password": &self.test_user.password,
+            }))
+            .await;
+        assert_is_redirect_to(&response, "/admin/dashboard");
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/newsletters", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
@@ -45,6 +172,7 @@ impl TestApp {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
                 .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .filter(|l| l.as_str().contains("/subscriptions/confirm"))
                 .collect();
             assert_eq!(links.len(), 1);
             let raw_link = links[0].as_str().to_owned();
@@ -59,9 +187,83 @@ impl TestApp {
 
         ConfirmationLinks { html, plain_text }
     }
+
+    #[allow(dead_code)]
+    pub fn get_preferences_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let links: Vec<_> = linkify::LinkFinder::new()
+            .links(body["TextBody"].as_str().unwrap())
+            .filter(|l| l.as_str().contains("/preferences"))
+            .collect();
+        assert_eq!(links.len(), 1);
+        let mut preferences_link = reqwest::Url::parse(links[0].as_str()).unwrap();
+        assert_eq!(preferences_link.host_str().unwrap(), "127.0.0.1");
+        preferences_link.set_port(Some(self.port)).unwrap();
+        preferences_link
+    }
+
+    /// The link of an email asking to confirm a change of address, given as JSON.
+    pub fn get_email_change_link(&self, email: &serde_json::Value) -> reqwest::Url {
+        let links: Vec<_> = linkify::LinkFinder::new()
+            .links(email["TextBody"].as_str().unwrap())
+            .filter(|l| l.as_str().contains("/subscriptions/confirm_email_change"))
+            .collect();
+        assert_eq!(links.len(), 1);
+        let mut link = reqwest::Url::parse(links[0].as_str()).unwrap();
+        link.set_port(Some(self.port)).unwrap();
+        link
+    }
+
+    /// The signed token carried by a preferences link.
+    pub fn preferences_token(preferences_link: &reqwest::Url) -> String {
+        preferences_link
+            .query_pairs()
+            .find(|(k, _)| k == "token")
+            .unwrap()
+            .1
+            .into_owned()
+    }
+
+    pub fn get_unsubscribe_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let header = body["Headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|h| h["Name"] == "List-Unsubscribe")
+            .expect("No List-Unsubscribe header");
+        let raw_link = header["Value"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        let mut unsubscribe_link = reqwest::Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+        unsubscribe_link.set_port(Some(self.port)).unwrap();
+        unsubscribe_link
+    }
+
+    /// The RFC 8058 one-click unsubscribe a mail client sends for `email_request`.
+    pub async fn post_one_click_unsubscribe(
+        &self,
+        email_request: &wiremock::Request,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(self.get_unsubscribe_link(email_request))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("List-Unsubscribe=One-Click")
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, with `customise` applied to the configuration last.
+pub async fn spawn_app_with(customise: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     let email_server = MockServer::start().await;
@@ -69,8 +271,12 @@ pub async fn spawn_app() -> TestApp {
     let configuration = {
         let mut c = get_configuration().expect("Failed to read configuration");
         c.database.database_name = Uuid::new_v4().to_string();
+        c.email_client.provider = EmailProvider::Postmark;
         c.email_client.base_url = email_server.uri();
         c.application.port = 0;
+        // Most tests post to /subscriptions directly, as an API client would.
+        c.spam_protection.allow_tokenless_submissions = true;
+        customise(&mut c);
         c
     };
 
@@ -81,15 +287,27 @@ pub async fn spawn_app() -> TestApp {
         .expect("Failed to build application");
     let port = app.port();
     let address = format!("http://127.0.0.1:{}", app.port());
-    let _ = tokio::spawn(app.run_until_stopped());
+    tokio::spawn(app.run_until_stopped());
 
     let db_pool = get_connection_pool(&configuration.database);
 
+    let api_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_store(true)
+        .build()
+        .unwrap();
+
+    let test_user = TestUser::generate();
+    test_user.store(&db_pool).await;
+
     TestApp {
         address,
         port,
         db_pool,
         email_server,
+        email_client: configuration.email_client.client(),
+        api_client,
+        test_user,
     }
 }
 
@@ -113,6 +331,54 @@ pub async fn configure_database(config: &DataBaseSettings) -> PgPool {
     connection_pool
 }
 
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    pub fn generate() -> Self {
+        Self {
+            user_id: Uuid::new_v4(),
+            username: Uuid::new_v4().to_string(),
+            password: Uuid::new_v4().to_string(),
+        }
+    }
+
+    async fn store(&self, pool: &PgPool) {
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        // Cheap parameters keep the test suite fast; verification reads them from the hash.
+        let password_hash = Argon2::new(
+            Algorithm::Argon2id,
+            Version::V0x13,
+            Params::new(1024, 1, 1, None).unwrap(),
+        )
+        .hash_password(self.password.as_bytes(), &salt)
+        .unwrap()
+        .to_string();
+        sqlx::query!(
+            "INSERT INTO users (user_id, username, password_hash) VALUES ($1, $2, $3)",
+            self.user_id,
+            self.username,
+            password_hash,
+        )
+        .execute(pool)
+        .await
+        .expect("Failed to store test user.");
+    }
+}
+
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
+
+pub struct ConfirmedSubscriber {
+    pub id: Uuid,
+    pub preferences_link: reqwest::Url,
+}
+
 pub struct ConfirmationLinks {
     pub html: reqwest::Url,
     pub plain_text: reqwest::Url,
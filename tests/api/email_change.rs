@@ -0,0 +1,238 @@
+use crate::common::{spawn_app, spawn_app_with, TestApp};
+use std::num::NonZeroU32;
+use zero2prod::configuration::TokenBucketSettings;
+
+async fn request_email_change(app: &TestApp, token: &str, email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/preferences/email", &app.address))
+        .form(&[("token", token), ("email", email)])
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+/// The emails sent since `skip` requests were received, as JSON.
+async fn sent_emails(app: &TestApp, skip: usize) -> Vec<serde_json::Value> {
+    app.dispatch_all_pending_emails().await;
+    app.email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .skip(skip)
+        .map(|r| serde_json::from_slice(&r.body).unwrap())
+        .collect()
+}
+
+async fn stored_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+#[tokio::test]
+async fn a_change_emails_the_new_address_and_notifies_the_old_one() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    app.mock_email_server().await;
+
+    let response = request_email_change(&app, &token, "ursula@example.org").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let emails = sent_emails(&app, 1).await;
+    assert_eq!(emails.len(), 2);
+    let confirmation = emails
+        .iter()
+        .find(|e| e["To"] == "ursula@example.org")
+        .unwrap();
+    assert_eq!(confirmation["Subject"], "Confirm your new email address");
+    let notice = emails
+        .iter()
+        .find(|e| e["To"] == "ursula@example.com")
+        .unwrap();
+    assert!(notice["TextBody"]
+        .as_str()
+        .unwrap()
+        .contains("ursula@example.org"));
+    assert_eq!(stored_email(&app).await, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn following_the_link_swaps_the_address_once() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    app.mock_email_server().await;
+    request_email_change(&app, &token, "ursula@example.org").await;
+    let emails = sent_emails(&app, 1).await;
+    let confirmation = emails
+        .iter()
+        .find(|e| e["To"] == "ursula@example.org")
+        .unwrap();
+    let link = app.get_email_change_link(confirmation);
+
+    let response = reqwest::get(link.clone()).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(stored_email(&app).await, "ursula@example.org");
+    let change = sqlx::query!("SELECT old_value, new_value FROM subscriber_preference_changes")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(change.old_value.as_deref(), Some("ursula@example.com"));
+    assert_eq!(change.new_value.as_deref(), Some("ursula@example.org"));
+    assert_eq!(reqwest::get(link).await.unwrap().status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn only_the_latest_change_link_works() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    app.mock_email_server().await;
+    request_email_change(&app, &token, "ursula@example.org").await;
+    let emails = sent_emails(&app, 1).await;
+    let first = emails
+        .iter()
+        .find(|e| e["To"] == "ursula@example.org")
+        .unwrap();
+    let first_link = app.get_email_change_link(first);
+
+    request_email_change(&app, &token, "ursula@example.net").await;
+
+    assert_eq!(
+        reqwest::get(first_link).await.unwrap().status().as_u16(),
+        401
+    );
+}
+
+#[tokio::test]
+async fn an_address_subscribed_in_the_meantime_cannot_be_taken_over() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    app.create_confirmed_subscriber("octavia@example.com").await;
+    app.mock_email_server().await;
+    let already_sent = app.email_server.received_requests().await.unwrap().len();
+    request_email_change(&app, &token, "Octavia@example.com").await;
+    let emails = sent_emails(&app, already_sent).await;
+    let confirmation = emails
+        .iter()
+        .find(|e| e["To"] == "Octavia@example.com")
+        .unwrap();
+
+    let response = reqwest::get(app.get_email_change_link(confirmation))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let emails: Vec<_> = sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.email)
+        .collect();
+    assert_eq!(emails, vec!["octavia@example.com", "ursula@example.com"]);
+}
+
+#[tokio::test]
+async fn an_address_erased_in_the_meantime_cannot_be_taken_over() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    let octavia = app.create_confirmed_subscriber("octavia@example.com").await;
+    app.mock_email_server().await;
+    let already_sent = app.email_server.received_requests().await.unwrap().len();
+    request_email_change(&app, &token, "octavia@example.com").await;
+    let emails = sent_emails(&app, already_sent).await;
+    let confirmation = emails
+        .iter()
+        .find(|e| e["To"] == "octavia@example.com")
+        .unwrap();
+    app.login_as_test_user().await;
+    app.api_client
+        .post(format!(
+            "{}/admin/subscribers/{}/erase",
+            app.address, octavia.id
+        ))
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(app.get_email_change_link(confirmation))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(stored_email(&app).await, "ursula@example.com");
+}
+
+#[tokio::test]
+async fn an_invalid_new_address_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+
+    for email in [
+        "not-an-email",
+        "URSULA@example.com",
+        "ursula@mailinator.com",
+    ] {
+        let response = request_email_change(&app, &token, email).await;
+
+        assert_eq!(response.status().as_u16(), 400, "Accepted {}", email);
+    }
+}
+
+#[tokio::test]
+async fn an_unknown_change_token_is_rejected() {
+    let app = spawn_app().await;
+    let base = format!("{}/subscriptions/confirm_email_change", app.address);
+
+    let unknown = reqwest::get(format!("{}?change_token={}", base, "a".repeat(25)))
+        .await
+        .unwrap();
+    let malformed = reqwest::get(format!("{}?change_token=short", base))
+        .await
+        .unwrap();
+
+    assert_eq!(unknown.status().as_u16(), 401);
+    assert_eq!(malformed.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn email_change_requests_are_rate_limited_like_subscriptions() {
+    // The signup takes the first token of the bucket.
+    let app = spawn_app_with(|c| {
+        c.rate_limit.per_ip = TokenBucketSettings {
+            capacity: 3,
+            refill_per_hour: NonZeroU32::new(1).unwrap(),
+        }
+    })
+    .await;
+    let subscriber = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    app.mock_email_server().await;
+
+    for i in 0..2 {
+        let response =
+            request_email_change(&app, &token, &format!("reader{}@example.com", i)).await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = request_email_change(&app, &token, "reader2@example.com").await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let pending = sqlx::query!("SELECT new_email FROM email_change_requests")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(pending.new_email, "reader1@example.com");
+}
@@ -0,0 +1,102 @@
+use crate::common::{spawn_app, spawn_app_with, TestApp};
+
+async fn subscribe(app: &TestApp, email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Accept", "application/problem+json")
+        .form(&[("name", "le guin"), ("email", email)])
+        .send()
+        .await
+        .unwrap()
+}
+
+async fn add_rule(app: &TestApp, domain: &str, rule: &str) {
+    sqlx::query!(
+        "INSERT INTO email_domain_rules (domain, rule) VALUES ($1, $2)",
+        domain,
+        rule
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn assert_rejected_with(response: reqwest::Response, code: &str) {
+    assert_eq!(response.status().as_u16(), 400);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["errors"][0]["field"], "email");
+    assert_eq!(problem["errors"][0]["code"], code);
+}
+
+#[tokio::test]
+async fn disposable_email_addresses_are_rejected_with_their_own_reason() {
+    let app = spawn_app().await;
+
+    let response = subscribe(&app, "ursula@mailinator.com").await;
+
+    assert_rejected_with(response, "disposable_domain").await;
+}
+
+#[tokio::test]
+async fn malformed_email_addresses_keep_a_different_reason() {
+    let app = spawn_app().await;
+
+    let response = subscribe(&app, "not-an-email").await;
+
+    assert_rejected_with(response, "invalid").await;
+}
+
+#[tokio::test]
+async fn domains_blocked_by_an_operator_are_rejected() {
+    let app = spawn_app().await;
+    add_rule(&app, "example.org", "block").await;
+
+    let response = subscribe(&app, "ursula@lists.example.org").await;
+
+    assert_rejected_with(response, "blocked_domain").await;
+}
+
+#[tokio::test]
+async fn domains_allowed_by_an_operator_skip_the_disposable_list() {
+    let app = spawn_app().await;
+    add_rule(&app, "mailinator.com", "allow").await;
+
+    let response = subscribe(&app, "ursula@mailinator.com").await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn the_configuration_can_block_domains() {
+    let app = spawn_app_with(|c| {
+        c.email_domain_policy.blocked_domains = vec!["example.net".into()];
+    })
+    .await;
+
+    let response = subscribe(&app, "ursula@example.net").await;
+
+    assert_rejected_with(response, "blocked_domain").await;
+}
+
+#[tokio::test]
+async fn imports_apply_the_domain_policy_too() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let response = app
+        .api_client
+        .post(format!(
+            "{}/admin/subscribers/import?status=confirmed",
+            app.address
+        ))
+        .header("Content-Type", "text/csv")
+        .body("name,email\nursula,ursula@yopmail.com\nle guin,le_guin@example.com\n")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(response.headers()["X-Imported-Count"], "1");
+    let report = response.text().await.unwrap();
+    assert!(report.contains("ursula@yopmail.com,yopmail.com is a disposable email service."));
+}
@@ -0,0 +1,141 @@
+use crate::common::{assert_is_redirect_to, spawn_app};
+use secrecy::ExposeSecret;
+use zero2prod::authentication::{create_user, CreateUserError};
+
+#[tokio::test]
+async fn the_login_form_is_served() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/login", app.address))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(r#"action="/login""#));
+}
+
+#[tokio::test]
+async fn a_wrong_password_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": &app.test_user.username,
+            "password": "not-the-password",
+        }))
+        .await;
+
+    assert_eq!(401, response.status().as_u16());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("<p><i>Authentication failed</i></p>"));
+}
+
+#[tokio::test]
+async fn an_unknown_username_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": "nobody",
+            "password": "whatever",
+        }))
+        .await;
+
+    assert_eq!(401, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn a_failed_login_does_not_open_a_session() {
+    let app = spawn_app().await;
+
+    app.post_login(&serde_json::json!({
+        "username": &app.test_user.username,
+        "password": "not-the-password",
+    }))
+    .await;
+    let response = app.get_admin_dashboard().await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn a_successful_login_redirects_to_the_admin_dashboard() {
+    let app = spawn_app().await;
+
+    app.login_as_test_user().await;
+    let response = app.get_admin_dashboard().await;
+
+    assert_eq!(200, response.status().as_u16());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(&format!("Welcome {}", app.test_user.username)));
+}
+
+#[tokio::test]
+async fn sessions_are_stored_server_side() {
+    let app = spawn_app().await;
+
+    app.login_as_test_user().await;
+
+    let sessions = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM sessions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(sessions.count, 1);
+}
+
+#[tokio::test]
+async fn there_is_no_default_admin_account() {
+    let app = spawn_app().await;
+
+    let admins = sqlx::query!("SELECT user_id FROM users WHERE username = 'admin'")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert!(admins.is_empty());
+}
+
+#[tokio::test]
+async fn a_created_user_can_log_in_with_the_generated_password() {
+    let app = spawn_app().await;
+
+    let password = create_user(&app.db_pool, "operator").await.unwrap();
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": "operator",
+            "password": password.expose_secret(),
+        }))
+        .await;
+
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
+
+#[tokio::test]
+async fn a_taken_username_is_not_overwritten() {
+    let app = spawn_app().await;
+    let password = create_user(&app.db_pool, "operator").await.unwrap();
+
+    let outcome = create_user(&app.db_pool, "operator").await;
+
+    assert!(matches!(outcome, Err(CreateUserError::UsernameTaken(_))));
+    let response = app
+        .post_login(&serde_json::json!({
+            "username": "operator",
+            "password": password.expose_secret(),
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/dashboard");
+}
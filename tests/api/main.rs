@@ -1,4 +1,21 @@
+mod admin_dashboard;
+mod admin_subscriber_export;
+mod admin_subscriber_import;
+mod admin_subscribers;
 mod common;
+mod email_change;
+mod email_domain_policy;
 mod health_check;
+mod login;
+mod newsletter;
+mod pending_cleanup;
+mod preferences;
+mod problem_details;
+mod rate_limit;
+mod resend_confirmation;
+mod subscriber_data;
 mod subscription;
 mod subscription_confirm;
+mod subscription_locale;
+mod subscription_spam;
+mod subscription_unsubscribe;
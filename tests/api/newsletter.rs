@@ -0,0 +1,256 @@
+use crate::common::{assert_is_redirect_to, spawn_app, ConfirmationLinks, TestApp};
+use secrecy::Secret;
+use uuid::Uuid;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::newsletter_digest::send_due_digests;
+
+async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+    let _mock_guard = Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .named("Create unconfirmed subscriber")
+        .expect(1)
+        .mount_as_scoped(&app.email_server)
+        .await;
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    app.get_confirmation_links(&email_request)
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+#[tokio::test]
+async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
+    let app = spawn_app().await;
+    create_unconfirmed_subscriber(&app).await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.login_as_test_user().await;
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_confirmed_subscribers() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.login_as_test_user().await;
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_with_an_invalid_stored_email_are_skipped() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions
+            (id, email, email_canonical, name, subscribed_at, status)
+        VALUES ($1, 'not-an-email', 'not-an-email', 'broken', now(), 'confirmed')
+        "#,
+        Uuid::new_v4()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.login_as_test_user().await;
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn newsletters_returns_400_for_invalid_data() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    let test_cases = [
+        (
+            serde_json::json!({
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }),
+            "missing title",
+        ),
+        (
+            serde_json::json!({"title": "Newsletter!"}),
+            "missing content",
+        ),
+    ];
+
+    for (invalid_body, error_message) in test_cases {
+        let response = app.post_newsletters(invalid_body).await;
+
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not fail with 400 Bad Request when the payload was {}.",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_publish_a_newsletter() {
+    let app = spawn_app().await;
+
+    let response = app.post_newsletters(newsletter_request_body()).await;
+
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn newsletters_cannot_be_published_outside_the_admin_area() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/newsletters", &app.address))
+        .json(&newsletter_request_body())
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    assert_eq!(response.status().as_u16(), 404);
+    app.dispatch_all_pending_emails().await;
+}
+
+/// Switches the only subscriber to `frequency`, their last digest sent `days` ago.
+async fn set_frequency(app: &TestApp, frequency: &str, days: i32) {
+    sqlx::query!(
+        "UPDATE subscriptions SET frequency = $1, last_digest_at = now() - make_interval(days => $2)",
+        frequency,
+        days,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn send_digests(app: &TestApp) -> u64 {
+    send_due_digests(
+        &app.db_pool,
+        &app.address,
+        chrono::Duration::days(30),
+        &Secret::new("secret".to_string()),
+    )
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn digest_subscribers_do_not_get_issues_as_they_are_published() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    set_frequency(&app, "weekly", 0).await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    app.login_as_test_user().await;
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(send_digests(&app).await, 0);
+}
+
+#[tokio::test]
+async fn a_weekly_subscriber_gets_the_issues_of_the_week_in_one_digest() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    set_frequency(&app, "weekly", 8).await;
+    app.login_as_test_user().await;
+    for title in ["First issue", "Second issue"] {
+        let mut body = newsletter_request_body();
+        body["title"] = title.into();
+        app.post_newsletters(body).await.error_for_status().unwrap();
+    }
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let sent = send_digests(&app).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(sent, 1);
+    let email_request = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let email: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    assert_eq!(email["Subject"], "Your weekly newsletter digest");
+    let text = email["TextBody"].as_str().unwrap();
+    assert!(text.find("First issue").unwrap() < text.find("Second issue").unwrap());
+    // The next digest is a week away.
+    assert_eq!(send_digests(&app).await, 0);
+}
+
+#[tokio::test]
+async fn a_monthly_subscriber_waits_for_the_end_of_the_month() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    set_frequency(&app, "monthly", 8).await;
+    app.login_as_test_user().await;
+    app.post_newsletters(newsletter_request_body())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(send_digests(&app).await, 0);
+}
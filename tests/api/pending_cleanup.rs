@@ -0,0 +1,199 @@
+use crate::common::{spawn_app, TestApp};
+use std::num::NonZeroU32;
+use zero2prod::pending_cleanup::{clean_up_pending_subscriptions, CleanupMode, CleanupReport};
+
+/// Pretend the subscription and its confirmation emails are `days` old, their links
+/// having expired a day after being sent.
+async fn backdate(app: &TestApp, email: &str, days: i32) {
+    sqlx::query!(
+        "UPDATE subscriptions SET subscribed_at = now() - make_interval(days => $2) WHERE email = $1",
+        email,
+        days,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET created_at = now() - make_interval(days => $2),
+            expires_at = now() - make_interval(days => $2 - 1)
+        WHERE subscriber_id = (SELECT id FROM subscriptions WHERE email = $1)
+        "#,
+        email,
+        days,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+}
+
+async fn clean_up(app: &TestApp, mode: CleanupMode, batch_size: u32) -> CleanupReport {
+    clean_up_pending_subscriptions(
+        &app.db_pool,
+        chrono::Duration::days(30),
+        mode,
+        NonZeroU32::new(batch_size).unwrap(),
+    )
+    .await
+    .unwrap()
+}
+
+async fn subscription_emails(app: &TestApp) -> Vec<String> {
+    sqlx::query!("SELECT email FROM subscriptions ORDER BY email")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|r| r.email)
+        .collect()
+}
+
+#[tokio::test]
+async fn purging_removes_stale_pending_subscribers_and_their_tokens() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("stale", "stale@example.com").await;
+    app.subscribe("fresh", "fresh@example.com").await;
+    backdate(&app, "stale@example.com", 45).await;
+
+    let report = clean_up(&app, CleanupMode::Purge, 500).await;
+
+    assert_eq!(
+        report,
+        CleanupReport {
+            subscribers: 1,
+            tokens: 1
+        }
+    );
+    assert_eq!(subscription_emails(&app).await, vec!["fresh@example.com"]);
+    let tokens = sqlx::query!("SELECT count(*) AS \"count!\" FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens.count, 1);
+}
+
+#[tokio::test]
+async fn confirmed_subscribers_are_never_cleaned_up() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    backdate(&app, "ursula_le_guin@gmail.com", 45).await;
+
+    let report = clean_up(&app, CleanupMode::Purge, 500).await;
+
+    assert_eq!(report, CleanupReport::default());
+    assert_eq!(
+        subscription_emails(&app).await,
+        vec!["ursula_le_guin@gmail.com"]
+    );
+}
+
+#[tokio::test]
+async fn a_subscriber_sent_a_recent_confirmation_email_is_kept() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    backdate(&app, "ursula_le_guin@gmail.com", 45).await;
+    reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/resend_confirmation",
+            &app.address
+        ))
+        .form(&[("email", "ursula_le_guin@gmail.com")])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let report = clean_up(&app, CleanupMode::Purge, 500).await;
+
+    assert_eq!(report, CleanupReport::default());
+}
+
+#[tokio::test]
+async fn archiving_keeps_a_copy_of_the_stale_subscribers() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    backdate(&app, "ursula_le_guin@gmail.com", 45).await;
+
+    let report = clean_up(&app, CleanupMode::Archive, 500).await;
+
+    assert_eq!(report.subscribers, 1);
+    assert!(subscription_emails(&app).await.is_empty());
+    let archived = sqlx::query!("SELECT email, name FROM archived_pending_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(archived.email, "ursula_le_guin@gmail.com");
+    assert_eq!(archived.name, "le guin");
+}
+
+#[tokio::test]
+async fn every_stale_subscriber_is_cleaned_up_across_batches() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    for i in 0..3 {
+        let email = format!("reader{}@example.com", i);
+        app.subscribe("reader", &email).await;
+        backdate(&app, &email, 45).await;
+    }
+
+    let report = clean_up(&app, CleanupMode::Purge, 1).await;
+
+    assert_eq!(report.subscribers, 3);
+    assert!(subscription_emails(&app).await.is_empty());
+}
+
+#[tokio::test]
+async fn a_purged_address_can_subscribe_again() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    backdate(&app, "ursula_le_guin@gmail.com", 45).await;
+    clean_up(&app, CleanupMode::Purge, 500).await;
+
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+
+    let link = app.get_confirmation_links(&email_request).html;
+    assert_eq!(reqwest::get(link).await.unwrap().status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn a_subscriber_with_a_working_link_is_kept_whatever_the_max_age() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    // Sent two hours ago, with a link valid for a day.
+    sqlx::query!("UPDATE subscriptions SET subscribed_at = now() - interval '2 hours'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET created_at = now() - interval '2 hours', expires_at = now() + interval '22 hours'
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let report = clean_up_pending_subscriptions(
+        &app.db_pool,
+        chrono::Duration::hours(1),
+        CleanupMode::Purge,
+        NonZeroU32::new(500).unwrap(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(report, CleanupReport::default());
+    assert_eq!(
+        subscription_emails(&app).await,
+        vec!["ursula_le_guin@gmail.com"]
+    );
+}
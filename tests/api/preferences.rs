@@ -0,0 +1,236 @@
+use crate::common::{spawn_app, spawn_app_with, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn post_preferences(app: &TestApp, body: &[(&str, &str)]) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/preferences", &app.address))
+        .form(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn the_preferences_link_opens_the_preferences_page() {
+    let app = spawn_app().await;
+    let preferences_link = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .preferences_link;
+
+    let response = reqwest::get(preferences_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("ursula_le_guin@gmail.com"));
+    // Attribute values have every non-alphanumeric character encoded.
+    assert!(html.contains(r#"value="le&#x20;guin""#));
+}
+
+#[tokio::test]
+async fn preferences_are_saved_and_every_change_is_recorded() {
+    let app = spawn_app().await;
+    let subscriber = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+
+    let response = post_preferences(
+        &app,
+        &[
+            ("token", &token),
+            ("action", "save"),
+            ("name", "Ursula K. Le Guin"),
+            ("frequency", "weekly"),
+            ("topics", "articles"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT name, frequency, topics FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "Ursula K. Le Guin");
+    assert_eq!(saved.frequency, "weekly");
+    assert_eq!(saved.topics, Some(vec!["articles".to_string()]));
+    let changes = sqlx::query!(
+        "SELECT field, old_value, new_value FROM subscriber_preference_changes ORDER BY field"
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    let changes: Vec<_> = changes
+        .into_iter()
+        .map(|c| (c.field, c.old_value, c.new_value))
+        .collect();
+    assert_eq!(
+        changes,
+        vec![
+            (
+                "frequency".to_string(),
+                Some("every_issue".to_string()),
+                Some("weekly".to_string())
+            ),
+            (
+                "name".to_string(),
+                Some("le guin".to_string()),
+                Some("Ursula K. Le Guin".to_string())
+            ),
+            ("topics".to_string(), None, Some("articles".to_string())),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn an_invalid_name_is_rejected_and_nothing_is_saved() {
+    let app = spawn_app().await;
+    let subscriber = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+
+    let response = post_preferences(
+        &app,
+        &[
+            ("token", &token),
+            ("action", "save"),
+            ("name", "<script>"),
+            ("frequency", "monthly"),
+        ],
+    )
+    .await;
+
+    assert_eq!(response.status().as_u16(), 400);
+    let saved = sqlx::query!("SELECT name, frequency FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.frequency, "every_issue");
+}
+
+#[tokio::test]
+async fn subscribers_can_unsubscribe_from_the_preferences_page() {
+    let app = spawn_app().await;
+    let subscriber = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+
+    let response = post_preferences(&app, &[("token", &token), ("action", "unsubscribe")]).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "unsubscribed");
+    let change = sqlx::query!("SELECT field, new_value FROM subscriber_preference_changes")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(change.field, "status");
+    assert_eq!(change.new_value.as_deref(), Some("unsubscribed"));
+}
+
+#[tokio::test]
+async fn a_tampered_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+    let subscriber = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    let first = if token.starts_with('0') { '1' } else { '0' };
+    let tampered = format!("{}{}", first, &token[1..]);
+
+    let response = reqwest::get(format!("{}/preferences?token={}", app.address, tampered))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn an_expired_link_is_rejected_with_a_401() {
+    let app = spawn_app_with(|c| c.preferences.link_ttl_hours = 0).await;
+    let preferences_link = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .preferences_link;
+
+    let response = reqwest::get(preferences_link).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn newsletters_on_a_topic_skip_subscribers_who_did_not_choose_it() {
+    let app = spawn_app().await;
+    let subscriber = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    post_preferences(
+        &app,
+        &[
+            ("token", &token),
+            ("action", "save"),
+            ("name", "le guin"),
+            ("frequency", "every_issue"),
+            ("topics", "articles"),
+        ],
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.login_as_test_user().await;
+    for topic in ["announcements", "articles"] {
+        let response = app
+            .post_newsletters(serde_json::json!({
+                "title": "Newsletter title",
+                "topic": topic,
+                "content": {
+                    "text": "Newsletter body as plain text",
+                    "html": "<p>Newsletter body as HTML</p>",
+                }
+            }))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    app.dispatch_all_pending_emails().await;
+}
+
+#[tokio::test]
+async fn newsletters_carry_a_preferences_link() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.mock_email_server().await;
+
+    app.login_as_test_user().await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let preferences_link = app.get_preferences_link(email_requests.last().unwrap());
+    let response = reqwest::get(preferences_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
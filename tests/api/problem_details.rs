@@ -0,0 +1,106 @@
+use crate::common::spawn_app;
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+#[tokio::test]
+async fn subscribe_reports_every_invalid_field_as_problem_json() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", PROBLEM_JSON)
+        .body("name=&email=not-an-email")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    assert_eq!(response.headers()["Content-Type"], PROBLEM_JSON);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Bad Request");
+    assert_eq!(problem["status"], 400);
+    assert!(problem["detail"].is_string());
+    let fields: Vec<_> = problem["errors"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["field"].as_str().unwrap())
+        .collect();
+    assert_eq!(fields, ["name", "email"]);
+}
+
+#[tokio::test]
+async fn error_bodies_stay_empty_without_the_accept_header() {
+    let app = spawn_app().await;
+
+    let response = app
+        .post_subscriptions("name=&email=not-an-email".into())
+        .await;
+
+    assert_eq!(400, response.status().as_u16());
+    assert!(response.text().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn malformed_forms_are_reported_as_problem_json() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept", PROBLEM_JSON)
+        .body("name=le%20guin")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["status"], 400);
+    assert!(problem["detail"].as_str().unwrap().contains("email"));
+}
+
+#[tokio::test]
+async fn confirm_reports_token_validation_failures_as_problem_json() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=not-valid",
+            app.address
+        ))
+        .header("Accept", PROBLEM_JSON)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(400, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    let errors = problem["errors"].as_array().unwrap();
+    assert!(!errors.is_empty());
+    assert!(errors
+        .iter()
+        .all(|e| e["field"] == "subscription_token" && e["message"].is_string()));
+}
+
+#[tokio::test]
+async fn unknown_confirmation_tokens_are_reported_as_problem_json() {
+    let app = spawn_app().await;
+
+    let response = reqwest::Client::new()
+        .get(format!(
+            "{}/subscriptions/confirm?subscription_token=abcdefghijklmnopqrstuvwxy",
+            app.address
+        ))
+        .header("Accept", PROBLEM_JSON)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(401, response.status().as_u16());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["title"], "Unauthorized");
+    assert!(problem.get("errors").is_none());
+}
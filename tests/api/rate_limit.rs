@@ -0,0 +1,150 @@
+use crate::common::spawn_app_with;
+use std::num::NonZeroU32;
+use zero2prod::configuration::TokenBucketSettings;
+
+fn bucket(capacity: u32) -> TokenBucketSettings {
+    TokenBucketSettings {
+        capacity,
+        refill_per_hour: NonZeroU32::new(1).unwrap(),
+    }
+}
+
+fn subscription(email: &str) -> String {
+    serde_urlencoded::to_string([("name", "le guin"), ("email", email)]).unwrap()
+}
+
+async fn post_subscriptions_from(
+    app: &crate::common::TestApp,
+    forwarded_for: &str,
+    body: String,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("X-Forwarded-For", forwarded_for)
+        .body(body)
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn repeated_attempts_for_the_same_email_are_rejected_with_429() {
+    let app = spawn_app_with(|c| c.rate_limit.per_email = bucket(2)).await;
+
+    for _ in 0..2 {
+        let response = app
+            .post_subscriptions(subscription("ursula_le_guin@gmail.com"))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    // Case and surrounding spaces do not make it a different address.
+    let response = app
+        .post_subscriptions(subscription(" Ursula_Le_Guin@gmail.com "))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(retry_after, 3600);
+    let subscriptions = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscriptions.count, 1);
+}
+
+#[tokio::test]
+async fn spellings_of_the_same_address_share_a_bucket() {
+    let app = spawn_app_with(|c| c.rate_limit.per_email = bucket(1)).await;
+    app.post_subscriptions(subscription("ursula@bücher.example"))
+        .await;
+
+    let response = app
+        .post_subscriptions(subscription("Ursula@xn--bcher-kva.example"))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn the_per_email_limit_follows_the_email_normalization() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.per_email = bucket(1);
+        c.email_normalization.lowercase_local_part = false;
+    })
+    .await;
+    app.post_subscriptions(subscription("ursula_le_guin@gmail.com"))
+        .await;
+
+    // A different subscriber as far as the subscribe handler is concerned.
+    let response = app
+        .post_subscriptions(subscription("Ursula_Le_Guin@gmail.com"))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn other_emails_are_not_affected_by_the_per_email_limit() {
+    let app = spawn_app_with(|c| c.rate_limit.per_email = bucket(1)).await;
+    app.post_subscriptions(subscription("ursula_le_guin@gmail.com"))
+        .await;
+
+    let response = app
+        .post_subscriptions(subscription("le_guin@gmail.com"))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn too_many_attempts_from_the_same_ip_are_rejected_with_429() {
+    let app = spawn_app_with(|c| c.rate_limit.per_ip = bucket(2)).await;
+
+    for i in 0..2 {
+        let response = app
+            .post_subscriptions(subscription(&format!("reader{}@gmail.com", i)))
+            .await;
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = app
+        .post_subscriptions(subscription("reader3@gmail.com"))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("Retry-After"));
+}
+
+#[tokio::test]
+async fn forwarded_for_is_ignored_unless_the_peer_is_a_trusted_proxy() {
+    let app = spawn_app_with(|c| c.rate_limit.per_ip = bucket(1)).await;
+    post_subscriptions_from(&app, "198.51.100.1", subscription("reader1@gmail.com")).await;
+
+    // A client cannot dodge the limit by making up a forwarding header.
+    let response =
+        post_subscriptions_from(&app, "198.51.100.2", subscription("reader2@gmail.com")).await;
+
+    assert_eq!(response.status().as_u16(), 429);
+}
+
+#[tokio::test]
+async fn clients_behind_a_trusted_proxy_have_their_own_limit() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.per_ip = bucket(1);
+        c.rate_limit.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+    })
+    .await;
+    post_subscriptions_from(&app, "198.51.100.1", subscription("reader1@gmail.com")).await;
+
+    let other_client =
+        post_subscriptions_from(&app, "198.51.100.2", subscription("reader2@gmail.com")).await;
+    let same_client =
+        post_subscriptions_from(&app, "198.51.100.1", subscription("reader3@gmail.com")).await;
+
+    assert_eq!(other_client.status().as_u16(), 200);
+    assert_eq!(same_client.status().as_u16(), 429);
+}
@@ -0,0 +1,59 @@
+use crate::common::{spawn_app, TestApp};
+
+async fn post_resend_confirmation(app: &TestApp, email: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/resend_confirmation",
+            &app.address
+        ))
+        .form(&[("email", email)])
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+#[tokio::test]
+async fn a_pending_subscriber_gets_a_new_link_and_the_old_one_stops_working() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+
+    let response = post_resend_confirmation(&app, "Ursula_Le_Guin@gmail.com").await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let old_link = app.get_confirmation_links(&email_requests[0]).html;
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_ne!(old_link, new_link);
+    assert_eq!(reqwest::get(old_link).await.unwrap().status().as_u16(), 410);
+    assert_eq!(reqwest::get(new_link).await.unwrap().status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn the_response_is_the_same_whether_or_not_the_address_is_pending() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let confirmed = post_resend_confirmation(&app, "ursula_le_guin@gmail.com").await;
+    let unknown = post_resend_confirmation(&app, "octavia@example.com").await;
+    app.dispatch_all_pending_emails().await;
+
+    for response in [confirmed, unknown] {
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers().get("content-length").unwrap(), "0");
+    }
+    assert_eq!(app.email_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn a_malformed_email_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = post_resend_confirmation(&app, "not-an-email").await;
+
+    assert_eq!(response.status().as_u16(), 400);
+}
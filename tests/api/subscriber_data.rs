@@ -0,0 +1,343 @@
+use crate::common::{assert_is_redirect_to, spawn_app, ConfirmedSubscriber, TestApp};
+use uuid::Uuid;
+
+async fn get_admin_export(app: &TestApp, subscriber_id: Uuid) -> reqwest::Response {
+    app.api_client
+        .get(format!(
+            "{}/admin/subscribers/{}/data",
+            app.address, subscriber_id
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn post_admin_erase(app: &TestApp, subscriber_id: Uuid) -> reqwest::Response {
+    app.api_client
+        .post(format!(
+            "{}/admin/subscribers/{}/erase",
+            app.address, subscriber_id
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn post_own_erase(app: &TestApp, token: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/preferences/erase", app.address))
+        .form(&[("token", token)])
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn count(app: &TestApp, table: &str) -> i64 {
+    sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {}", table))
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_export_or_erase_a_subscriber() {
+    let app = spawn_app().await;
+    let subscriber_id = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .id;
+
+    assert_is_redirect_to(&get_admin_export(&app, subscriber_id).await, "/login");
+    assert_is_redirect_to(&post_admin_erase(&app, subscriber_id).await, "/login");
+    assert_eq!(count(&app, "subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn the_export_contains_the_subscription_its_tokens_and_deliveries() {
+    let app = spawn_app().await;
+    let subscriber_id = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .id;
+    app.login_as_test_user().await;
+
+    let response = get_admin_export(&app, subscriber_id).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(response.headers()["Content-Disposition"]
+        .to_str()
+        .unwrap()
+        .starts_with("attachment"));
+    let export: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(export["subscription"]["id"], subscriber_id.to_string());
+    assert_eq!(export["subscription"]["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(export["subscription"]["status"], "confirmed");
+    let tokens = export["confirmation_tokens"].as_array().unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert!(!tokens[0]["consumed_at"].is_null());
+    assert!(tokens[0].get("subscription_token").is_none());
+    let deliveries = export["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["recipient"], "ursula_le_guin@gmail.com");
+    assert_eq!(deliveries[0]["outcome"], "delivered");
+}
+
+#[tokio::test]
+async fn exporting_an_unknown_subscriber_returns_a_404() {
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    let export = get_admin_export(&app, Uuid::new_v4()).await;
+    let erase = post_admin_erase(&app, Uuid::new_v4()).await;
+
+    assert_eq!(export.status().as_u16(), 404);
+    assert_eq!(erase.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn erasure_removes_the_subscriber_and_everything_pointing_at_them() {
+    let app = spawn_app().await;
+    let ConfirmedSubscriber {
+        id: subscriber_id,
+        preferences_link,
+    } = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    reqwest::Client::new()
+        .post(format!("{}/preferences", app.address))
+        .form(&[
+            (
+                "token",
+                TestApp::preferences_token(&preferences_link).as_str(),
+            ),
+            ("action", "unsubscribe"),
+        ])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.login_as_test_user().await;
+
+    let response = post_admin_erase(&app, subscriber_id).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    for table in [
+        "subscriptions",
+        "subscription_tokens",
+        "unsubscribe_tokens",
+        "subscriber_preference_changes",
+        "email_change_requests",
+        "email_deliveries",
+        "email_outbox",
+    ] {
+        assert_eq!(count(&app, table).await, 0, "{} was not emptied", table);
+    }
+    let tombstone = sqlx::query!("SELECT email_hash FROM erased_subscribers")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(!tombstone.email_hash.contains("ursula"));
+}
+
+#[tokio::test]
+async fn an_erased_address_cannot_be_imported_again() {
+    let app = spawn_app().await;
+    let subscriber_id = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .id;
+    app.login_as_test_user().await;
+    post_admin_erase(&app, subscriber_id)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = app
+        .api_client
+        .post(format!(
+            "{}/admin/subscribers/import?status=confirmed",
+            app.address
+        ))
+        .header("Content-Type", "text/csv")
+        .body("name,email\nursula,Ursula_Le_Guin@gmail.com\noctavia,octavia@example.com\n")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()["X-Imported-Count"], "1");
+    assert_eq!(response.headers()["X-Rejected-Count"], "1");
+    let report = response.text().await.unwrap();
+    assert!(report.contains("Ursula_Le_Guin@gmail.com,This email address was erased"));
+}
+
+#[tokio::test]
+async fn every_address_the_subscriber_held_is_tombstoned() {
+    let app = spawn_app().await;
+    let subscriber = app.create_confirmed_subscriber("ursula@example.com").await;
+    let token = TestApp::preferences_token(&subscriber.preferences_link);
+    app.mock_email_server().await;
+    let request_email_change = |email: &'static str| {
+        reqwest::Client::new()
+            .post(format!("{}/preferences/email", app.address))
+            .form(&[("token", token.as_str()), ("email", email)])
+            .send()
+    };
+    request_email_change("ursula@example.org")
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let confirmation = app
+        .email_server
+        .received_requests()
+        .await
+        .unwrap()
+        .iter()
+        .map(|r| serde_json::from_slice::<serde_json::Value>(&r.body).unwrap())
+        .find(|e| e["To"] == "ursula@example.org")
+        .unwrap();
+    reqwest::get(app.get_email_change_link(&confirmation))
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    // Left pending: the subscriber asked for it all the same.
+    request_email_change("ursula@example.net")
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.login_as_test_user().await;
+
+    post_admin_erase(&app, subscriber.id)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    assert_eq!(count(&app, "erased_subscribers").await, 3);
+    let response = app
+        .api_client
+        .post(format!(
+            "{}/admin/subscribers/import?status=confirmed",
+            app.address
+        ))
+        .header("Content-Type", "text/csv")
+        .body(
+            "name,email\n\
+             ursula,ursula@example.com\n\
+             ursula,ursula@example.org\n\
+             ursula,ursula@example.net\n",
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["X-Imported-Count"], "0");
+    assert_eq!(response.headers()["X-Rejected-Count"], "3");
+}
+
+#[tokio::test]
+async fn a_subscriber_can_download_their_data_from_the_preferences_link() {
+    let app = spawn_app().await;
+    let ConfirmedSubscriber {
+        id: subscriber_id,
+        preferences_link,
+    } = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+
+    let html = reqwest::get(preferences_link.clone())
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("/preferences/data?token="));
+    let response = reqwest::get(format!(
+        "{}/preferences/data?token={}",
+        app.address,
+        TestApp::preferences_token(&preferences_link)
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let export: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(export["subscription"]["id"], subscriber_id.to_string());
+}
+
+#[tokio::test]
+async fn a_subscriber_can_erase_their_data_once() {
+    let app = spawn_app().await;
+    let preferences_link = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .preferences_link;
+    let token = TestApp::preferences_token(&preferences_link);
+
+    let first = post_own_erase(&app, &token).await;
+    let second = post_own_erase(&app, &token).await;
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(count(&app, "subscriptions").await, 0);
+    assert_eq!(count(&app, "erased_subscribers").await, 1);
+    assert_eq!(second.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn a_tampered_token_is_rejected() {
+    let app = spawn_app().await;
+    let preferences_link = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .preferences_link;
+    let token = format!("{}0", TestApp::preferences_token(&preferences_link));
+
+    let export = reqwest::get(format!("{}/preferences/data?token={}", app.address, token))
+        .await
+        .unwrap();
+    let erase = post_own_erase(&app, &token).await;
+
+    assert_eq!(export.status().as_u16(), 401);
+    assert_eq!(erase.status().as_u16(), 401);
+    assert_eq!(count(&app, "subscriptions").await, 1);
+}
+
+#[tokio::test]
+async fn emails_sent_to_another_spelling_of_the_address_are_exported_and_erased() {
+    let app = spawn_app().await;
+    let subscriber_id = app
+        .create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await
+        .id;
+    app.mock_email_server().await;
+    app.post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40GMail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    app.login_as_test_user().await;
+
+    let export: serde_json::Value = get_admin_export(&app, subscriber_id)
+        .await
+        .json()
+        .await
+        .unwrap();
+    post_admin_erase(&app, subscriber_id)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let recipients: Vec<_> = export["deliveries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| d["recipient"].as_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(
+        recipients,
+        ["ursula_le_guin@gmail.com", "Ursula_Le_Guin@GMail.com"]
+    );
+    assert_eq!(count(&app, "email_deliveries").await, 0);
+}
@@ -1,9 +1,11 @@
-use crate::common::spawn_app;
+use crate::common::{spawn_app, spawn_app_with};
 use claim::assert_err;
+use std::num::NonZeroU32;
 use wiremock::{
     matchers::{method, path},
     Mock, ResponseTemplate,
 };
+use zero2prod::configuration::TokenBucketSettings;
 
 #[tokio::test]
 async fn subscribe_returns_200_on_valid_form_data() {
@@ -18,6 +20,7 @@ async fn subscribe_returns_200_on_valid_form_data() {
         .await;
 
     let response = app.post_subscriptions(body.to_string()).await;
+    app.dispatch_all_pending_emails().await;
 
     assert_eq!(200, response.status().as_u16());
 }
@@ -43,7 +46,7 @@ async fn subscribe_persist_the_new_subscriber() {
 async fn subscribe_returns_400_on_form_data_missing() {
     let app = spawn_app().await;
 
-    let test_cases = vec![
+    let test_cases = [
         ("name=le%20guin", "missing email"),
         ("email=ursula_le_guin%40gmail.com", "missing name"),
         ("", "both missing"),
@@ -87,6 +90,7 @@ async fn subscribe_sends_a_confirmation_email_for_valid_form_data() {
         .await;
 
     let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     assert_eq!(200, response.status().as_u16());
 }
@@ -104,9 +108,249 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
 
     let links = app.get_confirmation_links(email_request);
     assert_eq!(links.html, links.plain_text);
 }
+
+#[tokio::test]
+async fn subscribe_returns_200_even_if_the_email_provider_is_down() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&app.email_server)
+        .await;
+
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(200, response.status().as_u16());
+    let queued = sqlx::query!("SELECT recipient, n_retries FROM email_outbox",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch queued email.");
+    assert_eq!(queued.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(queued.n_retries, 1);
+}
+
+#[tokio::test]
+async fn subscribe_queues_the_confirmation_email_in_the_outbox() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    app.post_subscriptions(body.into()).await;
+
+    let queued = sqlx::query!("SELECT recipient, subject FROM email_outbox",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch queued email.");
+    assert_eq!(queued.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(queued.subject, "Welcome");
+}
+
+#[tokio::test]
+async fn subscribing_twice_while_pending_resends_the_confirmation_email() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+
+    let first = app.post_subscriptions(body.into()).await;
+    let second = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].status, "pending_confirmation");
+
+    // The link in the latest email confirms the subscription
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let confirmation_link = app.get_confirmation_links(email_requests.last().unwrap());
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn subscribing_again_while_pending_expires_the_earlier_link() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    let old_link = app.get_confirmation_links(&email_requests[0]).html;
+    let new_link = app.get_confirmation_links(&email_requests[1]).html;
+    assert_eq!(reqwest::get(old_link).await.unwrap().status().as_u16(), 410);
+    assert_eq!(reqwest::get(new_link).await.unwrap().status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn concurrent_first_signups_for_the_same_address_all_succeed() {
+    let app = spawn_app_with(|c| {
+        c.rate_limit.per_email = TokenBucketSettings {
+            capacity: 10,
+            refill_per_hour: NonZeroU32::new(10).unwrap(),
+        }
+    })
+    .await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let responses =
+        futures_util::future::join_all((0..10).map(|_| app.post_subscriptions(body.into()))).await;
+
+    for response in responses {
+        assert_eq!(200, response.status().as_u16());
+    }
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+}
+
+#[tokio::test]
+async fn addresses_differing_only_in_case_are_the_same_subscriber() {
+    let app = spawn_app().await;
+
+    let first = app
+        .post_subscriptions("name=le%20guin&email=Ursula_Le_Guin%40Gmail.com".into())
+        .await;
+    let second = app
+        .post_subscriptions("name=le%20guin&email=%20ursula_le_guin%40gmail.COM".into())
+        .await;
+
+    assert_eq!(200, first.status().as_u16());
+    assert_eq!(200, second.status().as_u16());
+    let saved = sqlx::query!("SELECT email, email_canonical FROM subscriptions",)
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriptions.");
+    assert_eq!(saved.len(), 1);
+    // The address is displayed and delivered to as it was first typed.
+    assert_eq!(saved[0].email, "Ursula_Le_Guin@Gmail.com");
+    assert_eq!(saved[0].email_canonical, "ursula_le_guin@gmail.com");
+}
+
+#[tokio::test]
+async fn unicode_mailbox_names_are_accepted_and_stored_as_typed() {
+    let app = spawn_app().await;
+    // The test provider cannot deliver to them: the email is dropped, not retried.
+    Mock::given(path("/email"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=%D0%9F%D1%91%D1%82%D1%80%40example.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email, email_canonical FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.email, "Пётр@example.com");
+    assert_eq!(saved.email_canonical, "пётр@example.com");
+    let outbox = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(outbox.count, 0);
+}
+
+#[tokio::test]
+async fn subscribing_again_once_confirmed_returns_200_and_sends_a_notice() {
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber("ursula_le_guin@gmail.com")
+        .await;
+    app.mock_email_server().await;
+
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    let notice: serde_json::Value = serde_json::from_slice(&email_requests[1].body).unwrap();
+    assert_eq!(notice["Subject"], "You are already subscribed");
+}
+
+#[tokio::test]
+async fn subscribing_again_after_unsubscribing_asks_for_a_new_confirmation() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    app.post_one_click_unsubscribe(email_request)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "pending_confirmation");
+    let email_requests = app.email_server.received_requests().await.unwrap();
+    assert_eq!(email_requests.len(), 2);
+    app.get_confirmation_links(&email_requests[1]);
+}
+
+#[tokio::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = app.post_subscriptions(body.into()).await;
+
+    assert_eq!(500, response.status().as_u16());
+}
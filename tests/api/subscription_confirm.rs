@@ -1,4 +1,5 @@
 use crate::common::spawn_app;
+use uuid::Uuid;
 use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
@@ -28,6 +29,7 @@ async fn the_link_returned_by_subcribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
     let confirmation_link = app.get_confirmation_links(email_request);
@@ -49,6 +51,7 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
     let confirmation_link = app.get_confirmation_links(email_request);
@@ -67,3 +70,200 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        app.address,
+        "a".repeat(25)
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn the_confirmation_link_can_only_be_used_once() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+
+    let first = reqwest::get(confirmation_link.html.clone()).await.unwrap();
+    let second = reqwest::get(confirmation_link.html).await.unwrap();
+
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 409);
+}
+
+#[tokio::test]
+async fn an_unsubscribed_subscriber_is_not_confirmed_by_an_unused_link() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    app.post_one_click_unsubscribe(email_request)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    let response = reqwest::get(app.get_confirmation_links(email_request).html)
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 409);
+    let saved = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "unsubscribed");
+}
+
+#[tokio::test]
+async fn expired_confirmation_links_are_rejected_with_a_410() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    sqlx::query!("UPDATE subscription_tokens SET expires_at = now() - interval '1 hour'",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+    let response = reqwest::get(confirmation_link.html).await.unwrap();
+
+    assert_eq!(response.status().as_u16(), 410);
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn subscription_tokens_are_not_stored_in_plaintext() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_link = app.get_confirmation_links(email_request);
+    let token = confirmation_link
+        .html
+        .query_pairs()
+        .find(|(key, _)| key == "subscription_token")
+        .unwrap()
+        .1
+        .into_owned();
+
+    let stored = sqlx::query!("SELECT subscription_token, is_hashed FROM subscription_tokens",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch stored token");
+    assert_ne!(stored.subscription_token, token);
+    assert!(stored.is_hashed);
+}
+
+#[tokio::test]
+async fn plaintext_tokens_issued_before_hashing_still_confirm_until_they_expire() {
+    let app = spawn_app().await;
+    let subscriber_id = Uuid::new_v4();
+    let token = "LegacyPlaintextToken12345";
+    sqlx::query!(
+        r#"
+        INSERT INTO subscriptions
+            (id, email, email_canonical, name, subscribed_at, status)
+        VALUES (
+            $1, 'ursula_le_guin@gmail.com', 'ursula_le_guin@gmail.com', 'le guin', now(),
+            'pending_confirmation'
+        )
+        "#,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, expires_at)
+        VALUES ($1, $2, now() + interval '1 hour')
+        "#,
+        token,
+        subscriber_id
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token={}",
+        app.address, token
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn confirm_fails_if_there_is_a_fatal_database_error() {
+    let app = spawn_app().await;
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN consumed_at;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=abcdefghijklmnopqrstuvwxy",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(500, response.status().as_u16());
+}
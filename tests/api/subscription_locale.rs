@@ -0,0 +1,82 @@
+use crate::common::{spawn_app, TestApp};
+
+async fn subscribe_with_accept_language(
+    app: &TestApp,
+    body: &str,
+    accept_language: &str,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Accept-Language", accept_language)
+        .body(body.to_owned())
+        .send()
+        .await
+        .expect("Failed to send formdata")
+}
+
+async fn queued_email(app: &TestApp) -> (String, String, String) {
+    let queued = sqlx::query!("SELECT subject, html_body, text_body FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch queued email.");
+    (queued.subject, queued.html_body, queued.text_body)
+}
+
+async fn stored_locale(app: &TestApp) -> String {
+    sqlx::query!("SELECT locale FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.")
+        .locale
+}
+
+#[tokio::test]
+async fn the_confirmation_email_follows_accept_language() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    let response = subscribe_with_accept_language(&app, body, "de-DE, fr-CH;q=0.8, en;q=0.5").await;
+
+    assert_eq!(200, response.status().as_u16());
+    let (subject, _, text_body) = queued_email(&app).await;
+    assert_eq!(subject, "Bienvenue");
+    assert!(text_body.contains("confirmer votre abonnement"));
+    assert_eq!(stored_locale(&app).await, "fr");
+}
+
+#[tokio::test]
+async fn the_locale_field_takes_precedence_over_accept_language() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&locale=fr";
+
+    subscribe_with_accept_language(&app, body, "en-US").await;
+
+    let (subject, _, _) = queued_email(&app).await;
+    assert_eq!(subject, "Bienvenue");
+    assert_eq!(stored_locale(&app).await, "fr");
+}
+
+#[tokio::test]
+async fn unsupported_locales_fall_back_to_english() {
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&locale=tlh";
+
+    subscribe_with_accept_language(&app, body, "de").await;
+
+    let (subject, _, _) = queued_email(&app).await;
+    assert_eq!(subject, "Welcome");
+    assert_eq!(stored_locale(&app).await, "en");
+}
+
+#[tokio::test]
+async fn the_subscriber_name_is_escaped_in_the_html_body() {
+    let app = spawn_app().await;
+    let body = "name=Ursula%20%26%20Octavia&email=ursula_le_guin%40gmail.com";
+
+    app.post_subscriptions(body.into()).await;
+
+    let (_, html_body, text_body) = queued_email(&app).await;
+    assert!(html_body.contains("Ursula &amp; Octavia"));
+    assert!(text_body.contains("Ursula & Octavia"));
+}
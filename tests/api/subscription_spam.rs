@@ -0,0 +1,165 @@
+use crate::common::{spawn_app, spawn_app_with, TestApp};
+use chrono::{Duration, Utc};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::configuration::get_configuration;
+use zero2prod::spam_protection::issue_form_token;
+
+fn subscription(extra: &[(&str, &str)]) -> String {
+    let mut fields = vec![("name", "le guin"), ("email", "ursula_le_guin@gmail.com")];
+    fields.extend_from_slice(extra);
+    serde_urlencoded::to_string(fields).unwrap()
+}
+
+fn form_token_issued_seconds_ago(seconds: i64) -> String {
+    let configuration = get_configuration().unwrap();
+    issue_form_token(
+        Utc::now() - Duration::seconds(seconds),
+        &configuration.application.hmac_secret,
+    )
+}
+
+async fn subscriptions_count(app: &TestApp) -> i64 {
+    sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+async fn expect_no_email(app: &TestApp) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+}
+
+#[tokio::test]
+async fn the_subscription_form_carries_a_form_token_and_a_honeypot() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(format!("{}/subscriptions", &app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"name="website""#));
+    assert!(html.contains(r#"name="form_token" value=""#));
+}
+
+#[tokio::test]
+async fn a_filled_honeypot_is_accepted_but_dropped() {
+    let app = spawn_app().await;
+    expect_no_email(&app).await;
+
+    let response = app
+        .post_subscriptions(subscription(&[("website", "http://cheap-pills.example")]))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriptions_count(&app).await, 0);
+}
+
+#[tokio::test]
+async fn a_form_sent_back_right_after_being_served_is_accepted_but_dropped() {
+    let app = spawn_app().await;
+    expect_no_email(&app).await;
+    let html = reqwest::get(format!("{}/subscriptions", &app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let form_token = html
+        .split(r#"name="form_token" value=""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap();
+
+    let response = app
+        .post_subscriptions(subscription(&[("form_token", form_token)]))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriptions_count(&app).await, 0);
+}
+
+#[tokio::test]
+async fn a_submission_without_a_form_token_is_accepted_but_dropped() {
+    let app = spawn_app_with(|c| c.spam_protection.allow_tokenless_submissions = false).await;
+    expect_no_email(&app).await;
+
+    let response = app.post_subscriptions(subscription(&[])).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriptions_count(&app).await, 0);
+}
+
+#[tokio::test]
+async fn a_form_token_is_only_accepted_once() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let form_token = form_token_issued_seconds_ago(30);
+    let replayed = serde_urlencoded::to_string([
+        ("name", "spammer"),
+        ("email", "spammer@gmail.com"),
+        ("form_token", &form_token),
+    ])
+    .unwrap();
+
+    app.post_subscriptions(subscription(&[("form_token", &form_token)]))
+        .await;
+    let response = app.post_subscriptions(replayed).await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriptions_count(&app).await, 1);
+}
+
+#[tokio::test]
+async fn a_forged_form_token_is_accepted_but_dropped() {
+    let app = spawn_app().await;
+    expect_no_email(&app).await;
+    let backdated = format!("{}.deadbeef", (Utc::now() - Duration::hours(1)).timestamp());
+
+    let response = app
+        .post_subscriptions(subscription(&[("form_token", &backdated)]))
+        .await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriptions_count(&app).await, 0);
+}
+
+#[tokio::test]
+async fn a_form_filled_at_human_speed_is_stored() {
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let form_token = form_token_issued_seconds_ago(30);
+
+    let response = app
+        .post_subscriptions(subscription(&[
+            ("website", ""),
+            ("form_token", &form_token),
+        ]))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriptions_count(&app).await, 1);
+}
@@ -0,0 +1,178 @@
+use crate::common::{spawn_app, TestApp};
+
+async fn saved_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription")
+        .status
+}
+
+#[tokio::test]
+async fn unsubscribing_without_token_is_rejected_with_a_400() {
+    let app = spawn_app().await;
+
+    let response = reqwest::get(&format!("{}/subscriptions/unsubscribe", app.address))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn unsubscribing_with_an_unknown_token_is_rejected_with_a_401() {
+    let app = spawn_app().await;
+    let link = format!(
+        "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+        app.address,
+        "a".repeat(25)
+    );
+
+    let page = reqwest::get(&link).await.unwrap();
+    let one_click = reqwest::Client::new().post(&link).send().await.unwrap();
+
+    assert_eq!(page.status().as_u16(), 401);
+    assert_eq!(one_click.status().as_u16(), 401);
+}
+
+#[tokio::test]
+async fn confirmation_emails_carry_one_click_unsubscribe_headers() {
+    let app = spawn_app().await;
+
+    app.mock_email_server().await;
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let headers = body["Headers"].as_array().unwrap();
+    assert!(headers.iter().any(
+        |h| h["Name"] == "List-Unsubscribe-Post" && h["Value"] == "List-Unsubscribe=One-Click"
+    ));
+    app.get_unsubscribe_link(&email_request);
+}
+
+#[tokio::test]
+async fn one_click_unsubscribe_marks_the_subscriber_as_unsubscribed() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+
+    // RFC 8058: the mail client POSTs this exact body to the List-Unsubscribe URL
+    let response = app.post_one_click_unsubscribe(&email_request).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn following_the_unsubscribe_link_only_asks_for_a_confirmation() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+
+    let response = reqwest::get(app.get_unsubscribe_link(&email_request))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"method="post""#));
+    assert_eq!(saved_status(&app).await, "pending_confirmation");
+}
+
+#[tokio::test]
+async fn the_button_of_the_unsubscribe_page_unsubscribes() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    let html = reqwest::get(app.get_unsubscribe_link(&email_request))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let action = html
+        .split(r#"<form action=""#)
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap();
+
+    let response = reqwest::Client::new()
+        .post(format!("{}{}", app.address, action))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn unsubscribe_tokens_are_not_stored_in_plaintext() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    let email_request = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    let link = app.get_unsubscribe_link(&email_request);
+    let token = link
+        .query_pairs()
+        .find(|(k, _)| k == "unsubscribe_token")
+        .unwrap()
+        .1;
+
+    let stored = sqlx::query!("SELECT unsubscribe_token, is_hashed FROM unsubscribe_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+
+    assert!(stored.is_hashed);
+    assert_ne!(stored.unsubscribe_token, token);
+}
+
+#[tokio::test]
+async fn the_link_of_an_earlier_email_keeps_working() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    let first_email = app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+    let second_email = &app.email_server.received_requests().await.unwrap()[1];
+    assert_ne!(
+        app.get_unsubscribe_link(&first_email),
+        app.get_unsubscribe_link(second_email)
+    );
+
+    let response = app.post_one_click_unsubscribe(&first_email).await;
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+}
+
+#[tokio::test]
+async fn plaintext_tokens_from_before_hashing_still_unsubscribe() {
+    let app = spawn_app().await;
+    app.mock_email_server().await;
+    app.subscribe("le guin", "ursula_le_guin@gmail.com").await;
+    let token = "legacyunsubscribetoken123";
+    sqlx::query!(
+        r#"
+        INSERT INTO unsubscribe_tokens (unsubscribe_token, subscriber_id, is_hashed)
+        SELECT $1, id, false FROM subscriptions
+        "#,
+        token,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    let response = reqwest::Client::new()
+        .post(format!(
+            "{}/subscriptions/unsubscribe?unsubscribe_token={}",
+            app.address, token
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(saved_status(&app).await, "unsubscribed");
+}